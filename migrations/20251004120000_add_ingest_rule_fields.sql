@@ -0,0 +1,3 @@
+ALTER TABLE service_envelopes ADD COLUMN matched_rule TEXT NULL;
+ALTER TABLE mesh_packets ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE nodes ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+CREATE TABLE "traceroute_probes" (
+    "id" integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "packet_id" integer NOT NULL,
+    "from_id" integer NOT NULL,
+    "to_id" integer NOT NULL,
+    "channel" text NOT NULL,
+    "scheduled" integer NOT NULL DEFAULT 0,
+    "created_at" integer NOT NULL,
+    "response_mesh_packet_id" integer NULL,
+    "responded_at" integer NULL,
+    FOREIGN KEY ("response_mesh_packet_id") REFERENCES "mesh_packets" ("id") ON DELETE SET NULL
+) STRICT;
+CREATE INDEX idx_traceroute_probes_packet_id ON traceroute_probes(packet_id);
+CREATE INDEX idx_traceroute_probes_to_id ON traceroute_probes(to_id, created_at DESC);
+CREATE INDEX idx_traceroute_probes_response_mesh_packet_id ON traceroute_probes(response_mesh_packet_id);
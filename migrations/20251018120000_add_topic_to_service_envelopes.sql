@@ -0,0 +1 @@
+ALTER TABLE service_envelopes ADD COLUMN topic TEXT NULL;
@@ -0,0 +1,6 @@
+CREATE TABLE "forward_queue" (
+    "id" integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "payload_data" blob NOT NULL,
+    "topic" text NULL,
+    "created_at" integer NOT NULL
+) STRICT;
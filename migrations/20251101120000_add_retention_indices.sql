@@ -0,0 +1,2 @@
+CREATE INDEX idx_service_envelopes_created_at ON service_envelopes(created_at);
+CREATE INDEX idx_mesh_packets_created_at ON mesh_packets(created_at);
@@ -0,0 +1,13 @@
+-- Deleting the last position or node info of a node deleted the node itself. SQLite does not
+-- allow changing a foreign key, manually updating the schema works
+
+PRAGMA writable_schema = 1;
+
+UPDATE SQLITE_MASTER SET SQL = REPLACE(REPLACE(SQL,
+    'FOREIGN KEY ("last_node_info_id") REFERENCES "node_info" ("id") ON DELETE CASCADE',
+    'FOREIGN KEY ("last_node_info_id") REFERENCES "node_info" ("id") ON DELETE SET NULL'),
+    'FOREIGN KEY ("last_position_id") REFERENCES "positions" ("id") ON DELETE CASCADE',
+    'FOREIGN KEY ("last_position_id") REFERENCES "positions" ("id") ON DELETE SET NULL')
+WHERE type = 'table' AND name = 'nodes';
+
+PRAGMA writable_schema = 0;
@@ -0,0 +1,52 @@
+CREATE TABLE "telemetry_rollups_hourly" (
+    "node_id" integer NOT NULL,
+    "gateway_id" text NOT NULL DEFAULT '',
+    "metric" text NOT NULL,
+    "bucket" integer NOT NULL,
+    "min_value" real NOT NULL,
+    "max_value" real NOT NULL,
+    "sum_value" real NOT NULL,
+    "count" integer NOT NULL,
+    PRIMARY KEY ("node_id", "metric", "gateway_id", "bucket")
+) STRICT, WITHOUT ROWID;
+
+CREATE TABLE "telemetry_rollups_daily" (
+    "node_id" integer NOT NULL,
+    "gateway_id" text NOT NULL DEFAULT '',
+    "metric" text NOT NULL,
+    "bucket" integer NOT NULL,
+    "min_value" real NOT NULL,
+    "max_value" real NOT NULL,
+    "sum_value" real NOT NULL,
+    "count" integer NOT NULL,
+    PRIMARY KEY ("node_id", "metric", "gateway_id", "bucket")
+) STRICT, WITHOUT ROWID;
+
+-- Backfill from the data imported so far, with the same filters as the importer
+INSERT INTO telemetry_rollups_hourly (node_id, gateway_id, metric, bucket, min_value, max_value, sum_value, count)
+SELECT node_id, gateway_id, metric, time - time % 3600000000000, MIN(value), MAX(value), SUM(value), COUNT(*)
+FROM (
+    SELECT from_id AS node_id, gateway_id, 'rx_rssi' AS metric, rx_time AS time, CAST(rx_rssi AS REAL) AS value FROM mesh_packets WHERE rx_time > 0 AND rx_rssi != 0
+    UNION ALL SELECT from_id, gateway_id, 'rx_snr', rx_time, rx_snr FROM mesh_packets WHERE rx_time > 0 AND rx_snr != 0
+    UNION ALL SELECT node_id, '', 'battery_level', time, CAST(battery_level AS REAL) FROM device_metrics WHERE time > 0 AND battery_level > 0
+    UNION ALL SELECT node_id, '', 'voltage', time, voltage FROM device_metrics WHERE time > 0 AND voltage > 0
+    UNION ALL SELECT node_id, '', 'channel_utilization', time, channel_utilization FROM device_metrics WHERE time > 0 AND channel_utilization > 0
+    UNION ALL SELECT node_id, '', 'air_util_tx', time, air_util_tx FROM device_metrics WHERE time > 0 AND air_util_tx > 0
+    UNION ALL SELECT node_id, '', 'temperature', time, temperature FROM environment_metrics WHERE time > 0 AND temperature > -100
+    UNION ALL SELECT node_id, '', 'relative_humidity', time, relative_humidity FROM environment_metrics WHERE time > 0 AND relative_humidity > 0
+    UNION ALL SELECT node_id, '', 'barometric_pressure', time, barometric_pressure FROM environment_metrics WHERE time > 0 AND barometric_pressure > 0
+    UNION ALL SELECT node_id, '', 'gas_resistance', time, gas_resistance FROM environment_metrics WHERE time > 0 AND gas_resistance > 0
+    UNION ALL SELECT node_id, '', 'iaq', time, CAST(iaq AS REAL) FROM environment_metrics WHERE time > 0 AND iaq > 0
+    UNION ALL SELECT node_id, '', 'ch1_voltage', time, ch1_voltage FROM power_metrics WHERE time > 0 AND ch1_voltage != 0
+    UNION ALL SELECT node_id, '', 'ch1_current', time, ch1_current FROM power_metrics WHERE time > 0 AND ch1_current != 0
+    UNION ALL SELECT node_id, '', 'ch2_voltage', time, ch2_voltage FROM power_metrics WHERE time > 0 AND ch2_voltage != 0
+    UNION ALL SELECT node_id, '', 'ch2_current', time, ch2_current FROM power_metrics WHERE time > 0 AND ch2_current != 0
+    UNION ALL SELECT node_id, '', 'ch3_voltage', time, ch3_voltage FROM power_metrics WHERE time > 0 AND ch3_voltage != 0
+    UNION ALL SELECT node_id, '', 'ch3_current', time, ch3_current FROM power_metrics WHERE time > 0 AND ch3_current != 0
+)
+GROUP BY 1, 2, 3, 4;
+
+INSERT INTO telemetry_rollups_daily (node_id, gateway_id, metric, bucket, min_value, max_value, sum_value, count)
+SELECT node_id, gateway_id, metric, bucket - bucket % 86400000000000, MIN(min_value), MAX(max_value), SUM(sum_value), SUM(count)
+FROM telemetry_rollups_hourly
+GROUP BY 1, 2, 3, 4;
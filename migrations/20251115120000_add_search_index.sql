@@ -0,0 +1,32 @@
+-- Text messages and waypoints, the rowid is the id of the mesh packet
+CREATE VIRTUAL TABLE "packet_search" USING fts5(
+    "content",
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- The rowid is the id of the node
+CREATE VIRTUAL TABLE "node_search" USING fts5(
+    "long_name",
+    "short_name",
+    "user_id",
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER packet_search_delete AFTER DELETE ON mesh_packets
+BEGIN
+    DELETE FROM packet_search WHERE rowid = old.id;
+END;
+
+CREATE TRIGGER node_search_delete AFTER DELETE ON nodes
+BEGIN
+    DELETE FROM node_search WHERE rowid = old.id;
+END;
+
+INSERT INTO packet_search (rowid, content)
+SELECT id, CAST(payload_data AS TEXT) FROM mesh_packets WHERE portnum = 1 AND duplicate_of_mesh_packet_id IS NULL;
+
+INSERT INTO packet_search (rowid, content)
+SELECT mesh_packet_id, TRIM(COALESCE(name, '') || ' ' || COALESCE(description, '')) FROM waypoints;
+
+INSERT INTO node_search (rowid, long_name, short_name, user_id)
+SELECT id, COALESCE(long_name, ''), COALESCE(short_name, ''), user_id FROM nodes;
@@ -0,0 +1,2 @@
+-- Merged envelopes are deduplicated by their hash
+CREATE INDEX idx_service_envelopes_hash ON service_envelopes(hash);
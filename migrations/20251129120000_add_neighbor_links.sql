@@ -0,0 +1,27 @@
+-- The latest state of each directed neighbor link, so the map does not aggregate the neighbors
+-- table on every poll
+CREATE TABLE "neighbor_links" (
+    "node_id" integer NOT NULL,
+    "neighbor_node_id" integer NOT NULL,
+    "snr" real,
+    "first_seen" integer NOT NULL,
+    "last_seen" integer NOT NULL,
+    "observations" integer NOT NULL,
+    PRIMARY KEY ("node_id", "neighbor_node_id")
+) STRICT, WITHOUT ROWID;
+
+INSERT INTO neighbor_links (node_id, neighbor_node_id, snr, first_seen, last_seen, observations)
+SELECT node_id, neighbor_node_id, snr, first_seen, last_seen, observations
+FROM (
+    SELECT
+        node_id,
+        neighbor_node_id,
+        snr,
+        MIN(timestamp) OVER link AS first_seen,
+        MAX(timestamp) OVER link AS last_seen,
+        COUNT(*) OVER link AS observations,
+        row_number() OVER (PARTITION BY node_id, neighbor_node_id ORDER BY timestamp DESC, id DESC) AS row_number
+    FROM neighbors
+    WINDOW link AS (PARTITION BY node_id, neighbor_node_id)
+)
+WHERE row_number = 1;
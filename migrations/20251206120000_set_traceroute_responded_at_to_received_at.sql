@@ -0,0 +1,4 @@
+-- The round trip is measured with the time the response was received, not when it was imported
+UPDATE traceroute_probes
+SET responded_at = (SELECT received_at FROM mesh_packets WHERE id = traceroute_probes.response_mesh_packet_id)
+WHERE response_mesh_packet_id IS NOT NULL;
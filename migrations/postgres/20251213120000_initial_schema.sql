@@ -0,0 +1,291 @@
+-- The schema of the SQLite migrations up to 20251206120000. Integers are BIGINT, including the
+-- flags, so the queries compare them with 0 and 1 on both backends.
+
+CREATE TABLE "mesh_packets" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "gateway_id" TEXT NOT NULL,
+    "from_id" BIGINT NOT NULL,
+    "to_id" BIGINT NOT NULL,
+    "channel_id" BIGINT NOT NULL,
+    "unique_id" BIGINT NOT NULL,
+    "portnum" BIGINT NOT NULL,
+    "payload_data" BYTEA NOT NULL,
+    "rx_time" BIGINT NOT NULL,
+    "rx_snr" DOUBLE PRECISION NOT NULL,
+    "rx_rssi" BIGINT NOT NULL,
+    "hop_start" BIGINT NOT NULL DEFAULT 0,
+    "hop_limit" BIGINT NOT NULL DEFAULT 0,
+    "want_ack" BIGINT NOT NULL,
+    "want_response" BIGINT NOT NULL,
+    "priority" BIGINT NOT NULL,
+    "source" BIGINT,
+    "dest" BIGINT,
+    "request_id" BIGINT,
+    "reply_id" BIGINT,
+    "emoji" BIGINT,
+    "created_at" BIGINT NOT NULL,
+    "hash" BYTEA NOT NULL,
+    "duplicate_of_mesh_packet_id" BIGINT NULL,
+    "received_at" BIGINT NOT NULL DEFAULT 0,
+    "hidden" BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX idx_mesh_packets_created_at ON mesh_packets (created_at);
+CREATE INDEX idx_mesh_packets_from_id_rx_time ON mesh_packets (from_id, rx_time DESC);
+CREATE INDEX idx_mesh_packets_hash ON mesh_packets (hash);
+CREATE INDEX idx_mesh_packets_rx_time ON mesh_packets (rx_time);
+CREATE INDEX idx_mesh_packets_unique_id ON mesh_packets (unique_id);
+
+CREATE TABLE "positions" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "latitude_i" BIGINT,
+    "longitude_i" BIGINT,
+    "latitude" DOUBLE PRECISION,
+    "longitude" DOUBLE PRECISION,
+    "altitude" BIGINT,
+    "time" BIGINT,
+    "location_source" BIGINT,
+    "altitude_source" BIGINT,
+    "timestamp" BIGINT,
+    "altitude_hae" BIGINT,
+    "altitude_geoidal_separation" BIGINT,
+    "pdop" BIGINT,
+    "hdop" BIGINT,
+    "vdop" BIGINT,
+    "gps_accuracy" BIGINT,
+    "ground_speed" BIGINT,
+    "ground_track" BIGINT,
+    "fix_quality" BIGINT,
+    "fix_type" BIGINT,
+    "sats_in_view" BIGINT,
+    "sensor_id" BIGINT,
+    "next_update" BIGINT,
+    "seq_number" BIGINT,
+    "precision_bits" BIGINT
+);
+
+CREATE TABLE "node_info" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "user_id" TEXT NOT NULL,
+    "long_name" TEXT NOT NULL,
+    "short_name" TEXT NOT NULL,
+    "hw_model_id" BIGINT NOT NULL,
+    "is_licensed" BIGINT NOT NULL,
+    "role" BIGINT NOT NULL,
+    "public_key" BYTEA NULL,
+    "is_unmessagable" BIGINT NULL
+);
+
+CREATE TABLE "neighbors" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "neighbor_node_id" BIGINT NOT NULL,
+    "snr" DOUBLE PRECISION,
+    "timestamp" BIGINT NOT NULL
+);
+
+CREATE INDEX idx_neighbors ON neighbors (node_id, neighbor_node_id, timestamp DESC);
+CREATE INDEX idx_neighbors_mesh_packet_id ON neighbors (mesh_packet_id);
+CREATE INDEX idx_neighbors_timestamp ON neighbors (timestamp);
+
+CREATE TABLE "device_metrics" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "time" BIGINT,
+    "battery_level" BIGINT,
+    "voltage" DOUBLE PRECISION,
+    "channel_utilization" DOUBLE PRECISION,
+    "air_util_tx" DOUBLE PRECISION,
+    "uptime_seconds" BIGINT DEFAULT 0
+);
+
+CREATE INDEX idx_device_metrics_node_id ON device_metrics (node_id);
+
+CREATE TABLE "environment_metrics" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "time" BIGINT,
+    "temperature" DOUBLE PRECISION,
+    "relative_humidity" DOUBLE PRECISION,
+    "barometric_pressure" DOUBLE PRECISION,
+    "gas_resistance" DOUBLE PRECISION,
+    "iaq" BIGINT DEFAULT 0
+);
+
+CREATE INDEX idx_environment_metrics_node_id ON environment_metrics (node_id);
+
+CREATE TABLE "power_metrics" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "time" BIGINT,
+    "ch1_voltage" DOUBLE PRECISION,
+    "ch1_current" DOUBLE PRECISION,
+    "ch2_voltage" DOUBLE PRECISION,
+    "ch2_current" DOUBLE PRECISION,
+    "ch3_voltage" DOUBLE PRECISION,
+    "ch3_current" DOUBLE PRECISION
+);
+
+CREATE TABLE "waypoints" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "mesh_packet_id" BIGINT UNIQUE NOT NULL REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "node_id" BIGINT NOT NULL,
+    "waypoint_id" BIGINT,
+    "latitude_i" BIGINT,
+    "longitude_i" BIGINT,
+    "latitude" DOUBLE PRECISION,
+    "longitude" DOUBLE PRECISION,
+    "expire" BIGINT,
+    "locked_to" BIGINT,
+    "name" TEXT,
+    "description" TEXT,
+    "icon" TEXT
+);
+
+CREATE TABLE "nodes" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "node_id" BIGINT NOT NULL,
+    "user_id" TEXT NOT NULL,
+    "last_rx_time" BIGINT,
+    "last_rx_snr" DOUBLE PRECISION,
+    "last_rx_rssi" BIGINT,
+    "long_name" TEXT,
+    "short_name" TEXT,
+    "hw_model_id" BIGINT,
+    "is_licensed" BIGINT,
+    "role" BIGINT,
+    "latitude" DOUBLE PRECISION,
+    "longitude" DOUBLE PRECISION,
+    "altitude" BIGINT,
+    "last_node_info_id" BIGINT REFERENCES "node_info" ("id") ON DELETE SET NULL,
+    "last_position_id" BIGINT REFERENCES "positions" ("id") ON DELETE SET NULL,
+    "battery_level" BIGINT,
+    "voltage" DOUBLE PRECISION,
+    "channel_utilization" DOUBLE PRECISION,
+    "air_util_tx" DOUBLE PRECISION,
+    "last_device_metrics_id" BIGINT NULL REFERENCES "device_metrics" ("id") ON DELETE SET NULL,
+    "temperature" DOUBLE PRECISION,
+    "relative_humidity" DOUBLE PRECISION,
+    "barometric_pressure" DOUBLE PRECISION,
+    "gas_resistance" DOUBLE PRECISION,
+    "last_environment_metrics_id" BIGINT NULL REFERENCES "environment_metrics" ("id") ON DELETE SET NULL,
+    "created_at" BIGINT NOT NULL DEFAULT 0,
+    "uptime_seconds" BIGINT NULL,
+    "iaq" BIGINT NULL,
+    "last_hop_start" BIGINT NULL,
+    "last_hop_limit" BIGINT NULL,
+    "updated_at" BIGINT NOT NULL DEFAULT 0,
+    "public_key" BYTEA NULL,
+    "is_unmessagable" BIGINT NULL,
+    "hidden" BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX idx_nodes_unique_id ON nodes (node_id);
+CREATE INDEX idx_nodes_last_rx_time ON nodes (last_rx_time);
+
+CREATE TABLE "service_envelopes" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "payload_data" BYTEA NOT NULL,
+    "hash" BYTEA NOT NULL,
+    "created_at" BIGINT NOT NULL,
+    "processed_at" BIGINT,
+    "matched_rule" TEXT NULL,
+    "topic" TEXT NULL
+);
+
+CREATE INDEX idx_service_envelope_processed_at ON service_envelopes (processed_at);
+CREATE INDEX idx_service_envelopes_created_at ON service_envelopes (created_at);
+CREATE INDEX idx_service_envelopes_hash ON service_envelopes (hash);
+
+CREATE TABLE "traceroute_probes" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "packet_id" BIGINT NOT NULL,
+    "from_id" BIGINT NOT NULL,
+    "to_id" BIGINT NOT NULL,
+    "channel" TEXT NOT NULL,
+    "scheduled" BIGINT NOT NULL DEFAULT 0,
+    "created_at" BIGINT NOT NULL,
+    "response_mesh_packet_id" BIGINT NULL REFERENCES "mesh_packets" ("id") ON DELETE SET NULL,
+    "responded_at" BIGINT NULL
+);
+
+CREATE INDEX idx_traceroute_probes_packet_id ON traceroute_probes (packet_id);
+CREATE INDEX idx_traceroute_probes_response_mesh_packet_id ON traceroute_probes (response_mesh_packet_id);
+CREATE INDEX idx_traceroute_probes_to_id ON traceroute_probes (to_id, created_at DESC);
+
+CREATE TABLE "forward_queue" (
+    "id" BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    "payload_data" BYTEA NOT NULL,
+    "topic" TEXT NULL,
+    "created_at" BIGINT NOT NULL
+);
+
+CREATE TABLE "telemetry_rollups_hourly" (
+    "node_id" BIGINT NOT NULL,
+    "gateway_id" TEXT NOT NULL DEFAULT '',
+    "metric" TEXT NOT NULL,
+    "bucket" BIGINT NOT NULL,
+    "min_value" DOUBLE PRECISION NOT NULL,
+    "max_value" DOUBLE PRECISION NOT NULL,
+    "sum_value" DOUBLE PRECISION NOT NULL,
+    "count" BIGINT NOT NULL,
+    PRIMARY KEY ("node_id", "metric", "gateway_id", "bucket")
+);
+
+CREATE TABLE "telemetry_rollups_daily" (
+    "node_id" BIGINT NOT NULL,
+    "gateway_id" TEXT NOT NULL DEFAULT '',
+    "metric" TEXT NOT NULL,
+    "bucket" BIGINT NOT NULL,
+    "min_value" DOUBLE PRECISION NOT NULL,
+    "max_value" DOUBLE PRECISION NOT NULL,
+    "sum_value" DOUBLE PRECISION NOT NULL,
+    "count" BIGINT NOT NULL,
+    PRIMARY KEY ("node_id", "metric", "gateway_id", "bucket")
+);
+
+-- The latest state of each directed neighbor link
+CREATE TABLE "neighbor_links" (
+    "node_id" BIGINT NOT NULL,
+    "neighbor_node_id" BIGINT NOT NULL,
+    "snr" DOUBLE PRECISION,
+    "first_seen" BIGINT NOT NULL,
+    "last_seen" BIGINT NOT NULL,
+    "observations" BIGINT NOT NULL,
+    PRIMARY KEY ("node_id", "neighbor_node_id")
+);
+
+-- Full-text search like the FTS5 tables of SQLite: words are matched without diacritics
+CREATE EXTENSION IF NOT EXISTS unaccent;
+CREATE TEXT SEARCH CONFIGURATION meshstellar (COPY = simple);
+ALTER TEXT SEARCH CONFIGURATION meshstellar
+    ALTER MAPPING FOR hword, hword_part, word WITH unaccent, simple;
+
+-- Text messages and waypoints
+CREATE TABLE "packet_search" (
+    "mesh_packet_id" BIGINT PRIMARY KEY REFERENCES "mesh_packets" ("id") ON DELETE CASCADE,
+    "content" TEXT NOT NULL,
+    "document" TSVECTOR GENERATED ALWAYS AS (to_tsvector('meshstellar', "content")) STORED
+);
+
+CREATE INDEX idx_packet_search_document ON packet_search USING GIN (document);
+
+CREATE TABLE "node_search" (
+    "id" BIGINT PRIMARY KEY REFERENCES "nodes" ("id") ON DELETE CASCADE,
+    "long_name" TEXT NOT NULL,
+    "short_name" TEXT NOT NULL,
+    "user_id" TEXT NOT NULL,
+    "document" TSVECTOR GENERATED ALWAYS AS (
+        to_tsvector('meshstellar', "long_name" || ' ' || "short_name" || ' ' || "user_id")
+    ) STORED
+);
+
+CREATE INDEX idx_node_search_document ON node_search USING GIN (document);
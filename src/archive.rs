@@ -0,0 +1,432 @@
+//! Moves the data of complete months out of the database into monthly archive files with the same
+//! schema. Reads of old data attach the archives they need as the `archive` schema, one at a time,
+//! so the number of archives is not limited by the number of attached databases.
+
+use crate::{
+    retention::BATCH_PAUSE,
+    util::{config::get_config, MIGRATOR},
+};
+use anyhow::anyhow;
+use chrono::{Datelike, Months, NaiveDate, TimeZone, Utc};
+use sqlx::{
+    sqlite::SqliteConnectOptions, ConnectOptions, Connection, SqliteConnection, SqlitePool,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::time::sleep;
+use tracing::info;
+
+const FILE_PREFIX: &str = "meshstellar-";
+const FILE_EXTENSION: &str = "db";
+const BATCH_SIZE: i64 = 1000;
+
+/// The tables with the payloads of the packets, archived with their packets.
+const PAYLOAD_TABLES: [&str; 7] = [
+    "positions",
+    "node_info",
+    "neighbors",
+    "device_metrics",
+    "environment_metrics",
+    "power_metrics",
+    "waypoints",
+];
+
+fn archive_directory() -> PathBuf {
+    PathBuf::from(
+        get_config()
+            .get_string("archive_directory")
+            .unwrap_or_else(|_| "archive".into()),
+    )
+}
+
+/// Parses a month like `2025-06`.
+pub fn parse_month(month: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid month {}, use YYYY-MM", month))
+}
+
+fn month_start(month: NaiveDate) -> i64 {
+    month
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp_nanos_opt()
+        .unwrap()
+}
+
+fn next_month(month: NaiveDate) -> NaiveDate {
+    month + Months::new(1)
+}
+
+/// Returns the end of the month in nanoseconds, so archives without data after a time are skipped.
+pub fn month_end(month: NaiveDate) -> i64 {
+    month_start(next_month(month))
+}
+
+fn archive_path(directory: &Path, month: NaiveDate) -> PathBuf {
+    directory.join(format!(
+        "{}{}.{}",
+        FILE_PREFIX,
+        month.format("%Y-%m"),
+        FILE_EXTENSION
+    ))
+}
+
+/// Returns the months and files of the archives, oldest first.
+pub fn archive_files() -> anyhow::Result<Vec<(NaiveDate, PathBuf)>> {
+    let directory = archive_directory();
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut archives = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == FILE_EXTENSION))
+        .filter_map(|path| {
+            let month = path
+                .file_stem()?
+                .to_str()?
+                .strip_prefix(FILE_PREFIX)
+                .and_then(|month| parse_month(month).ok())?;
+            Some((month, path))
+        })
+        .collect::<Vec<_>>();
+    archives.sort();
+
+    Ok(archives)
+}
+
+/// Returns the files of the archives with data after `time` in nanoseconds, newest first.
+pub fn archive_files_after(time: i64) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(archive_files()?
+        .into_iter()
+        .rev()
+        .filter(|(month, _)| month_end(*month) > time)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Attaches an archive as the `archive` schema. An archive left attached by an interrupted read
+/// is detached first.
+pub async fn attach(conn: &mut SqliteConnection, path: &Path) -> anyhow::Result<()> {
+    let _ = detach(conn).await;
+
+    sqlx::query("ATTACH DATABASE ? AS archive")
+        .bind(path.to_string_lossy().into_owned())
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn detach(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    sqlx::query("DETACH DATABASE archive").execute(conn).await?;
+
+    Ok(())
+}
+
+/// Moves the processed envelopes and the packets with their payloads received before `before`
+/// into monthly archives. The telemetry rollups and traceroute probes stay in the database, so
+/// the long-term plots keep working. A probe answered by an archived packet loses its
+/// `response_mesh_packet_id`, the response itself stays readable in the archive.
+pub async fn archive(pool: &SqlitePool, before: NaiveDate) -> anyhow::Result<()> {
+    let current_month = Utc::now().date_naive().with_day(1).unwrap();
+    if before > current_month {
+        return Err(anyhow!("Only complete months can be archived"));
+    }
+
+    let oldest = sqlx::query_scalar!(
+        r#"SELECT MIN(created_at) AS "created_at?: i64" FROM (
+            SELECT MIN(created_at) AS created_at FROM mesh_packets
+            UNION ALL SELECT MIN(created_at) FROM service_envelopes WHERE processed_at IS NOT NULL
+        )"#
+    )
+    .fetch_one(pool)
+    .await?;
+    let Some(oldest) = oldest else {
+        return Ok(());
+    };
+
+    let directory = archive_directory();
+    fs::create_dir_all(&directory)?;
+
+    let mut month = Utc
+        .timestamp_nanos(oldest)
+        .date_naive()
+        .with_day(1)
+        .unwrap();
+    while month < before {
+        archive_month(pool, &archive_path(&directory, month), month).await?;
+        month = next_month(month);
+    }
+
+    info!("Run `meshstellar vacuum` to return the archived space to the file system");
+
+    Ok(())
+}
+
+async fn archive_month(pool: &SqlitePool, path: &Path, month: NaiveDate) -> anyhow::Result<()> {
+    let from = month_start(month);
+    let to = month_end(month);
+
+    let count = sqlx::query_scalar!(
+        r#"SELECT
+            (SELECT COUNT(*) FROM mesh_packets WHERE created_at >= ?1 AND created_at < ?2)
+            + (SELECT COUNT(*) FROM service_envelopes WHERE created_at >= ?1 AND created_at < ?2 AND processed_at IS NOT NULL)
+            AS "count!: i64""#,
+        from,
+        to
+    )
+    .fetch_one(pool)
+    .await?;
+    if count == 0 {
+        return Ok(());
+    }
+
+    // The archive gets the current schema, an existing archive is upgraded
+    let mut archive = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .connect()
+        .await?;
+    MIGRATOR.run(&mut archive).await?;
+    archive.close().await?;
+
+    let mut conn = pool.acquire().await?;
+    attach(&mut conn, path).await?;
+    let result = move_month(&mut conn, from, to).await;
+    detach(&mut conn).await?;
+    let (packets, envelopes) = result?;
+
+    info!(
+        "Archived {} packets and {} envelopes of {} to {}",
+        packets,
+        envelopes,
+        month.format("%Y-%m"),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Copies a month into the attached archive, and deletes the rows found in the archive. Copied rows
+/// are ignored when an interrupted archive is run again, so nothing is deleted before it is in the
+/// archive. The payloads are deleted with their packets.
+async fn move_month(conn: &mut SqliteConnection, from: i64, to: i64) -> anyhow::Result<(u64, u64)> {
+    copy_month(conn, from, to).await?;
+
+    let packets = delete_archived(conn, "mesh_packets", "", from, to).await?;
+    let envelopes = delete_archived(
+        conn,
+        "service_envelopes",
+        "AND processed_at IS NOT NULL",
+        from,
+        to,
+    )
+    .await?;
+
+    Ok((packets, envelopes))
+}
+
+async fn delete_archived(
+    conn: &mut SqliteConnection,
+    table: &str,
+    condition: &str,
+    from: i64,
+    to: i64,
+) -> anyhow::Result<u64> {
+    let query = format!(
+        "DELETE FROM main.{table} WHERE id IN (SELECT id FROM main.{table} AS copied WHERE created_at >= ?1 AND created_at < ?2 {condition} AND EXISTS (SELECT 1 FROM archive.{table} WHERE id = copied.id) LIMIT ?3)"
+    );
+    let mut deleted = 0;
+
+    loop {
+        let count = sqlx::query(&query)
+            .bind(from)
+            .bind(to)
+            .bind(BATCH_SIZE)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        deleted += count;
+
+        if count < BATCH_SIZE as u64 {
+            return Ok(deleted);
+        }
+
+        sleep(BATCH_PAUSE).await;
+    }
+}
+
+/// Copies a month into the attached archive in id ranges of `BATCH_SIZE`, each in a transaction
+/// of its own, so the importer is not locked out for the whole month. Packets are copied in the
+/// same transaction as their payloads.
+async fn copy_month(conn: &mut SqliteConnection, from: i64, to: i64) -> anyhow::Result<()> {
+    copy_in_batches(conn, "mesh_packets", "", &PAYLOAD_TABLES, from, to).await?;
+    copy_in_batches(
+        conn,
+        "service_envelopes",
+        "AND processed_at IS NOT NULL",
+        &[],
+        from,
+        to,
+    )
+    .await
+}
+
+async fn copy_in_batches(
+    conn: &mut SqliteConnection,
+    table: &str,
+    condition: &str,
+    payload_tables: &[&str],
+    from: i64,
+    to: i64,
+) -> anyhow::Result<()> {
+    let rows = format!(
+        "SELECT * FROM main.{table} WHERE created_at >= ?1 AND created_at < ?2 {condition} AND id >= ?3 AND id < ?4"
+    );
+
+    let (first, last): (Option<i64>, Option<i64>) = sqlx::query_as(&format!(
+        "SELECT MIN(id), MAX(id) FROM main.{table} WHERE created_at >= ?1 AND created_at < ?2 {condition}"
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_one(&mut *conn)
+    .await?;
+    let (Some(mut start), Some(last)) = (first, last) else {
+        return Ok(());
+    };
+
+    loop {
+        let end = start + BATCH_SIZE;
+        let mut txn = conn.begin().await?;
+
+        sqlx::query(&format!("INSERT OR IGNORE INTO archive.{table} {rows}"))
+            .bind(from)
+            .bind(to)
+            .bind(start)
+            .bind(end)
+            .execute(&mut *txn)
+            .await?;
+
+        for payload_table in payload_tables {
+            sqlx::query(&format!(
+                "INSERT OR IGNORE INTO archive.{payload_table} SELECT * FROM main.{payload_table} WHERE mesh_packet_id IN (SELECT id FROM ({rows}))"
+            ))
+            .bind(from)
+            .bind(to)
+            .bind(start)
+            .bind(end)
+            .execute(&mut *txn)
+            .await?;
+        }
+
+        txn.commit().await?;
+
+        if end > last {
+            return Ok(());
+        }
+        start = end;
+
+        sleep(BATCH_PAUSE).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn count(conn: &mut SqliteConnection, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn archive_month_moves_packets_with_their_payloads() {
+        let june = parse_month("2025-06").unwrap();
+
+        // The archive is attached to a database file, attached to an in-memory database it would
+        // be in memory as well
+        let directory =
+            std::env::temp_dir().join(format!("meshstellar-archive-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let pool = SqlitePoolOptions::new()
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(directory.join("meshstellar.db"))
+                    .create_if_missing(true)
+                    .foreign_keys(true),
+            )
+            .await
+            .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+
+        // More packets than fit in a batch, and one packet of the next month
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2501)
+             INSERT INTO mesh_packets (gateway_id, from_id, to_id, channel_id, unique_id, portnum, payload_data, rx_time, rx_snr, rx_rssi, want_ack, want_response, priority, created_at, hash)
+             SELECT '!0000aaaa', 1, 4294967295, 0, i, 3, x'', 0, 0, 0, 0, 0, 0, CASE WHEN i <= 2500 THEN ?1 + i ELSE ?2 END, randomblob(32) FROM n",
+        )
+        .bind(month_start(june))
+        .bind(month_end(june))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO positions (mesh_packet_id, node_id) SELECT id, 1 FROM mesh_packets",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("meshstellar-archive-{}.db", std::process::id()));
+        let result = archive_month(&pool, &path, june).await;
+        let mut archive = SqliteConnectOptions::new()
+            .filename(&path)
+            .connect()
+            .await
+            .unwrap();
+        let archived = (
+            count(&mut archive, "mesh_packets").await,
+            count(&mut archive, "positions").await,
+        );
+        archive.close().await.unwrap();
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(archived, (2500, 2500));
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(count(&mut conn, "mesh_packets").await, 1);
+        assert_eq!(count(&mut conn, "positions").await, 1);
+    }
+
+    #[test]
+    fn parse_month_accepts_year_and_month() {
+        assert_eq!(
+            parse_month("2025-06").unwrap(),
+            NaiveDate::from_ymd_opt(2025, 6, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_month_rejects_other_formats() {
+        for month in ["2025-13", "2025-6-1", "2025-06-01", "June 2025", ""] {
+            assert!(parse_month(month).is_err(), "{}", month);
+        }
+    }
+
+    #[test]
+    fn month_end_is_the_start_of_the_next_month() {
+        let december = parse_month("2024-12").unwrap();
+        assert_eq!(
+            month_end(december),
+            month_start(parse_month("2025-01").unwrap())
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Online backups with `VACUUM INTO`. The copy is made in a read transaction, so the importer
+//! keeps writing in WAL mode while it runs.
+
+use crate::util::{config::get_config, DbPool};
+use anyhow::anyhow;
+use chrono::{NaiveDateTime, Utc};
+use serde::Deserialize;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteLockingMode},
+    ConnectOptions, Connection, SqliteConnection, SqlitePool,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+// Archives and the simulated database are named `meshstellar-*.db` as well, so only names with a
+// backup timestamp are rotated
+const FILE_PREFIX: &str = "meshstellar-backup-";
+const FILE_EXTENSION: &str = "db";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Deserialize)]
+struct BackupConfig {
+    directory: PathBuf,
+    #[serde(default = "default_interval_hours")]
+    interval_hours: u64,
+    /// The number of backups kept in the directory.
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_interval_hours() -> u64 {
+    24
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+fn backup_config() -> anyhow::Result<Option<BackupConfig>> {
+    match get_config().get("backup") {
+        Ok(config) => Ok(Some(config)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(err) => Err(anyhow!(err)),
+    }
+}
+
+/// Backs up the database according to the `backup` table. Returns immediately when it is not
+/// configured, PostgreSQL databases are backed up with its own tools.
+pub async fn start_backups(pool: DbPool) -> anyhow::Result<()> {
+    let Some(config) = backup_config()? else {
+        return Ok(());
+    };
+    let pool = pool.sqlite()?;
+
+    // The first backup is made after an interval, so restarts do not rotate the backups away
+    let period = Duration::from_secs(config.interval_hours.max(1) * 3600);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+
+        // A full disk or an unmounted directory should not stop the importer, the next backup
+        // is tried after an interval
+        if let Err(err) = backup_to_directory(pool, &config).await {
+            error!("Backup to {} failed: {:?}", config.directory.display(), err);
+        }
+    }
+}
+
+/// Makes a backup in the configured directory, for the `backup` command without a file.
+pub async fn backup_now(pool: &SqlitePool) -> anyhow::Result<PathBuf> {
+    let config = backup_config()?.ok_or(anyhow!(
+        "Configure a [backup] directory, or pass the file to back up to"
+    ))?;
+
+    backup_to_directory(pool, &config).await
+}
+
+async fn backup_to_directory(pool: &SqlitePool, config: &BackupConfig) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(&config.directory)?;
+
+    let file_name = format!(
+        "{}{}.{}",
+        FILE_PREFIX,
+        Utc::now().format(TIMESTAMP_FORMAT),
+        FILE_EXTENSION
+    );
+    let path = config.directory.join(file_name);
+
+    backup(pool, &path).await?;
+    rotate(&config.directory, config.keep)?;
+
+    Ok(path)
+}
+
+/// Writes a copy of the database to `path`, which must not exist yet.
+pub async fn backup(pool: &SqlitePool, path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        return Err(anyhow!("Backup file {} already exists", path.display()));
+    }
+
+    // Written under another name first, so an interrupted backup is not taken for a complete one
+    let partial_path = path.with_extension("partial");
+    let _ = fs::remove_file(&partial_path);
+
+    let started = Instant::now();
+    sqlx::query("VACUUM INTO ?")
+        .bind(partial_path.to_string_lossy().into_owned())
+        .execute(pool)
+        .await?;
+    fs::rename(&partial_path, path)?;
+
+    info!(
+        "Backed up the database to {} in {:.1}s",
+        path.display(),
+        started.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// Removes all but the newest `keep` backups.
+fn rotate(directory: &Path, keep: usize) -> anyhow::Result<()> {
+    let mut backups = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| Some((backup_time(&path)?, path)))
+        .collect::<Vec<_>>();
+    backups.sort();
+
+    let remove = backups.len().saturating_sub(keep.max(1));
+    for (_, path) in backups.into_iter().take(remove) {
+        fs::remove_file(&path)?;
+        info!("Removed old backup {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// The time in the name of a backup file, or `None` for other files.
+fn backup_time(path: &Path) -> Option<NaiveDateTime> {
+    let timestamp = path
+        .file_name()?
+        .to_str()?
+        .strip_prefix(FILE_PREFIX)?
+        .strip_suffix(FILE_EXTENSION)?
+        .strip_suffix('.')?;
+
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+/// The file of a `sqlite://` database URL.
+fn database_path(database_url: &str) -> anyhow::Result<PathBuf> {
+    let path = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))
+        .ok_or(anyhow!(
+            "Only supported with a sqlite:// database_url, use the PostgreSQL tools instead"
+        ))?;
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    if path.is_empty() || path == ":memory:" {
+        return Err(anyhow!("{} is an in-memory database", database_url));
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+/// Locks the database exclusively until the connection is closed. Every meshstellar process
+/// keeps connections open, so this fails while one of them is running.
+async fn lock_database(database_path: &Path) -> anyhow::Result<SqliteConnection> {
+    let mut connection = SqliteConnectOptions::new()
+        .filename(database_path)
+        .create_if_missing(false)
+        .locking_mode(SqliteLockingMode::Exclusive)
+        .busy_timeout(Duration::ZERO)
+        .connect()
+        .await?;
+
+    // In exclusive locking mode the lock of the first write transaction is held until the
+    // connection is closed
+    let in_use = || {
+        anyhow!(
+            "{} is in use, stop meshstellar first",
+            database_path.display()
+        )
+    };
+    sqlx::query("BEGIN EXCLUSIVE")
+        .execute(&mut connection)
+        .await
+        .map_err(|_| in_use())?;
+    sqlx::query("COMMIT").execute(&mut connection).await?;
+
+    Ok(connection)
+}
+
+/// Returns the database file, or fails while the database is in use.
+pub async fn stopped_database_path(database_url: &str) -> anyhow::Result<PathBuf> {
+    let database_path = database_path(database_url)?;
+
+    if database_path.exists() {
+        lock_database(&database_path).await?.close().await?;
+    }
+
+    Ok(database_path)
+}
+
+/// Replaces the database with a backup, meshstellar must not be running. The current database is
+/// kept next to it with a `.before-restore` extension.
+pub async fn restore(database_url: &str, backup_path: &Path) -> anyhow::Result<()> {
+    let database_path = database_path(database_url)?;
+    // Keeps meshstellar from starting until the backup is copied
+    let lock = match database_path.exists() {
+        true => Some(lock_database(&database_path).await?),
+        false => None,
+    };
+
+    let mut connection = SqliteConnectOptions::new()
+        .filename(backup_path)
+        .read_only(true)
+        .connect()
+        .await?;
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&mut connection)
+        .await?;
+    connection.close().await?;
+    if integrity != "ok" {
+        return Err(anyhow!(
+            "{} is damaged: {}",
+            backup_path.display(),
+            integrity
+        ));
+    }
+
+    let restore_path = database_path.with_extension("restore");
+    fs::copy(backup_path, &restore_path)?;
+
+    // Open files cannot be renamed on Windows
+    if let Some(lock) = lock {
+        lock.close().await?;
+    }
+
+    if database_path.exists() {
+        fs::rename(
+            &database_path,
+            database_path.with_extension("before-restore"),
+        )?;
+    }
+    for suffix in ["-wal", "-shm"] {
+        let mut path = database_path.clone().into_os_string();
+        path.push(suffix);
+        let _ = fs::remove_file(path);
+    }
+    fs::rename(&restore_path, &database_path)?;
+
+    info!(
+        "Restored {} from {}",
+        database_path.display(),
+        backup_path.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_time_parses_backup_names() {
+        assert_eq!(
+            backup_time(Path::new("backups/meshstellar-backup-20250601T020304Z.db")),
+            NaiveDateTime::parse_from_str("2025-06-01 02:03:04", "%Y-%m-%d %H:%M:%S").ok()
+        );
+    }
+
+    #[test]
+    fn backup_time_skips_other_files() {
+        for name in [
+            "meshstellar-2025-06.db",
+            "meshstellar-simulate.db",
+            "meshstellar.db",
+            "meshstellar-backup-20250601T020304Z.partial",
+            "meshstellar-backup-latest.db",
+        ] {
+            assert_eq!(backup_time(Path::new(name)), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rotate_keeps_other_files() {
+        let directory =
+            std::env::temp_dir().join(format!("meshstellar-rotate-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "meshstellar-2025-06.db",
+            "meshstellar-simulate.db",
+            "meshstellar-backup-20250601T000000Z.db",
+            "meshstellar-backup-20250602T000000Z.db",
+            "meshstellar-backup-20250603T000000Z.db",
+        ] {
+            fs::write(directory.join(name), b"").unwrap();
+        }
+
+        rotate(&directory, 2).unwrap();
+
+        let mut names = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            names,
+            [
+                "meshstellar-2025-06.db",
+                "meshstellar-backup-20250602T000000Z.db",
+                "meshstellar-backup-20250603T000000Z.db",
+                "meshstellar-simulate.db",
+            ]
+        );
+    }
+}
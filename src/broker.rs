@@ -0,0 +1,152 @@
+use crate::{
+    import::queue_service_envelope,
+    util::{
+        config::get_config, format_subscriptions, mqtt_subscriptions, mqtt_topic_exclusions, DbPool,
+    },
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use rumqttd::{local::LinkRx, Broker, Notification};
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::HashMap, thread};
+use tracing::{debug, error, info};
+
+#[derive(Deserialize)]
+struct EmbeddedBrokerConfig {
+    #[serde(default = "default_listen")]
+    listen: String,
+    /// Usernames and passwords of the clients, anonymous clients are accepted when empty.
+    #[serde(default)]
+    users: HashMap<String, String>,
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:1883".to_string()
+}
+
+fn default_max_connections() -> usize {
+    100
+}
+
+fn embedded_broker_config() -> anyhow::Result<Option<EmbeddedBrokerConfig>> {
+    match get_config().get("embedded_broker") {
+        Ok(config) => Ok(Some(config)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(err) => Err(anyhow!(err)),
+    }
+}
+
+/// Whether the `embedded_broker` table is configured, the broker then replaces the connection
+/// to an external broker.
+pub fn is_configured() -> anyhow::Result<bool> {
+    Ok(embedded_broker_config()?.is_some())
+}
+
+fn broker_config(config: &EmbeddedBrokerConfig) -> anyhow::Result<rumqttd::Config> {
+    let auth = if config.users.is_empty() {
+        None
+    } else {
+        Some(&config.users)
+    };
+
+    Ok(serde_json::from_value(json!({
+        "id": 0,
+        "router": {
+            "max_connections": config.max_connections,
+            "max_outgoing_packet_count": 200,
+            "max_segment_size": 104857600,
+            "max_segment_count": 10,
+        },
+        "v4": {
+            "1": {
+                "name": "meshstellar",
+                "listen": config.listen,
+                "next_connection_delay_ms": 1,
+                "connections": {
+                    "connection_timeout_ms": 60000,
+                    "max_payload_size": 102400,
+                    "max_inflight_count": 100,
+                    "auth": auth,
+                    "dynamic_filters": true,
+                },
+            },
+        },
+    }))?)
+}
+
+/// Runs an MQTT broker nodes can uplink to. Publishes on the subscribed topics are stored
+/// directly, other clients (and the downlink) use the broker like any other broker.
+pub async fn start_server(pool: DbPool) -> anyhow::Result<()> {
+    let config = embedded_broker_config()?.unwrap_or(EmbeddedBrokerConfig {
+        listen: default_listen(),
+        users: HashMap::new(),
+        max_connections: default_max_connections(),
+    });
+    let subscriptions = mqtt_subscriptions()?;
+    let exclusions = mqtt_topic_exclusions()?;
+
+    info!("Starting embedded MQTT broker @ {}", config.listen);
+
+    if config.users.is_empty() {
+        info!("No embedded_broker.users configured, accepting anonymous clients");
+    }
+
+    let mut broker = Broker::new(broker_config(&config)?);
+    let (mut link_tx, link_rx) = broker.link("meshstellar")?;
+
+    // The broker runs its own runtime on separate threads
+    thread::spawn(move || {
+        if let Err(err) = broker.start() {
+            error!("Embedded MQTT broker stopped: {:?}", err);
+        }
+    });
+
+    for subscription in &subscriptions {
+        link_tx.subscribe(subscription.path.clone())?;
+    }
+    info!(
+        "Storing messages published to {}",
+        format_subscriptions(&subscriptions)
+    );
+
+    store_publishes(pool, link_rx, exclusions).await
+}
+
+async fn store_publishes(
+    pool: DbPool,
+    mut link_rx: LinkRx,
+    exclusions: Vec<String>,
+) -> anyhow::Result<()> {
+    loop {
+        let Some(notification) = link_rx.next().await? else {
+            continue;
+        };
+
+        match notification {
+            Notification::Forward(forward) => {
+                let topic = String::from_utf8_lossy(&forward.publish.topic);
+
+                if exclusions
+                    .iter()
+                    .any(|exclusion| rumqttc::matches(&topic, exclusion))
+                {
+                    debug!("Skipping message on excluded topic {}", topic);
+                    continue;
+                }
+
+                let created_at = Utc::now().timestamp_nanos_opt().unwrap();
+                queue_service_envelope(
+                    &mut pool.acquire().await?.executor(),
+                    &forward.publish.payload,
+                    Some(&topic),
+                    created_at,
+                )
+                .await?;
+            }
+            notification => debug!("Broker notification: {:?}", notification),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Capture files hold the raw service envelopes with their topic and receive time, to reproduce
+//! an incident or a demo offline.
+
+use crate::{
+    archive,
+    import::{notify_importer, queue_service_envelope},
+    util::db::{on_db, Db, DbConnection, DbPool},
+};
+use anyhow::anyhow;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+    time::Duration,
+};
+use tokio::{sync::mpsc, task};
+use tracing::info;
+
+const BATCH_SIZE: i64 = 1000;
+/// Envelopes are at most as large as the MQTT packets meshstellar accepts, a larger length means
+/// the file is corrupt.
+const MAX_ENVELOPE_LENGTH: usize = 102400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Length-delimited `CapturedEnvelope` protobufs.
+    Delimited,
+    /// One JSON object per line, with the payload base64 encoded.
+    Jsonl,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> anyhow::Result<Format> {
+        match format {
+            "delimited" => Ok(Format::Delimited),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(anyhow!(
+                "Unknown capture format {}, use delimited or jsonl",
+                format
+            )),
+        }
+    }
+
+    /// Files ending in `.jsonl` or `.json` are JSONL, other files are length-delimited.
+    pub fn from_path(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl" | "json") => Format::Jsonl,
+            _ => Format::Delimited,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct CapturedEnvelope {
+    #[prost(bytes = "vec", tag = "1")]
+    payload: Vec<u8>,
+    #[prost(string, optional, tag = "2")]
+    topic: Option<String>,
+    /// Receive time in nanoseconds since the epoch.
+    #[prost(int64, tag = "3")]
+    created_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonlEnvelope {
+    payload: String,
+    topic: Option<String>,
+    created_at: i64,
+}
+
+/// Writes the service envelopes received since `since` (nanoseconds) to `path`, returns the
+/// number of exported envelopes. Archived envelopes are exported first, they are older than the
+/// ones in the database. Archives only exist for SQLite databases.
+pub async fn export(
+    pool: &DbPool,
+    path: &str,
+    format: Format,
+    since: i64,
+) -> anyhow::Result<usize> {
+    // The file is written on a blocking thread, the envelopes are sent to it in order
+    let (sender, mut receiver) = mpsc::channel::<CapturedEnvelope>(BATCH_SIZE as usize);
+    let path = path.to_string();
+    let writer = task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        while let Some(envelope) = receiver.blocking_recv() {
+            write_envelope(&mut writer, format, envelope)?;
+        }
+        writer.flush()?;
+        Ok(())
+    });
+
+    let result = send_envelopes(pool, &sender, since).await;
+    drop(sender);
+    // A write error ends the export as well, it is the cause of the failed send
+    writer.await??;
+
+    result
+}
+
+/// Sends the envelopes of the archives and the database to the writer, returns their number.
+async fn send_envelopes(
+    pool: &DbPool,
+    sender: &mpsc::Sender<CapturedEnvelope>,
+    since: i64,
+) -> anyhow::Result<usize> {
+    let mut conn = pool.acquire().await?;
+    let mut exported = 0;
+
+    if let Db::Sqlite(conn) = &mut conn {
+        for (month, archive_path) in archive::archive_files()? {
+            if archive::month_end(month) <= since {
+                continue;
+            }
+
+            archive::attach(conn, &archive_path).await?;
+            let result = export_envelopes(
+                &mut Db::Sqlite(&mut **conn),
+                "archive.service_envelopes",
+                sender,
+                since,
+            )
+            .await;
+            archive::detach(conn).await?;
+            exported += result?;
+        }
+    }
+
+    let table = pool.pick("main.service_envelopes", "service_envelopes");
+    exported += export_envelopes(&mut conn.executor(), table, sender, since).await?;
+
+    Ok(exported)
+}
+
+async fn export_envelopes(
+    conn: &mut DbConnection<'_>,
+    table: &str,
+    sender: &mpsc::Sender<CapturedEnvelope>,
+    since: i64,
+) -> anyhow::Result<usize> {
+    let query = format!(
+        "SELECT id, payload_data, topic, created_at FROM {} WHERE id > $1 AND created_at >= $2 ORDER BY id LIMIT $3",
+        table
+    );
+    let mut last_id = 0;
+    let mut exported = 0;
+
+    loop {
+        let envelopes: Vec<(i64, Vec<u8>, Option<String>, i64)> = on_db!(conn, |conn| {
+            sqlx::query_as(&query)
+                .bind(last_id)
+                .bind(since)
+                .bind(BATCH_SIZE)
+                .fetch_all(conn)
+                .await
+        })?;
+
+        let Some(last) = envelopes.last() else {
+            break;
+        };
+        last_id = last.0;
+
+        for (_, payload, topic, created_at) in envelopes {
+            let captured = CapturedEnvelope {
+                payload,
+                topic,
+                created_at,
+            };
+            sender
+                .send(captured)
+                .await
+                .map_err(|_| anyhow!("Writing the capture file failed"))?;
+            exported += 1;
+        }
+    }
+
+    Ok(exported)
+}
+
+fn write_envelope<W: Write>(
+    writer: &mut W,
+    format: Format,
+    envelope: CapturedEnvelope,
+) -> anyhow::Result<()> {
+    match format {
+        Format::Delimited => writer.write_all(&envelope.encode_length_delimited_to_vec())?,
+        Format::Jsonl => {
+            let line = JsonlEnvelope {
+                payload: BASE64_STANDARD.encode(&envelope.payload),
+                topic: envelope.topic,
+                created_at: envelope.created_at,
+            };
+            serde_json::to_writer(&mut *writer, &line)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the next envelope, returns `None` at the end of the file.
+fn read_envelope<R: BufRead>(
+    reader: &mut R,
+    format: Format,
+) -> anyhow::Result<Option<CapturedEnvelope>> {
+    match format {
+        Format::Delimited => {
+            // The length is a varint of at most 10 bytes
+            let mut length = 0usize;
+            for index in 0..10 {
+                let mut byte = [0u8; 1];
+                match reader.read_exact(&mut byte) {
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof && index == 0 => {
+                        return Ok(None)
+                    }
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                        return Err(anyhow!(
+                            "The capture file ends in the middle of an envelope"
+                        ))
+                    }
+                    result => result?,
+                }
+
+                length |= ((byte[0] & 0x7f) as usize) << (index * 7);
+                if length > MAX_ENVELOPE_LENGTH {
+                    return Err(anyhow!(
+                        "Envelope of more than {} bytes, the capture file is corrupt",
+                        MAX_ENVELOPE_LENGTH
+                    ));
+                }
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+                if index == 9 {
+                    return Err(anyhow!(
+                        "Invalid envelope length, the capture file is corrupt"
+                    ));
+                }
+            }
+
+            let mut buf = vec![0u8; length];
+            match reader.read_exact(&mut buf) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(anyhow!(
+                        "The capture file ends in the middle of an envelope"
+                    ))
+                }
+                result => result?,
+            }
+
+            Ok(Some(CapturedEnvelope::decode(buf.as_slice())?))
+        }
+        Format::Jsonl => loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let envelope: JsonlEnvelope = serde_json::from_str(&line)?;
+            return Ok(Some(CapturedEnvelope {
+                payload: BASE64_STANDARD.decode(envelope.payload)?,
+                topic: envelope.topic,
+                created_at: envelope.created_at,
+            }));
+        },
+    }
+}
+
+/// Inserts the envelopes of a capture file for import. Without a `speed` they are inserted at
+/// once with their original receive time. With a `speed` the original time between the envelopes
+/// is kept (divided by `speed`), and they are stored as received now, so the web interface shows
+/// them as live traffic.
+pub async fn replay(
+    pool: &DbPool,
+    path: &str,
+    format: Format,
+    speed: Option<f64>,
+) -> anyhow::Result<usize> {
+    // The file is read on a blocking thread, a few batches ahead
+    let (sender, mut receiver) = mpsc::channel::<CapturedEnvelope>(BATCH_SIZE as usize);
+    let path = path.to_string();
+    let reader = task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        while let Some(envelope) = read_envelope(&mut reader, format)? {
+            if sender.blocking_send(envelope).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let result = queue_envelopes(pool, &mut receiver, speed).await;
+    drop(receiver);
+    // A read error ends the replay as well, the envelopes before it are queued
+    reader.await??;
+
+    result
+}
+
+async fn queue_envelopes(
+    pool: &DbPool,
+    receiver: &mut mpsc::Receiver<CapturedEnvelope>,
+    speed: Option<f64>,
+) -> anyhow::Result<usize> {
+    let mut replayed = 0;
+
+    match speed {
+        None => loop {
+            let mut txn = pool.begin().await?;
+            let mut inserted = 0;
+
+            while inserted < BATCH_SIZE {
+                let Some(envelope) = receiver.recv().await else {
+                    break;
+                };
+
+                queue_service_envelope(
+                    &mut txn.executor(),
+                    &envelope.payload,
+                    envelope.topic.as_deref(),
+                    envelope.created_at,
+                )
+                .await?;
+                inserted += 1;
+            }
+
+            txn.commit().await?;
+            notify_importer();
+            replayed += inserted as usize;
+
+            if inserted < BATCH_SIZE {
+                break;
+            }
+            info!("Replayed {} envelopes", replayed);
+        },
+        Some(speed) => {
+            let mut previous_created_at: Option<i64> = None;
+
+            while let Some(envelope) = receiver.recv().await {
+                if let Some(previous_created_at) = previous_created_at {
+                    let delay = (envelope.created_at - previous_created_at).max(0) as f64 / speed;
+                    tokio::time::sleep(Duration::from_nanos(delay as u64)).await;
+                }
+                previous_created_at = Some(envelope.created_at);
+
+                let created_at = Utc::now().timestamp_nanos_opt().unwrap();
+                queue_service_envelope(
+                    &mut pool.acquire().await?.executor(),
+                    &envelope.payload,
+                    envelope.topic.as_deref(),
+                    created_at,
+                )
+                .await?;
+                replayed += 1;
+            }
+        }
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn envelopes() -> Vec<CapturedEnvelope> {
+        vec![
+            CapturedEnvelope {
+                payload: vec![0x0a, 0x02, 0x08, 0x01],
+                topic: Some("msh/EU_868/2/e/LongFast/!deadbeef".to_string()),
+                created_at: 1_700_000_000_000_000_000,
+            },
+            CapturedEnvelope {
+                payload: vec![0u8; 300],
+                topic: None,
+                created_at: 1_700_000_001_000_000_000,
+            },
+        ]
+    }
+
+    fn round_trip(format: Format) -> Vec<CapturedEnvelope> {
+        let mut file = Vec::new();
+        for envelope in envelopes() {
+            write_envelope(&mut file, format, envelope).unwrap();
+        }
+
+        let mut reader = Cursor::new(file);
+        let mut read = Vec::new();
+        while let Some(envelope) = read_envelope(&mut reader, format).unwrap() {
+            read.push(envelope);
+        }
+        read
+    }
+
+    #[test]
+    fn envelopes_are_read_as_written() {
+        assert_eq!(round_trip(Format::Delimited), envelopes());
+        assert_eq!(round_trip(Format::Jsonl), envelopes());
+    }
+
+    #[test]
+    fn blank_lines_are_skipped_in_jsonl() {
+        let mut file = b"\n".to_vec();
+        write_envelope(&mut file, Format::Jsonl, envelopes().remove(0)).unwrap();
+        file.extend_from_slice(b"\n\n");
+
+        let mut reader = Cursor::new(file);
+        assert!(read_envelope(&mut reader, Format::Jsonl).unwrap().is_some());
+        assert!(read_envelope(&mut reader, Format::Jsonl).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_lengths_are_rejected() {
+        // A varint of 2^32 would otherwise allocate 4 GiB
+        let mut reader = Cursor::new(vec![0x80, 0x80, 0x80, 0x80, 0x10, 0x00]);
+        assert!(read_envelope(&mut reader, Format::Delimited).is_err());
+
+        let mut reader = Cursor::new(vec![0xff; 16]);
+        assert!(read_envelope(&mut reader, Format::Delimited).is_err());
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let file = envelopes()[0].encode_length_delimited_to_vec();
+
+        let mut reader = Cursor::new(file[..file.len() - 1].to_vec());
+        assert!(read_envelope(&mut reader, Format::Delimited).is_err());
+
+        let mut reader = Cursor::new(vec![0x80]);
+        assert!(read_envelope(&mut reader, Format::Delimited).is_err());
+    }
+
+    #[test]
+    fn the_format_follows_the_extension() {
+        assert_eq!(Format::from_path("capture.jsonl"), Format::Jsonl);
+        assert_eq!(Format::from_path("capture.json"), Format::Jsonl);
+        assert_eq!(Format::from_path("capture.bin"), Format::Delimited);
+        assert!(Format::parse("csv").is_err());
+    }
+}
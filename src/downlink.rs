@@ -0,0 +1,290 @@
+use crate::{
+    proto::meshtastic::{
+        mesh_packet::PayloadVariant, Data, MeshPacket, PortNum, RouteDiscovery, ServiceEnvelope,
+    },
+    radio::{connected_radio, tcp},
+    util::{
+        config::get_config,
+        connect_to_mqtt_publisher,
+        crypto::{self, Channel},
+        parse_node_id, NODENUM_BROADCAST,
+    },
+};
+use anyhow::anyhow;
+use prost::Message;
+use rumqttc::{AsyncClient, QoS};
+use serde::Deserialize;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+};
+use tracing::info;
+
+/// Maximum length of a text message, the payload of a packet is limited to 233 bytes.
+pub const MAX_TEXT_MESSAGE_BYTES: usize = 200;
+
+#[derive(Deserialize)]
+struct DownlinkConfig {
+    /// Root of the downlink topic, e.g. `msh/EU_868/2/e`. Packets are published to
+    /// `{topic}/{channel}/{node_id}`.
+    topic: Option<String>,
+    /// Node id used as sender of the packets and as gateway id of the service envelopes.
+    node_id: Option<String>,
+    /// One of the `radio_tcp_hosts`, used instead of the broker.
+    radio: Option<String>,
+    #[serde(default = "default_hop_limit")]
+    hop_limit: u32,
+}
+
+fn default_hop_limit() -> u32 {
+    3
+}
+
+#[derive(Clone)]
+enum Transport {
+    Mqtt {
+        client: AsyncClient,
+        topic: String,
+        node_id: u32,
+    },
+    Radio {
+        name: String,
+    },
+}
+
+/// A packet sent into the mesh.
+pub struct SentPacket {
+    pub id: u32,
+    pub from: u32,
+}
+
+/// Sends packets into the mesh by publishing them to the downlink topic of a gateway node, or
+/// through a radio connected over TCP.
+#[derive(Clone)]
+pub struct Downlink {
+    transport: Transport,
+    hop_limit: u32,
+}
+
+impl Downlink {
+    /// Connects to the broker if the `downlink` table is configured.
+    pub fn from_config() -> anyhow::Result<Option<Downlink>> {
+        let config: DownlinkConfig = match get_config().get("downlink") {
+            Ok(config) => config,
+            Err(config::ConfigError::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(anyhow!(err)),
+        };
+
+        let transport = match (config.radio, config.topic, config.node_id) {
+            (Some(radio), _, _) => {
+                // Serial radios run in a process of their own, only the TCP radios of the `all`
+                // mode are connected next to the web interface
+                let name = tcp::with_default_port(&radio);
+                if !tcp::configured_addresses()?.contains(&name) {
+                    return Err(anyhow!(
+                        "downlink.radio {} should be one of the radio_tcp_hosts",
+                        radio
+                    ));
+                }
+
+                info!("Downlink enabled through radio {}", name);
+
+                Transport::Radio { name }
+            }
+            (None, Some(topic), Some(node_id)) => {
+                let node_id = parse_node_id(&node_id)
+                    .ok_or(anyhow!("Invalid downlink node_id: {}", node_id))?;
+
+                info!("Downlink enabled on {} as !{:08x}", topic, node_id);
+
+                Transport::Mqtt {
+                    client: connect_to_mqtt_publisher()?,
+                    topic: topic.trim_end_matches('/').to_string(),
+                    node_id,
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Configure either downlink.radio, or downlink.topic and downlink.node_id"
+                ));
+            }
+        };
+
+        Ok(Some(Downlink {
+            transport,
+            hop_limit: config.hop_limit,
+        }))
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        match &self.transport {
+            Transport::Mqtt { .. } => crypto::configured_channels()
+                .iter()
+                .map(|channel| channel.name.clone())
+                .collect(),
+            Transport::Radio { name } => connected_radio(name)
+                .map(|radio| radio.channels.into_iter().map(|(_, name)| name).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Sends a text message to a channel (`to` is [`NODENUM_BROADCAST`]) or a single node.
+    pub async fn send_text_message(
+        &self,
+        channel_name: &str,
+        to: u32,
+        text: &str,
+    ) -> anyhow::Result<SentPacket> {
+        if text.is_empty() || text.len() > MAX_TEXT_MESSAGE_BYTES {
+            return Err(anyhow!(
+                "Text messages should be between 1 and {} bytes",
+                MAX_TEXT_MESSAGE_BYTES
+            ));
+        }
+
+        let data = Data {
+            portnum: PortNum::TextMessageApp as i32,
+            payload: text.as_bytes().to_vec(),
+            ..Default::default()
+        };
+
+        self.send(channel_name, to, data).await
+    }
+
+    /// Sends a traceroute request to a node. The id of the sent packet is the `request_id` of
+    /// the response.
+    pub async fn send_traceroute(&self, channel_name: &str, to: u32) -> anyhow::Result<SentPacket> {
+        let data = Data {
+            portnum: PortNum::TracerouteApp as i32,
+            payload: RouteDiscovery::default().encode_to_vec(),
+            want_response: true,
+            ..Default::default()
+        };
+
+        self.send(channel_name, to, data).await
+    }
+
+    async fn send(&self, channel_name: &str, to: u32, data: Data) -> anyhow::Result<SentPacket> {
+        match &self.transport {
+            Transport::Mqtt {
+                client,
+                topic,
+                node_id,
+            } => {
+                self.publish(client, topic, *node_id, channel_name, to, data)
+                    .await
+            }
+            Transport::Radio { name } => self.send_to_radio(name, channel_name, to, data).await,
+        }
+    }
+
+    async fn publish(
+        &self,
+        client: &AsyncClient,
+        topic: &str,
+        node_id: u32,
+        channel_name: &str,
+        to: u32,
+        data: Data,
+    ) -> anyhow::Result<SentPacket> {
+        let channel: &Channel = crypto::configured_channels()
+            .iter()
+            .find(|channel| channel.name == channel_name)
+            .ok_or(anyhow!("Unknown channel {}", channel_name))?;
+
+        let id = next_packet_id();
+        let encrypted =
+            crypto::apply_channel_cipher(&channel.key, id, node_id, &data.encode_to_vec()).ok_or(
+                anyhow!("Cannot encrypt packet for channel {}", channel_name),
+            )?;
+
+        let packet = MeshPacket {
+            from: node_id,
+            to,
+            channel: channel.hash,
+            id,
+            hop_limit: self.hop_limit,
+            hop_start: self.hop_limit,
+            want_ack: to != NODENUM_BROADCAST,
+            payload_variant: Some(PayloadVariant::Encrypted(encrypted)),
+            ..Default::default()
+        };
+
+        let gateway_id = format!("!{:08x}", node_id);
+        let topic = format!("{}/{}/{}", topic, channel.name, gateway_id);
+        let envelope = ServiceEnvelope {
+            packet: Some(packet),
+            channel_id: channel.name.clone(),
+            gateway_id,
+        };
+
+        client
+            .publish(&topic, QoS::AtLeastOnce, false, envelope.encode_to_vec())
+            .await?;
+
+        info!("Sent packet {} to !{:08x} via {}", id, to, topic);
+
+        Ok(SentPacket { id, from: node_id })
+    }
+
+    /// The radio encrypts the packet with its own channel settings, it only needs the index of
+    /// the channel.
+    async fn send_to_radio(
+        &self,
+        name: &str,
+        channel_name: &str,
+        to: u32,
+        data: Data,
+    ) -> anyhow::Result<SentPacket> {
+        let radio = connected_radio(name).ok_or(anyhow!("Radio {} is not connected", name))?;
+
+        let (channel_index, _) = radio
+            .channels
+            .iter()
+            .find(|(_, name)| name == channel_name)
+            .ok_or(anyhow!("Unknown channel {}", channel_name))?;
+
+        let id = next_packet_id();
+        let packet = MeshPacket {
+            from: radio.node_num,
+            to,
+            channel: *channel_index,
+            id,
+            hop_limit: self.hop_limit,
+            hop_start: self.hop_limit,
+            want_ack: to != NODENUM_BROADCAST,
+            payload_variant: Some(PayloadVariant::Decoded(data)),
+            ..Default::default()
+        };
+
+        radio.send(packet).await?;
+
+        info!("Sent packet {} to !{:08x} via {}", id, to, name);
+
+        Ok(SentPacket {
+            id,
+            from: radio.node_num,
+        })
+    }
+}
+
+/// Packet ids only need to be unique for a while. Like the firmware does, they count up from a
+/// random start, the keys of `RandomState` come from the random source of the operating system.
+fn next_packet_id() -> u32 {
+    static NEXT_PACKET_ID: OnceLock<AtomicU32> = OnceLock::new();
+
+    let next_packet_id = NEXT_PACKET_ID
+        .get_or_init(|| AtomicU32::new(RandomState::new().build_hasher().finish() as u32));
+
+    loop {
+        let id = next_packet_id.fetch_add(1, Ordering::Relaxed);
+        // Zero is not a valid packet id
+        if id != 0 {
+            return id;
+        }
+    }
+}
@@ -1,6 +1,4 @@
-use sqlx::sqlite::SqliteRow;
-use sqlx::FromRow;
-use sqlx::Row;
+use sqlx::{ColumnIndex, Decode, FromRow, Row, Type};
 
 use crate::proto::meshtastic::{mesh_packet::Priority, PortNum};
 use crate::util::capitalize;
@@ -54,8 +52,15 @@ fn parse_hexadecimal_id(input: &str) -> Option<u32> {
         .and_then(|hex| u32::from_str_radix(hex, 16).ok())
 }
 
-impl FromRow<'_, SqliteRow> for MeshPacket {
-    fn from_row(row: &SqliteRow) -> Result<Self, sqlx::Error> {
+impl<'r, R: Row> FromRow<'r, R> for MeshPacket
+where
+    &'r str: ColumnIndex<R>,
+    i64: Decode<'r, R::Database> + Type<R::Database>,
+    f64: Decode<'r, R::Database> + Type<R::Database>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+    Vec<u8>: Decode<'r, R::Database> + Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
         let id = row.try_get::<i64, _>("id").unwrap_or_default();
         let from_id = row.try_get::<i64, _>("from_id").unwrap_or_default();
         let to_id = row.try_get::<i64, _>("to_id").unwrap_or_default();
@@ -2,6 +2,7 @@ pub mod device_metrics_select_result;
 pub mod environment_metrics_select_result;
 pub mod gateway_packet_info;
 pub mod mesh_packet;
+pub mod neighbor_link_select_result;
 pub mod neighbor_select_result;
 pub mod node_select_result;
 pub mod plot_data;
@@ -9,15 +10,18 @@ pub mod position_select_result;
 pub mod power_metrics_select_result;
 pub mod returning_id;
 pub mod routing_dto;
+pub mod search_result;
 pub mod service_envelope_select_result;
 pub mod stats_select_result;
 pub mod trace_route_dto;
+pub mod traceroute_probe_select_result;
 pub mod waypoint_select_result;
 
 pub use device_metrics_select_result::DeviceMetricsSelectResult;
 pub use environment_metrics_select_result::EnvironmentMetricsSelectResult;
 pub use gateway_packet_info::GatewayPacketInfo;
 pub use mesh_packet::MeshPacket;
+pub use neighbor_link_select_result::NeighborLinkSelectResult;
 pub use neighbor_select_result::NeighborSelectResult;
 pub use node_select_result::NodeSelectResult;
 pub use plot_data::PlotData;
@@ -25,7 +29,9 @@ pub use position_select_result::PositionSelectResult;
 pub use power_metrics_select_result::PowerMetricsSelectResult;
 pub use returning_id::ReturningId;
 pub use routing_dto::RoutingDto;
+pub use search_result::{Highlight, NodeSearchResult, PacketSearchResult};
 pub use service_envelope_select_result::ServiceEnvelopeSelectResult;
 pub use stats_select_result::StatsSelectResult;
 pub use trace_route_dto::TracerouteDto;
+pub use traceroute_probe_select_result::TracerouteProbeSelectResult;
 pub use waypoint_select_result::WaypointSelectResult;
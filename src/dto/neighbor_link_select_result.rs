@@ -0,0 +1,11 @@
+use sqlx::FromRow;
+
+/// The latest state of a link to a neighbor the node reported.
+#[derive(Clone, Debug, FromRow)]
+pub struct NeighborLinkSelectResult {
+    pub neighbor_node_id: i64,
+    pub snr: Option<f64>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub observations: i64,
+}
@@ -0,0 +1,33 @@
+use crate::proto::meshtastic::PortNum;
+use sqlx::FromRow;
+
+/// The matches in the name columns are marked, see `search::highlight`.
+#[derive(Clone, Debug, FromRow)]
+pub struct NodeSearchResult {
+    pub node_id: i64,
+    pub long_name: String,
+    pub short_name: String,
+    pub user_id: String,
+}
+
+/// A text message or waypoint, with a marked snippet of the text around the matches.
+#[derive(Clone, Debug, FromRow)]
+pub struct PacketSearchResult {
+    pub id: i64,
+    pub from_id: i64,
+    pub portnum: i64,
+    pub received_at: i64,
+    pub snippet: String,
+}
+
+impl PacketSearchResult {
+    pub fn is_waypoint(&self) -> bool {
+        self.portnum == PortNum::WaypointApp as i64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Highlight {
+    Text(String),
+    Match(String),
+}
@@ -7,4 +7,6 @@ pub struct TracerouteDto {
     pub route_back: Vec<u32>,
     pub snr_towards: Vec<i32>,
     pub snr_back: Vec<i32>,
+    /// Round trip time in seconds, for responses to traceroutes sent by meshstellar.
+    pub round_trip_seconds: Option<f64>,
 }
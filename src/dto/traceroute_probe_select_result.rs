@@ -0,0 +1,16 @@
+use sqlx::FromRow;
+
+#[derive(Clone, Debug, FromRow)]
+pub struct TracerouteProbeSelectResult {
+    pub scheduled: i64,
+    pub created_at: i64,
+    pub response_mesh_packet_id: Option<i64>,
+    pub responded_at: Option<i64>,
+}
+
+impl TracerouteProbeSelectResult {
+    pub fn round_trip_seconds(&self) -> Option<f64> {
+        self.responded_at
+            .map(|responded_at| (responded_at - self.created_at) as f64 / 1_000_000_000.0)
+    }
+}
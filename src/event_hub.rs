@@ -0,0 +1,157 @@
+//! Fans out the server-sent events of the web interface. The producers query the database and
+//! render each update once, the hub keeps a snapshot for new clients and broadcasts the updates to
+//! the connected clients.
+
+use async_stream::stream;
+use axum::response::sse::Event;
+use futures::stream::Stream;
+use itertools::Itertools;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// The number of updates a client can fall behind. A client that falls further behind is
+/// disconnected, its browser reconnects and gets a new snapshot.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// The number of packets and of text messages in the snapshot, like the packet list shows.
+const SNAPSHOT_PACKETS: usize = 100;
+
+#[derive(Clone, Debug)]
+struct Update {
+    event: &'static str,
+    data: Arc<str>,
+}
+
+impl Update {
+    fn new(event: &'static str, data: String) -> Update {
+        Update {
+            event,
+            data: data.into(),
+        }
+    }
+
+    fn to_event(&self) -> Event {
+        Event::default().event(self.event).data(&*self.data)
+    }
+}
+
+#[derive(Default)]
+struct Snapshot {
+    /// The latest update of each node, with the time it was updated.
+    nodes: HashMap<i64, (i64, Update)>,
+    /// The latest packets and text messages by id.
+    packets: VecDeque<(i64, Update)>,
+    text_messages: VecDeque<(i64, Update)>,
+    stats: Option<Update>,
+}
+
+impl Snapshot {
+    /// The nodes come first, so the packets can show their names.
+    fn updates(&self) -> Vec<Update> {
+        let nodes = self
+            .nodes
+            .values()
+            .sorted_by_key(|(updated_at, _)| *updated_at)
+            .map(|(_, update)| update.clone());
+        let packets = self
+            .packets
+            .iter()
+            .merge_by(&self.text_messages, |(a, _), (b, _)| a < b)
+            .map(|(_, update)| update.clone());
+
+        nodes.chain(self.stats.clone()).chain(packets).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct EventHub {
+    snapshot: Arc<Mutex<Snapshot>>,
+    sender: broadcast::Sender<Update>,
+}
+
+impl Default for EventHub {
+    fn default() -> EventHub {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        EventHub {
+            snapshot: Arc::new(Mutex::new(Snapshot::default())),
+            sender,
+        }
+    }
+}
+
+impl EventHub {
+    pub fn publish_node(&self, node_id: i64, updated_at: i64, data: String) {
+        let update = Update::new("update-node", data);
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.nodes.insert(node_id, (updated_at, update.clone()));
+        self.send(update);
+    }
+
+    /// Leaves a node out of the snapshot, e.g. when it is hidden by the ingest rules.
+    pub fn remove_node(&self, node_id: i64) {
+        self.snapshot.lock().unwrap().nodes.remove(&node_id);
+    }
+
+    pub fn publish_packet(&self, id: i64, data: String) {
+        let update = Update::new("mesh-packet", data);
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        push_bounded(&mut snapshot.packets, (id, update.clone()));
+        self.send(update);
+    }
+
+    pub fn publish_text_message(&self, id: i64, data: String) {
+        let update = Update::new("text-message", data);
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        push_bounded(&mut snapshot.text_messages, (id, update.clone()));
+        self.send(update);
+    }
+
+    pub fn publish_stats(&self, data: String) {
+        let update = Update::new("statistics", data);
+
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.stats = Some(update.clone());
+        self.send(update);
+    }
+
+    /// Sending fails when no client is connected, the update is in the snapshot anyway.
+    fn send(&self, update: Update) {
+        let _ = self.sender.send(update);
+    }
+
+    /// Returns the snapshot followed by the live updates. The snapshot is taken while holding the
+    /// lock the producers publish under, so no update is missed or sent twice.
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Event, Infallible>> + use<> {
+        let (updates, mut receiver) = {
+            let snapshot = self.snapshot.lock().unwrap();
+            (snapshot.updates(), self.sender.subscribe())
+        };
+
+        stream! {
+            for update in updates {
+                yield Ok(update.to_event());
+            }
+
+            // A lagging client is disconnected, it gets a new snapshot when it reconnects
+            while let Ok(update) = receiver.recv().await {
+                yield Ok(update.to_event());
+            }
+        }
+    }
+}
+
+fn push_bounded(updates: &mut VecDeque<(i64, Update)>, update: (i64, Update)) {
+    updates.push_back(update);
+
+    if updates.len() > SNAPSHOT_PACKETS {
+        updates.pop_front();
+    }
+}
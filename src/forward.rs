@@ -0,0 +1,215 @@
+use crate::{
+    mqtt_processor::{self, Destination},
+    proto::meshtastic::ServiceEnvelope,
+    util::db::{on_db, DbPool},
+};
+use anyhow::anyhow;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use itertools::Itertools;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use prost::Message;
+use reqwest::{Client, StatusCode};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+const BATCH_SIZE: i64 = 100;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// Topics are percent encoded in the `x-meshstellar-topics` header, keeping them readable.
+const TOPIC_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'!')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.');
+
+/// Stores an envelope in the `forward_queue` until it is uploaded. Messages that are no service
+/// envelope would be rejected by the central instance, so they are skipped right away.
+pub async fn spool_envelope(
+    pool: &DbPool,
+    raw_message: &[u8],
+    topic: &str,
+    created_at: i64,
+) -> anyhow::Result<()> {
+    if ServiceEnvelope::decode(raw_message).is_err() {
+        debug!("Not forwarding invalid service envelope on {}", topic);
+        return Ok(());
+    }
+
+    on_db!(pool, |pool| {
+        sqlx::query(
+            "INSERT INTO forward_queue (payload_data, topic, created_at) VALUES ($1, $2, $3)",
+        )
+        .bind(raw_message)
+        .bind(topic)
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .map(|_| ())
+    })?;
+
+    Ok(())
+}
+
+struct Uploader {
+    client: Client,
+    endpoint: Url,
+    credentials: Option<(String, String)>,
+}
+
+impl Uploader {
+    /// `to` is the URL of the central instance, credentials for its `web_username` and
+    /// `web_password` are taken from the URL.
+    fn new(to: &str) -> anyhow::Result<Uploader> {
+        let mut url = Url::parse(to)?;
+
+        let credentials = match (url.username(), url.password()) {
+            ("", _) => None,
+            (username, password) => Some((
+                username.to_string(),
+                password.unwrap_or_default().to_string(),
+            )),
+        };
+        url.set_username("")
+            .and_then(|_| url.set_password(None))
+            .map_err(|_| anyhow!("Invalid forward URL {}", to))?;
+
+        let mut endpoint = if url.path().ends_with("/api/ingest") {
+            url
+        } else {
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            url.join("api/ingest")?
+        };
+        endpoint
+            .query_pairs_mut()
+            .clear()
+            .append_pair("format", "delimited");
+
+        Ok(Uploader {
+            client: Client::builder().timeout(Duration::from_secs(60)).build()?,
+            endpoint,
+            credentials,
+        })
+    }
+
+    /// Uploads the oldest envelopes in the queue, returns the number of uploaded envelopes.
+    async fn upload_batch(&self, pool: &DbPool) -> anyhow::Result<usize> {
+        let envelopes: Vec<(i64, Vec<u8>, Option<String>, i64)> = on_db!(pool, |pool| {
+            sqlx::query_as(
+                "SELECT id, payload_data, topic, created_at FROM forward_queue ORDER BY id LIMIT $1",
+            )
+            .bind(BATCH_SIZE)
+            .fetch_all(pool)
+            .await
+        })?;
+
+        let Some(last_id) = envelopes.last().map(|(id, ..)| *id) else {
+            return Ok(0);
+        };
+
+        let mut body = Vec::new();
+        for (_, payload_data, ..) in &envelopes {
+            prost::encode_length_delimiter(payload_data.len(), &mut body)?;
+            body.extend_from_slice(payload_data);
+        }
+
+        let topics = envelopes
+            .iter()
+            .map(|(_, _, topic, _)| {
+                utf8_percent_encode(topic.as_deref().unwrap_or_default(), TOPIC_ENCODE_SET)
+            })
+            .join(",");
+        let received_at = envelopes
+            .iter()
+            .map(|(.., created_at)| {
+                Utc.timestamp_nanos(*created_at)
+                    .to_rfc3339_opts(SecondsFormat::Nanos, true)
+            })
+            .join(",");
+
+        let mut request = self
+            .client
+            .post(self.endpoint.clone())
+            .header("x-meshstellar-topics", topics)
+            .header("x-meshstellar-received-at", received_at)
+            .body(body);
+
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                return Err(anyhow!(
+                    "Central instance rejected the credentials ({})",
+                    response.status()
+                ))
+            }
+            status => {
+                return Err(anyhow!(
+                    "Upload failed ({}): {}",
+                    status,
+                    response.text().await.unwrap_or_default()
+                ))
+            }
+        }
+
+        on_db!(pool, |pool| {
+            sqlx::query("DELETE FROM forward_queue WHERE id <= $1")
+                .bind(last_id)
+                .execute(pool)
+                .await
+                .map(|_| ())
+        })?;
+
+        Ok(envelopes.len())
+    }
+}
+
+/// Uploads the queue, backing off while the central instance cannot be reached. The queue is
+/// stored in the database, so uploading resumes where it left off after a restart.
+async fn upload_queue(pool: DbPool, uploader: Uploader) -> anyhow::Result<()> {
+    let mut retry_delay = Duration::from_secs(1);
+
+    loop {
+        match uploader.upload_batch(&pool).await {
+            Ok(0) => sleep(Duration::from_secs(1)).await,
+            Ok(uploaded) => {
+                debug!("Forwarded {} envelopes", uploaded);
+                retry_delay = Duration::from_secs(1);
+            }
+            Err(err) => {
+                error!("Error while forwarding envelopes: {:?}", err);
+                warn!("Retrying in {} seconds", retry_delay.as_secs());
+
+                sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Subscribes to the local broker and forwards every envelope to a central instance.
+pub async fn start_server(pool: DbPool, to: String) -> anyhow::Result<()> {
+    let uploader = Uploader::new(&to)?;
+    info!("Forwarding envelopes to {}", uploader.endpoint);
+
+    let queued: i64 = on_db!(pool, |pool| {
+        sqlx::query_scalar("SELECT COUNT(*) FROM forward_queue")
+            .fetch_one(pool)
+            .await
+    })?;
+    if queued > 0 {
+        info!("Resuming with {} queued envelopes", queued);
+    }
+
+    tokio::select! {
+        result = mqtt_processor::subscribe(pool.clone(), Destination::Forward) => result,
+        result = upload_queue(pool, uploader) => result,
+    }
+}
@@ -1,37 +1,46 @@
 use crate::{
+    backup,
     dto::{ReturningId, ServiceEnvelopeSelectResult},
+    ingest_rules::{IngestRules, RuleAction, RuleMatch},
     proto::{
         self,
         meshtastic::{
             mesh_packet::PayloadVariant::Decoded, routing, telemetry, MeshPacket, NeighborInfo,
-            PortNum, Position, RouteDiscovery, Routing, ServiceEnvelope, Telemetry, User, Waypoint,
+            NodeInfo, PortNum, Position, RouteDiscovery, Routing, ServiceEnvelope, Telemetry, User,
+            Waypoint,
         },
     },
-    util::{none_if_default, DB},
+    recent_packets::{RecentPackets, REPEAT_WINDOW_SECONDS},
+    retention, rollups, search,
+    util::{
+        config::get_config,
+        crypto,
+        db::{on_db, DbConnection, DbPool},
+        none_if_default,
+    },
 };
-use aes::Aes128;
 use anyhow::anyhow;
-use base64::Engine;
 use chrono::Utc;
-use ctr::{
-    cipher::{KeyIvInit, StreamCipher},
-    Ctr128BE,
-};
+use itertools::Itertools;
 use prost::Message;
-use sqlx::pool::PoolConnection;
-use sqlx::{Row, SqliteConnection, SqlitePool};
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::HashSet,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use tokio::time;
-use tokio_stream::{wrappers::IntervalStream, StreamExt};
-use tracing::{error, info, warn};
+use tokio::{
+    sync::{mpsc, Notify},
+    task, time,
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, Clone, Error)]
 #[error("Mesh packet processing error: {0}")]
 struct MeshPacketProcessingError(String);
 
 async fn ensure_node_exists(
-    txn: &mut SqliteConnection,
+    txn: &mut DbConnection<'_>,
     packet: &MeshPacket,
     received_at: i64,
 ) -> anyhow::Result<()> {
@@ -39,52 +48,160 @@ async fn ensure_node_exists(
         let user_id = format!("!{:08x}", packet.from);
         let rx_time = packet.rx_time as i64 * 1_000_000_000;
 
-        let _result = sqlx::query!(
-            "INSERT INTO nodes (node_id, user_id, last_rx_time, last_rx_snr, last_rx_rssi, last_hop_start, last_hop_limit, created_at, updated_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)
-            ON CONFLICT(node_id) DO NOTHING",
-            packet.from,
-            user_id,
-            rx_time,
-            packet.rx_snr,
-            packet.rx_rssi,
-            packet.hop_start,
-            packet.hop_limit,
-            received_at,
-        )
-        .execute(txn)
-        .await?;
+        let inserted = on_db!(txn, |txn| {
+            sqlx::query(
+                "INSERT INTO nodes (node_id, user_id, last_rx_time, last_rx_snr, last_rx_rssi, last_hop_start, last_hop_limit, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8)
+                ON CONFLICT(node_id) DO NOTHING",
+            )
+            .bind(i64::from(packet.from))
+            .bind(&user_id)
+            .bind(rx_time)
+            .bind(packet.rx_snr)
+            .bind(packet.rx_rssi)
+            .bind(i64::from(packet.hop_start))
+            .bind(i64::from(packet.hop_limit))
+            .bind(received_at)
+            .execute(txn)
+            .await
+            .map(|result| result.rows_affected())
+        })?;
+
+        if inserted > 0 {
+            search::index_node(txn, packet.from).await?;
+        }
     }
 
     Ok(())
 }
 
+/// Whether the envelope of a packet was imported before, for packets the cache cannot decide on.
+async fn is_imported(txn: &mut DbConnection<'_>, raw_message_hash: &[u8]) -> anyhow::Result<bool> {
+    let exact_match: Option<i64> = on_db!(txn, |txn| {
+        sqlx::query_scalar("SELECT id FROM mesh_packets WHERE hash = $1 AND unique_id != 0 LIMIT 1")
+            .bind(raw_message_hash)
+            .fetch_optional(txn)
+            .await
+    })?;
+
+    Ok(exact_match.is_some())
+}
+
+/// Looks up the first mesh packet a packet is a repeat of, for packets the cache cannot decide
+/// on.
+async fn first_packet(
+    txn: &mut DbConnection<'_>,
+    packet: &MeshPacket,
+    data: &proto::meshtastic::Data,
+) -> anyhow::Result<Option<i64>> {
+    let rx_time = packet.rx_time as i64 * 1_000_000_000;
+    let window = REPEAT_WINDOW_SECONDS * 1_000_000_000;
+
+    let first = on_db!(txn, |txn| {
+        sqlx::query_scalar(
+            "SELECT id FROM mesh_packets
+             WHERE unique_id = $1 AND payload_data = $2 AND rx_time BETWEEN $3 - $4 AND $3 + $4
+             AND duplicate_of_mesh_packet_id IS NULL
+             ORDER BY id LIMIT 1",
+        )
+        .bind(i64::from(packet.id))
+        .bind(&data.payload)
+        .bind(rx_time)
+        .bind(window)
+        .fetch_optional(txn)
+        .await
+    })?;
+
+    Ok(first)
+}
+
+/// Returns whether the sender of a packet is hidden, creating the node when it does not exist.
+/// Nodes that are not cached, e.g. created by another process, are read from the database.
+async fn node_hidden(
+    txn: &mut DbConnection<'_>,
+    recent_packets: &mut RecentPackets,
+    packet: &MeshPacket,
+    received_at: i64,
+) -> anyhow::Result<bool> {
+    if let Some(hidden) = recent_packets.node_hidden(packet.from) {
+        return Ok(hidden);
+    }
+
+    ensure_node_exists(txn, packet, received_at).await?;
+
+    let hidden: Option<i64> = on_db!(txn, |txn| {
+        sqlx::query_scalar("SELECT hidden FROM nodes WHERE node_id = $1")
+            .bind(i64::from(packet.from))
+            .fetch_optional(txn)
+            .await
+    })?;
+    let hidden = hidden.is_some_and(|hidden| hidden != 0);
+    recent_packets.insert_node(packet.from, hidden);
+
+    Ok(hidden)
+}
+
 async fn process_mesh_packet(
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
+    recent_packets: &mut RecentPackets,
     gateway_id: String,
     raw_message_hash: &[u8],
     packet: &MeshPacket,
     received_at: i64,
+    hidden: bool,
 ) -> anyhow::Result<()> {
     if let Some(Decoded(ref data)) = packet.payload_variant {
-        let (mesh_repeat_id, node_exists) =
-            fetch_mesh_repeat_and_node_exists(packet, txn, data, raw_message_hash).await?;
-
-        if !node_exists {
-            ensure_node_exists(txn, packet, received_at).await?;
+        let mut repeat_of = recent_packets.repeat_of(packet, data, raw_message_hash)?;
+        if repeat_of.is_none() && recent_packets.may_miss(packet) {
+            if is_imported(txn, raw_message_hash).await? {
+                return Err(anyhow!("Duplicate mesh packet: {}", packet.id));
+            }
+            repeat_of = first_packet(txn, packet, data).await?;
         }
+        let mesh_repeat_id = repeat_of.unwrap_or(0);
 
-        let mesh_packet_id =
-            create_packet(gateway_id, packet, data, raw_message_hash, txn, received_at).await?;
+        let node_hidden = node_hidden(txn, recent_packets, packet, received_at).await?;
 
-        if mesh_repeat_id != 0 {
-            let _ = sqlx::query!(
-                "UPDATE mesh_packets SET duplicate_of_mesh_packet_id = ? WHERE id = ?",
-                mesh_repeat_id,
-                mesh_packet_id,
+        // Packets of nodes hidden by the ingest rules are hidden as well
+        let hidden = hidden || node_hidden;
+
+        let mesh_packet_id = create_packet(
+            gateway_id.clone(),
+            packet,
+            data,
+            raw_message_hash,
+            txn,
+            received_at,
+            hidden,
+        )
+        .await?;
+        recent_packets.insert(packet, data, raw_message_hash, mesh_packet_id, repeat_of);
+
+        // Repeats are received through another gateway, so they count for its reception. Hidden
+        // packets are left out, like everywhere else.
+        if !hidden {
+            rollups::record_reception(
+                txn,
+                packet.from,
+                &gateway_id,
+                packet.rx_time as i64 * 1_000_000_000,
+                packet.rx_rssi,
+                packet.rx_snr,
             )
-            .execute(&mut **txn)
             .await?;
+        }
+
+        if mesh_repeat_id != 0 {
+            on_db!(txn, |txn| {
+                sqlx::query(
+                    "UPDATE mesh_packets SET duplicate_of_mesh_packet_id = $1 WHERE id = $2",
+                )
+                .bind(mesh_repeat_id)
+                .bind(mesh_packet_id)
+                .execute(txn)
+                .await
+                .map(|_| ())
+            })?;
 
             Err(anyhow!(MeshPacketProcessingError(format!(
                 "Skipping processing of duplicate packet {}",
@@ -93,22 +210,30 @@ async fn process_mesh_packet(
         } else {
             let rx_time_nanos = packet.rx_time as i64 * 1_000_000_000;
 
-            let _ = sqlx::query!(
-                "UPDATE nodes
-                 SET last_rx_time = ?, last_rx_snr = ?, last_rx_rssi = ?, last_hop_start = ?, last_hop_limit = ?, updated_at = ?
-                 WHERE node_id = ?",
-                rx_time_nanos,  // New last_rx_time value
-                packet.rx_snr,  // New last_rx_snr value
-                packet.rx_rssi, // New last_rx_rssi value
-                packet.hop_start, // New last_hop_start value
-                packet.hop_limit, // New last_hop_limit value
-                received_at,
-                packet.from,    // node_id condition
-            )
-            .execute(&mut **txn)
-            .await?;
+            let packet_time = update_time(packet);
+            on_db!(txn, |txn| {
+                sqlx::query(
+                    "UPDATE nodes
+                     SET last_rx_time = $1, last_rx_snr = $2, last_rx_rssi = $3, last_hop_start = $4, last_hop_limit = $5, updated_at = $6
+                     WHERE node_id = $7 AND COALESCE(last_rx_time, 0) <= $8",
+                )
+                .bind(rx_time_nanos) // New last_rx_time value
+                .bind(packet.rx_snr) // New last_rx_snr value
+                .bind(packet.rx_rssi) // New last_rx_rssi value
+                .bind(i64::from(packet.hop_start)) // New last_hop_start value
+                .bind(i64::from(packet.hop_limit)) // New last_hop_limit value
+                .bind(received_at)
+                .bind(i64::from(packet.from)) // node_id condition
+                .bind(packet_time)
+                .execute(txn)
+                .await
+                .map(|_| ())
+            })?;
 
             match PortNum::try_from(data.portnum) {
+                Ok(PortNum::TextMessageApp) => {
+                    handle_text_message_payload(data, mesh_packet_id, txn).await
+                }
                 Ok(PortNum::PositionApp) => {
                     handle_position_payload(data, packet, mesh_packet_id, txn).await
                 }
@@ -116,7 +241,7 @@ async fn process_mesh_packet(
                     handle_neighbor_payload(data, packet, mesh_packet_id, txn).await
                 }
                 Ok(PortNum::TelemetryApp) => {
-                    handle_telemetry_payload(data, packet, mesh_packet_id, txn).await
+                    handle_telemetry_payload(data, packet, mesh_packet_id, hidden, txn).await
                 }
                 Ok(PortNum::NodeinfoApp) => {
                     handle_nodeinfo_payload(data, packet, mesh_packet_id, txn).await
@@ -145,55 +270,11 @@ async fn process_mesh_packet(
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct PacketStatus {
-    mesh_repeat_id: i64,
-    exact_match: i64,
-    node_exists: i64,
-}
-
-async fn fetch_mesh_repeat_and_node_exists(
-    packet: &proto::meshtastic::MeshPacket,
-    txn: &mut SqliteConnection,
-    data: &proto::meshtastic::Data,
-    raw_message_hash: &[u8],
-) -> anyhow::Result<(i64, bool)> {
-    let range_start = (packet.rx_time as i64 - 3600) * 1_000_000_000;
-    let range_end = (packet.rx_time as i64 + 3600) * 1_000_000_000;
-
-    let result : PacketStatus = sqlx::query_as!(
-        PacketStatus,
-        r#"
-            SELECT
-                COALESCE((SELECT id FROM mesh_packets WHERE unique_id = ? AND unique_id != 0 AND payload_data = ? AND created_at BETWEEN ? AND ? ORDER BY created_at ASC LIMIT 1), 0) AS "mesh_repeat_id!",
-                EXISTS(SELECT 1 FROM mesh_packets WHERE hash = ? AND unique_id != 0) AS "exact_match!",
-                EXISTS(SELECT 1 FROM nodes WHERE node_id = ?) AS "node_exists!"
-        "#,
-        packet.id,
-        data.payload,
-        range_start,
-        range_end,
-        raw_message_hash,
-        packet.from
-    )
-    .fetch_one(txn)
-    .await?;
-
-    let exact_match = result.exact_match != 0;
-    let node_exists = result.node_exists != 0;
-
-    if !exact_match {
-        Ok((result.mesh_repeat_id, node_exists))
-    } else {
-        Err(anyhow!("Duplicate mesh packet: {}", packet.id))
-    }
-}
-
 async fn handle_position_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
 ) -> Result<(), anyhow::Error> {
     if let Ok(position_payload) = Position::decode(&*data.payload)
         && position_payload.latitude_i.is_some() && position_payload.longitude_i.is_some() {
@@ -206,58 +287,69 @@ async fn handle_position_payload(
             let longitude = position_payload.longitude_i.map(|i| i as f64 / 1e7);
             let time = position_payload.time as i64 * 1_000_000_000;
 
-            let result = sqlx::query_as!(
-                ReturningId,
-                "INSERT INTO positions (
-                    mesh_packet_id, node_id, latitude, longitude, latitude_i, longitude_i,
-                    altitude, time, location_source, altitude_source, timestamp, altitude_hae,
-                    altitude_geoidal_separation, pdop, hdop, vdop, gps_accuracy, ground_speed,
-                    ground_track, fix_quality, fix_type, sats_in_view, sensor_id, next_update,
-                    seq_number, precision_bits
-                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-                RETURNING id",
-                mesh_packet_id,
-                packet.from,
-                latitude,
-                longitude,
-                position_payload.latitude_i,
-                position_payload.longitude_i,
-                position_payload.altitude,
-                time,
-                position_payload.location_source,
-                position_payload.altitude_source,
-                timestamp,
-                position_payload.altitude_hae,
-                position_payload.altitude_geoidal_separation,
-                position_payload.pdop,
-                position_payload.hdop,
-                position_payload.vdop,
-                position_payload.gps_accuracy,
-                position_payload.ground_speed,
-                position_payload.ground_track,
-                position_payload.fix_quality,
-                position_payload.fix_type,
-                position_payload.sats_in_view,
-                position_payload.sensor_id,
-                position_payload.next_update,
-                position_payload.seq_number,
-                position_payload.precision_bits
-            )
-            .fetch_one(&mut **txn)
-            .await?;
-
-            let _ = sqlx::query!(
-                "UPDATE nodes
-                SET latitude = ?, longitude = ?, altitude = ?, last_position_id = ?
-                WHERE node_id = ?",
-                latitude,
-                longitude,
-                position_payload.altitude,
-                result.id,
-                packet.from,
-            )
-            .execute(&mut **txn)
-            .await?;
+            let result = on_db!(txn, |txn| {
+                sqlx::query_as::<_, ReturningId>(
+                    "INSERT INTO positions (
+                        mesh_packet_id, node_id, latitude, longitude, latitude_i, longitude_i,
+                        altitude, time, location_source, altitude_source, timestamp, altitude_hae,
+                        altitude_geoidal_separation, pdop, hdop, vdop, gps_accuracy, ground_speed,
+                        ground_track, fix_quality, fix_type, sats_in_view, sensor_id, next_update,
+                        seq_number, precision_bits
+                    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16,
+                              $17, $18, $19, $20, $21, $22, $23, $24, $25, $26)
+                    RETURNING id",
+                )
+                .bind(mesh_packet_id)
+                .bind(i64::from(packet.from))
+                .bind(latitude)
+                .bind(longitude)
+                .bind(position_payload.latitude_i)
+                .bind(position_payload.longitude_i)
+                .bind(position_payload.altitude)
+                .bind(time)
+                .bind(position_payload.location_source)
+                .bind(position_payload.altitude_source)
+                .bind(timestamp)
+                .bind(position_payload.altitude_hae)
+                .bind(position_payload.altitude_geoidal_separation)
+                .bind(i64::from(position_payload.pdop))
+                .bind(i64::from(position_payload.hdop))
+                .bind(i64::from(position_payload.vdop))
+                .bind(i64::from(position_payload.gps_accuracy))
+                .bind(position_payload.ground_speed.map(i64::from))
+                .bind(position_payload.ground_track.map(i64::from))
+                .bind(i64::from(position_payload.fix_quality))
+                .bind(i64::from(position_payload.fix_type))
+                .bind(i64::from(position_payload.sats_in_view))
+                .bind(i64::from(position_payload.sensor_id))
+                .bind(i64::from(position_payload.next_update))
+                .bind(i64::from(position_payload.seq_number))
+                .bind(i64::from(position_payload.precision_bits))
+                .fetch_one(txn)
+                .await
+            })?;
+
+            let packet_time = update_time(packet);
+            on_db!(txn, |txn| {
+                sqlx::query(
+                    "UPDATE nodes
+                    SET latitude = $1, longitude = $2, altitude = $3, last_position_id = $4
+                    WHERE node_id = $5 AND COALESCE((
+                        SELECT mesh_packets.rx_time FROM positions
+                        JOIN mesh_packets ON mesh_packets.id = positions.mesh_packet_id
+                        WHERE positions.id = nodes.last_position_id
+                    ), 0) <= $6",
+                )
+                .bind(latitude)
+                .bind(longitude)
+                .bind(position_payload.altitude)
+                .bind(result.id)
+                .bind(i64::from(packet.from))
+                .bind(packet_time)
+                .execute(txn)
+                .await
+                .map(|_| ())
+            })?;
         };
     Ok(())
 }
@@ -266,7 +358,7 @@ async fn handle_neighbor_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
 ) -> Result<(), anyhow::Error> {
     let neighbor_info = NeighborInfo::decode(&*data.payload);
     if let Ok(neighbor_info) = neighbor_info {
@@ -279,117 +371,198 @@ async fn handle_neighbor_payload(
                 let rx_time = packet.rx_time as i64 * 1_000_000_000;
 
                 // Insert neighbor relationship
-                let _ = sqlx::query!(
-                    "INSERT INTO neighbors (mesh_packet_id, node_id, neighbor_node_id, snr, timestamp)
-                    VALUES (?, ?, ?, ?, ?)",
-                    mesh_packet_id,
-                    neighbor_info.node_id,
-                    neighbor_node.node_id,
-                    neighbor_node.snr,
-                    rx_time,
-                )
-                .execute(&mut **txn)
-                .await?;
+                on_db!(txn, |txn| {
+                    sqlx::query(
+                        "INSERT INTO neighbors (mesh_packet_id, node_id, neighbor_node_id, snr, timestamp)
+                        VALUES ($1, $2, $3, $4, $5)",
+                    )
+                    .bind(mesh_packet_id)
+                    .bind(i64::from(neighbor_info.node_id))
+                    .bind(i64::from(neighbor_node.node_id))
+                    .bind(neighbor_node.snr)
+                    .bind(rx_time)
+                    .execute(txn)
+                    .await
+                    .map(|_| ())
+                })?;
+
+                // Packets are not always imported in order, e.g. from a merged database
+                on_db!(txn, |txn| {
+                    sqlx::query(
+                        "INSERT INTO neighbor_links (node_id, neighbor_node_id, snr, first_seen, last_seen, observations)
+                        VALUES ($1, $2, $3, $4, $4, 1)
+                        ON CONFLICT (node_id, neighbor_node_id) DO UPDATE SET
+                            snr = CASE WHEN excluded.last_seen >= neighbor_links.last_seen THEN excluded.snr ELSE neighbor_links.snr END,
+                            first_seen = CASE WHEN excluded.first_seen < neighbor_links.first_seen THEN excluded.first_seen ELSE neighbor_links.first_seen END,
+                            last_seen = CASE WHEN excluded.last_seen > neighbor_links.last_seen THEN excluded.last_seen ELSE neighbor_links.last_seen END,
+                            observations = neighbor_links.observations + 1",
+                    )
+                    .bind(i64::from(neighbor_info.node_id))
+                    .bind(i64::from(neighbor_node.node_id))
+                    .bind(neighbor_node.snr)
+                    .bind(rx_time)
+                    .execute(txn)
+                    .await
+                    .map(|_| ())
+                })?;
             }
         }
     };
     Ok(())
 }
 
+async fn handle_text_message_payload(
+    data: &proto::meshtastic::Data,
+    mesh_packet_id: i64,
+    txn: &mut DbConnection<'_>,
+) -> anyhow::Result<()> {
+    if let Ok(text) = std::str::from_utf8(&data.payload) {
+        search::index_packet(txn, mesh_packet_id, text).await?;
+    }
+    Ok(())
+}
+
 async fn handle_telemetry_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    hidden: bool,
+    txn: &mut DbConnection<'_>,
 ) -> Result<(), anyhow::Error> {
     if let Ok(telemetry_payload) = Telemetry::decode(&*data.payload) {
         let time = none_if_default(telemetry_payload.time).map(|time| time as i64 * 1000000000);
+        // The plots leave hidden packets out, the rollups as well
+        let rollup_time = time.filter(|_| !hidden);
 
         match telemetry_payload.variant {
             Some(telemetry::Variant::DeviceMetrics(device_metrics_payload)) => {
-                let result = sqlx::query_as!(
-                    ReturningId,
-                    "INSERT INTO device_metrics (mesh_packet_id, node_id, time, battery_level, voltage, air_util_tx, channel_utilization, uptime_seconds)
-                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-                     RETURNING id",
-                    mesh_packet_id,
-                    packet.from,
-                    time,
-                    device_metrics_payload.battery_level,
-                    device_metrics_payload.voltage,
-                    device_metrics_payload.air_util_tx,
-                    device_metrics_payload.channel_utilization,
-                    device_metrics_payload.uptime_seconds,
-                )
-                .fetch_one(&mut **txn)
-                .await?;
+                if let Some(time) = rollup_time {
+                    rollups::record_device_metrics(txn, packet.from, time, &device_metrics_payload)
+                        .await?;
+                }
+
+                let battery_level = device_metrics_payload.battery_level.map(i64::from);
+                let uptime_seconds = device_metrics_payload.uptime_seconds.map(i64::from);
+                let result = on_db!(txn, |txn| {
+                    sqlx::query_as::<_, ReturningId>(
+                        "INSERT INTO device_metrics (mesh_packet_id, node_id, time, battery_level, voltage, air_util_tx, channel_utilization, uptime_seconds)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                         RETURNING id",
+                    )
+                    .bind(mesh_packet_id)
+                    .bind(i64::from(packet.from))
+                    .bind(time)
+                    .bind(battery_level)
+                    .bind(device_metrics_payload.voltage)
+                    .bind(device_metrics_payload.air_util_tx)
+                    .bind(device_metrics_payload.channel_utilization)
+                    .bind(uptime_seconds)
+                    .fetch_one(txn)
+                    .await
+                })?;
 
                 // Update nodes with device metrics
-                let _ = sqlx::query!(
-                    "UPDATE nodes SET battery_level = ?, voltage = ?, air_util_tx = ?, channel_utilization = ?, uptime_seconds = ?, last_device_metrics_id = ?
-                    WHERE node_id = ?",
-                    device_metrics_payload.battery_level,
-                    device_metrics_payload.voltage,
-                    device_metrics_payload.air_util_tx,
-                    device_metrics_payload.channel_utilization,
-                    device_metrics_payload.uptime_seconds,
-                    result.id,
-                    packet.from,
-                )
-                .execute(&mut **txn)
-                .await?;
+                let packet_time = update_time(packet);
+                on_db!(txn, |txn| {
+                    sqlx::query(
+                        "UPDATE nodes SET battery_level = $1, voltage = $2, air_util_tx = $3, channel_utilization = $4, uptime_seconds = $5, last_device_metrics_id = $6
+                        WHERE node_id = $7 AND COALESCE((
+                            SELECT mesh_packets.rx_time FROM device_metrics
+                            JOIN mesh_packets ON mesh_packets.id = device_metrics.mesh_packet_id
+                            WHERE device_metrics.id = nodes.last_device_metrics_id
+                        ), 0) <= $8",
+                    )
+                    .bind(battery_level)
+                    .bind(device_metrics_payload.voltage)
+                    .bind(device_metrics_payload.air_util_tx)
+                    .bind(device_metrics_payload.channel_utilization)
+                    .bind(uptime_seconds)
+                    .bind(result.id)
+                    .bind(i64::from(packet.from))
+                    .bind(packet_time)
+                    .execute(txn)
+                    .await
+                    .map(|_| ())
+                })?;
             }
             Some(telemetry::Variant::EnvironmentMetrics(environment_metrics_payload)) => {
-                let result = sqlx::query_as!(
-                    ReturningId,
-                    "INSERT INTO environment_metrics (mesh_packet_id, node_id, time, temperature, relative_humidity, barometric_pressure, gas_resistance, iaq)
-                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-                     RETURNING id",
-                    mesh_packet_id,
-                    packet.from,
-                    time,
-                    environment_metrics_payload.temperature,
-                    environment_metrics_payload.relative_humidity,
-                    environment_metrics_payload.barometric_pressure,
-                    environment_metrics_payload.gas_resistance,
-                    environment_metrics_payload.iaq,
-                )
-                .fetch_one(&mut **txn)
-                .await?;
+                if let Some(time) = rollup_time {
+                    rollups::record_environment_metrics(
+                        txn,
+                        packet.from,
+                        time,
+                        &environment_metrics_payload,
+                    )
+                    .await?;
+                }
+
+                let iaq = environment_metrics_payload.iaq.map(i64::from);
+                let result = on_db!(txn, |txn| {
+                    sqlx::query_as::<_, ReturningId>(
+                        "INSERT INTO environment_metrics (mesh_packet_id, node_id, time, temperature, relative_humidity, barometric_pressure, gas_resistance, iaq)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                         RETURNING id",
+                    )
+                    .bind(mesh_packet_id)
+                    .bind(i64::from(packet.from))
+                    .bind(time)
+                    .bind(environment_metrics_payload.temperature)
+                    .bind(environment_metrics_payload.relative_humidity)
+                    .bind(environment_metrics_payload.barometric_pressure)
+                    .bind(environment_metrics_payload.gas_resistance)
+                    .bind(iaq)
+                    .fetch_one(txn)
+                    .await
+                })?;
 
                 // Update nodes with environment metrics
-                let _ = sqlx::query!(
-                    "UPDATE nodes SET temperature = ?, relative_humidity = ?, barometric_pressure = ?, gas_resistance = ?, iaq = ?, last_environment_metrics_id = ?
-                    WHERE node_id = ?",
-                    environment_metrics_payload.temperature,
-                    environment_metrics_payload.relative_humidity,
-                    environment_metrics_payload.barometric_pressure,
-                    environment_metrics_payload.gas_resistance,
-                    environment_metrics_payload.iaq,
-                    result.id,
-                    packet.from,
-                )
-                .execute(&mut **txn)
-                .await?;
+                let packet_time = update_time(packet);
+                on_db!(txn, |txn| {
+                    sqlx::query(
+                        "UPDATE nodes SET temperature = $1, relative_humidity = $2, barometric_pressure = $3, gas_resistance = $4, iaq = $5, last_environment_metrics_id = $6
+                        WHERE node_id = $7 AND COALESCE((
+                            SELECT mesh_packets.rx_time FROM environment_metrics
+                            JOIN mesh_packets ON mesh_packets.id = environment_metrics.mesh_packet_id
+                            WHERE environment_metrics.id = nodes.last_environment_metrics_id
+                        ), 0) <= $8",
+                    )
+                    .bind(environment_metrics_payload.temperature)
+                    .bind(environment_metrics_payload.relative_humidity)
+                    .bind(environment_metrics_payload.barometric_pressure)
+                    .bind(environment_metrics_payload.gas_resistance)
+                    .bind(iaq)
+                    .bind(result.id)
+                    .bind(i64::from(packet.from))
+                    .bind(packet_time)
+                    .execute(txn)
+                    .await
+                    .map(|_| ())
+                })?;
             }
             Some(telemetry::Variant::PowerMetrics(power_metrics_payload)) => {
-                let _ = sqlx::query_as!(
-                    ReturningId,
-                    "INSERT INTO power_metrics (mesh_packet_id, node_id, time, ch1_voltage, ch1_current, ch2_voltage, ch2_current, ch3_voltage, ch3_current)
-                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
-                     RETURNING id",
-                    mesh_packet_id,
-                    packet.from,
-                    time,
-                    power_metrics_payload.ch1_voltage,
-                    power_metrics_payload.ch1_current,
-                    power_metrics_payload.ch2_voltage,
-                    power_metrics_payload.ch2_current,
-                    power_metrics_payload.ch3_voltage,
-                    power_metrics_payload.ch3_current,
-                )
-                .fetch_one(&mut **txn)
-                .await?;
+                if let Some(time) = rollup_time {
+                    rollups::record_power_metrics(txn, packet.from, time, &power_metrics_payload)
+                        .await?;
+                }
+
+                on_db!(txn, |txn| {
+                    sqlx::query(
+                        "INSERT INTO power_metrics (mesh_packet_id, node_id, time, ch1_voltage, ch1_current, ch2_voltage, ch2_current, ch3_voltage, ch3_current)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                    )
+                    .bind(mesh_packet_id)
+                    .bind(i64::from(packet.from))
+                    .bind(time)
+                    .bind(power_metrics_payload.ch1_voltage)
+                    .bind(power_metrics_payload.ch1_current)
+                    .bind(power_metrics_payload.ch2_voltage)
+                    .bind(power_metrics_payload.ch2_current)
+                    .bind(power_metrics_payload.ch3_voltage)
+                    .bind(power_metrics_payload.ch3_current)
+                    .execute(txn)
+                    .await
+                    .map(|_| ())
+                })?;
 
                 // Not stored in nodes table currently
             }
@@ -405,45 +578,59 @@ async fn handle_nodeinfo_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
 ) -> Result<(), anyhow::Error> {
     if let Ok(node_info_payload) = User::decode(&*data.payload) {
-        let result = sqlx::query_as!(
-            ReturningId,
-            "INSERT INTO node_info (mesh_packet_id, node_id, user_id, long_name, short_name, hw_model_id, is_licensed, role, public_key, is_unmessagable)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id",
-            mesh_packet_id,
-            packet.from,
-            node_info_payload.id,
-            node_info_payload.long_name,
-            node_info_payload.short_name,
-            node_info_payload.hw_model,
-            node_info_payload.is_licensed,
-            node_info_payload.role,
-            node_info_payload.public_key,
-            node_info_payload.is_unmessagable
-        )
-        .fetch_one(&mut **txn)
-        .await?;
+        let is_licensed = i64::from(node_info_payload.is_licensed);
+        let is_unmessagable = node_info_payload.is_unmessagable.map(i64::from);
+        let result = on_db!(txn, |txn| {
+            sqlx::query_as::<_, ReturningId>(
+                "INSERT INTO node_info (mesh_packet_id, node_id, user_id, long_name, short_name, hw_model_id, is_licensed, role, public_key, is_unmessagable)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id",
+            )
+            .bind(mesh_packet_id)
+            .bind(i64::from(packet.from))
+            .bind(&node_info_payload.id)
+            .bind(&node_info_payload.long_name)
+            .bind(&node_info_payload.short_name)
+            .bind(node_info_payload.hw_model)
+            .bind(is_licensed)
+            .bind(node_info_payload.role)
+            .bind(&node_info_payload.public_key)
+            .bind(is_unmessagable)
+            .fetch_one(txn)
+            .await
+        })?;
 
         // Update nodes table
-        let _ = sqlx::query!(
-            "UPDATE nodes
-            SET user_id = ?, long_name = ?, short_name = ?, hw_model_id = ?, is_licensed = ?, role = ?, last_node_info_id = ?, public_key = ?, is_unmessagable = ?
-            WHERE node_id = ?",
-            node_info_payload.id,
-            node_info_payload.long_name,
-            node_info_payload.short_name,
-            node_info_payload.hw_model,
-            node_info_payload.is_licensed,
-            node_info_payload.role,
-            result.id,
-            node_info_payload.public_key,
-            node_info_payload.is_unmessagable,
-            packet.from,
-        )
-        .execute(&mut **txn)
-        .await?;
+        let packet_time = update_time(packet);
+        on_db!(txn, |txn| {
+            sqlx::query(
+                "UPDATE nodes
+                SET user_id = $1, long_name = $2, short_name = $3, hw_model_id = $4, is_licensed = $5, role = $6, last_node_info_id = $7, public_key = $8, is_unmessagable = $9
+                WHERE node_id = $10 AND COALESCE((
+                    SELECT mesh_packets.rx_time FROM node_info
+                    JOIN mesh_packets ON mesh_packets.id = node_info.mesh_packet_id
+                    WHERE node_info.id = nodes.last_node_info_id
+                ), 0) <= $11",
+            )
+            .bind(&node_info_payload.id)
+            .bind(&node_info_payload.long_name)
+            .bind(&node_info_payload.short_name)
+            .bind(node_info_payload.hw_model)
+            .bind(is_licensed)
+            .bind(node_info_payload.role)
+            .bind(result.id)
+            .bind(&node_info_payload.public_key)
+            .bind(is_unmessagable)
+            .bind(i64::from(packet.from))
+            .bind(packet_time)
+            .execute(txn)
+            .await
+            .map(|_| ())
+        })?;
+
+        search::index_node(txn, packet.from).await?;
     };
     Ok(())
 }
@@ -452,7 +639,7 @@ async fn handle_waypoint_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
 ) -> anyhow::Result<()> {
     if let Ok(waypoint_payload) = Waypoint::decode(&*data.payload) {
         let expire =
@@ -469,26 +656,32 @@ async fn handle_waypoint_payload(
         let latitude = waypoint_payload.latitude_i.map(|i| i as f64 / 1e7);
         let longitude = waypoint_payload.longitude_i.map(|i| i as f64 / 1e7);
 
-        sqlx::query!(
-            "INSERT INTO waypoints (
-                mesh_packet_id, node_id, waypoint_id, latitude, longitude, latitude_i, longitude_i,
-                expire, locked_to, name, description, icon
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            mesh_packet_id,
-            packet.from,
-            waypoint_payload.id,
-            latitude,
-            longitude,
-            waypoint_payload.latitude_i,
-            waypoint_payload.longitude_i,
-            expire,
-            locked_to,
-            waypoint_payload.name,
-            waypoint_payload.description,
-            icon
-        )
-        .execute(&mut **txn)
-        .await?;
+        on_db!(txn, |txn| {
+            sqlx::query(
+                "INSERT INTO waypoints (
+                    mesh_packet_id, node_id, waypoint_id, latitude, longitude, latitude_i, longitude_i,
+                    expire, locked_to, name, description, icon
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            )
+            .bind(mesh_packet_id)
+            .bind(i64::from(packet.from))
+            .bind(i64::from(waypoint_payload.id))
+            .bind(latitude)
+            .bind(longitude)
+            .bind(waypoint_payload.latitude_i)
+            .bind(waypoint_payload.longitude_i)
+            .bind(expire)
+            .bind(locked_to.map(i64::from))
+            .bind(&waypoint_payload.name)
+            .bind(&waypoint_payload.description)
+            .bind(&icon)
+            .execute(txn)
+            .await
+            .map(|_| ())
+        })?;
+
+        let content = format!("{} {}", waypoint_payload.name, waypoint_payload.description);
+        search::index_packet(txn, mesh_packet_id, content.trim()).await?;
     };
     Ok(())
 }
@@ -496,8 +689,8 @@ async fn handle_waypoint_payload(
 async fn handle_traceroute_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
-    _mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    mesh_packet_id: i64,
+    txn: &mut DbConnection<'_>,
 ) -> anyhow::Result<()> {
     if let Ok(route_discovery_payload) = RouteDiscovery::decode(&*data.payload) {
         let mut node_ids = HashSet::from_iter(route_discovery_payload.route);
@@ -507,6 +700,29 @@ async fn handle_traceroute_payload(
 
         // Traceroutes are parsed on the fly currently, no database entry will be created.
     };
+
+    if !data.want_response && data.request_id != 0 {
+        // Link responses to the traceroutes sent by meshstellar
+        let request_id = data.request_id as i64;
+        let to = packet.to as i64;
+        on_db!(txn, |txn| {
+            sqlx::query(
+                r#"
+                UPDATE traceroute_probes
+                SET response_mesh_packet_id = $1,
+                    responded_at = (SELECT received_at FROM mesh_packets WHERE id = $1)
+                WHERE packet_id = $2 AND from_id = $3 AND response_mesh_packet_id IS NULL
+                "#,
+            )
+            .bind(mesh_packet_id)
+            .bind(request_id)
+            .bind(to)
+            .execute(txn)
+            .await
+            .map(|_| ())
+        })?;
+    }
+
     Ok(())
 }
 
@@ -514,7 +730,7 @@ async fn handle_routing_payload(
     data: &proto::meshtastic::Data,
     packet: &proto::meshtastic::MeshPacket,
     _mesh_packet_id: i64,
-    txn: &mut PoolConnection<DB>,
+    txn: &mut DbConnection<'_>,
 ) -> anyhow::Result<()> {
     if let Ok(routing_payload) = Routing::decode(&*data.payload) {
         let variant = routing_payload
@@ -537,7 +753,7 @@ async fn handle_routing_payload(
 
 async fn create_nodes_if_not_exist(
     node_ids: HashSet<u32>,
-    txn: &mut PoolConnection<sqlx::Sqlite>,
+    txn: &mut DbConnection<'_>,
 ) -> Result<(), anyhow::Error> {
     if node_ids.is_empty() {
         return Ok(());
@@ -545,40 +761,66 @@ async fn create_nodes_if_not_exist(
 
     let query = format!(
         "SELECT node_id FROM nodes WHERE node_id IN ({})",
-        node_ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ")
+        (1..=node_ids.len()).map(|index| format!("${}", index)).join(", ")
     );
-    let mut query = sqlx::query(&query);
-    for node_id in &node_ids {
-        query = query.bind(node_id);
-    }
-    let existing_nodes: HashSet<u32> = query
-        .fetch_all(&mut **txn)
-        .await?
+    let existing_nodes: Vec<i64> = on_db!(txn, |txn| {
+        let mut query = sqlx::query_scalar(&query);
+        for node_id in &node_ids {
+            query = query.bind(i64::from(*node_id));
+        }
+        query.fetch_all(txn).await
+    })?;
+    let existing_nodes: HashSet<u32> = existing_nodes
         .into_iter()
-        .map(|row| row.get::<u32, _>("node_id"))
+        .map(|node_id| node_id as u32)
         .collect();
+
+    let created_at = Utc::now().timestamp_nanos_opt().unwrap();
     for node_id in node_ids.difference(&existing_nodes) {
         if *node_id != 0 && *node_id != 0xFFFFFFFF {
-            // Attempt to insert the node, ignoring conflicts
+            // Insert the node, ignoring conflicts. A failed statement would abort the transaction
+            // on PostgreSQL, so the node is created with all required columns.
             let user_id = format!("!{:08x}", node_id);
-            let _ = sqlx::query!(
-                "INSERT INTO nodes (node_id, user_id) VALUES (?, ?) ON CONFLICT(node_id) DO NOTHING",
-                node_id, user_id
-            )
-            .execute(&mut **txn)
-            .await;
+            let inserted = on_db!(txn, |txn| {
+                sqlx::query(
+                    "INSERT INTO nodes (node_id, user_id, created_at, updated_at) VALUES ($1, $2, $3, $3)
+                     ON CONFLICT(node_id) DO NOTHING",
+                )
+                .bind(i64::from(*node_id))
+                .bind(&user_id)
+                .bind(created_at)
+                .execute(txn)
+                .await
+                .map(|result| result.rows_affected())
+            })?;
+
+            if inserted > 0 {
+                search::index_node(txn, *node_id).await?;
+            }
         }
     }
     Ok(())
 }
 
+/// The time compared to decide whether a packet is newer than the one a node was last updated
+/// from, so older packets, e.g. from a merged database, do not replace the latest state. Packets
+/// without a receive time count as the newest.
+fn update_time(packet: &MeshPacket) -> i64 {
+    if packet.rx_time == 0 {
+        i64::MAX
+    } else {
+        packet.rx_time as i64 * 1_000_000_000
+    }
+}
+
 async fn create_packet(
     gateway_id: String,
     packet: &MeshPacket,
     data: &proto::meshtastic::Data,
     raw_message_hash: &[u8],
-    txn: &mut SqliteConnection,
+    txn: &mut DbConnection<'_>,
     received_at: i64,
+    hidden: bool,
 ) -> anyhow::Result<i64> {
     let now = Utc::now().timestamp_nanos_opt().unwrap();
     let source = none_if_default(data.source as i64);
@@ -588,169 +830,607 @@ async fn create_packet(
     let emoji = none_if_default(data.emoji as i64);
     let rx_time = packet.rx_time as i64 * 1_000_000_000;
 
-    let result = sqlx::query_as!(
-        ReturningId,
-        "INSERT INTO mesh_packets (
-            gateway_id, from_id, to_id, channel_id, unique_id, portnum,
-            payload_data, rx_time, rx_snr, rx_rssi, hop_start, hop_limit,
-            want_ack, want_response, source, dest, request_id, reply_id,
-            emoji, priority, hash, created_at, received_at
+    let result = on_db!(txn, |txn| {
+        sqlx::query_as::<_, ReturningId>(
+            "INSERT INTO mesh_packets (
+                gateway_id, from_id, to_id, channel_id, unique_id, portnum,
+                payload_data, rx_time, rx_snr, rx_rssi, hop_start, hop_limit,
+                want_ack, want_response, source, dest, request_id, reply_id,
+                emoji, priority, hash, created_at, received_at, hidden
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+                    $15, $16, $17, $18, $19, $20, $21, $22, $23, $24)
+            RETURNING id",
         )
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
-                ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)
-        RETURNING id",
-        gateway_id,
-        packet.from,
-        packet.to,
-        packet.channel,
-        packet.id,
-        data.portnum,
-        data.payload,
-        rx_time,
-        packet.rx_snr,
-        packet.rx_rssi,
-        packet.hop_start,
-        packet.hop_limit,
-        packet.want_ack,
-        data.want_response,
-        source,
-        dest,
-        request_id,
-        reply_id,
-        emoji,
-        packet.priority,
-        raw_message_hash,
-        now,
-        received_at,
-    )
-    .fetch_one(txn)
-    .await?;
+        .bind(&gateway_id)
+        .bind(i64::from(packet.from))
+        .bind(i64::from(packet.to))
+        .bind(i64::from(packet.channel))
+        .bind(i64::from(packet.id))
+        .bind(data.portnum)
+        .bind(&data.payload)
+        .bind(rx_time)
+        .bind(packet.rx_snr)
+        .bind(packet.rx_rssi)
+        .bind(i64::from(packet.hop_start))
+        .bind(i64::from(packet.hop_limit))
+        .bind(i64::from(packet.want_ack))
+        .bind(i64::from(data.want_response))
+        .bind(source)
+        .bind(dest)
+        .bind(request_id)
+        .bind(reply_id)
+        .bind(emoji)
+        .bind(packet.priority)
+        .bind(raw_message_hash)
+        .bind(now)
+        .bind(received_at)
+        .bind(i64::from(hidden))
+        .fetch_one(txn)
+        .await
+    })?;
 
     Ok(result.id)
 }
 
-fn create_nonce(packet_id: u32, from_node: u32) -> [u8; 16] {
-    let mut nonce = [0u8; 16];
-    nonce[0..8].copy_from_slice(&(packet_id as u64).to_le_bytes());
-    nonce[8..12].copy_from_slice(&from_node.to_le_bytes());
-    nonce[12..16].copy_from_slice(&0u32.to_le_bytes());
-    nonce
-}
-
+/// Decrypts a packet with the keys of the configured channels matching its channel hash, falling
+/// back to the default key.
 fn decrypt(
     packet: &proto::meshtastic::MeshPacket,
     encrypted_payload: &[u8],
-) -> Option<Vec<u8>> {
-    const DEFAULT_KEY: &str = "1PG7OiApB1nwvP+rz05pAQ==";
+) -> Option<proto::meshtastic::Data> {
+    let default_key = crypto::channel_key(crypto::DEFAULT_KEY)?;
+
+    crypto::configured_channels()
+        .iter()
+        .filter(|channel| channel.hash == packet.channel)
+        .map(|channel| &channel.key)
+        .chain(std::iter::once(&default_key))
+        .filter_map(|key| {
+            crypto::apply_channel_cipher(key, packet.id, packet.from, encrypted_payload)
+        })
+        .find_map(|decrypted_payload| proto::meshtastic::Data::decode(&*decrypted_payload).ok())
+}
 
-    // The key is base64 encoded.
-    let key_bytes = base64::prelude::BASE64_STANDARD.decode(DEFAULT_KEY).ok()?;
+async fn update_node_visibility(
+    txn: &mut DbConnection<'_>,
+    recent_packets: &mut RecentPackets,
+    node_id: u32,
+    hidden: bool,
+    updated_at: i64,
+) -> anyhow::Result<()> {
+    let updated = on_db!(txn, |txn| {
+        sqlx::query(
+            "UPDATE nodes SET hidden = $1, updated_at = $2 WHERE node_id = $3 AND hidden != $1",
+        )
+        .bind(i64::from(hidden))
+        .bind(updated_at)
+        .bind(i64::from(node_id))
+        .execute(txn)
+        .await
+        .map(|result| result.rows_affected())
+    })?;
 
-    if key_bytes.len() == 16 {
-        // AES-128
-        let nonce = create_nonce(packet.id, packet.from);
-        let mut cipher =
-            Ctr128BE::<Aes128>::new(key_bytes.as_slice().into(), &nonce.into());
+    if updated > 0 {
+        recent_packets.insert_node(node_id, hidden);
+    }
 
-        let mut buf = encrypted_payload.to_vec();
-        cipher.apply_keystream(&mut buf);
-        Some(buf)
-    } else {
-        None
+    Ok(())
+}
+
+/// A packet decoded, decrypted and checked against the ingest rules by the workers.
+struct DecodedPacket {
+    gateway_id: String,
+    packet: MeshPacket,
+    inside_area: Option<bool>,
+    rule_match: Option<RuleMatch>,
+}
+
+struct DecodedEnvelope {
+    envelope: ServiceEnvelopeSelectResult,
+    packet: Option<DecodedPacket>,
+}
+
+/// The database independent part of the import, run on the worker pool.
+fn decode_envelope(
+    ingest_rules: &IngestRules,
+    envelope: ServiceEnvelopeSelectResult,
+) -> DecodedEnvelope {
+    let mut packet = None;
+
+    if let Ok(message) = ServiceEnvelope::decode(&*envelope.payload_data)
+        && let Some(mut mesh_packet) = message.packet {
+            if let Some(proto::meshtastic::mesh_packet::PayloadVariant::Encrypted(
+                encrypted_payload,
+            )) = mesh_packet.payload_variant.clone()
+                && let Some(data) = decrypt(&mesh_packet, &encrypted_payload) {
+                    mesh_packet.payload_variant =
+                        Some(proto::meshtastic::mesh_packet::PayloadVariant::Decoded(data));
+                }
+
+            packet = Some(DecodedPacket {
+                inside_area: ingest_rules.position_in_area(&mesh_packet),
+                rule_match: ingest_rules.evaluate(
+                    &message.gateway_id,
+                    &message.channel_id,
+                    &mesh_packet,
+                ),
+                gateway_id: message.gateway_id,
+                packet: mesh_packet,
+            });
+        }
+
+    DecodedEnvelope { envelope, packet }
+}
+
+async fn write_packet(
+    txn: &mut DbConnection<'_>,
+    recent_packets: &mut RecentPackets,
+    envelope: &ServiceEnvelopeSelectResult,
+    decoded_packet: DecodedPacket,
+) -> anyhow::Result<()> {
+    let DecodedPacket {
+        gateway_id,
+        packet,
+        inside_area,
+        rule_match,
+    } = decoded_packet;
+
+    // Nodes reporting a position outside of the configured area are hidden. The node is created
+    // first, so a node is hidden from its very first position on.
+    if let Some(inside_area) = inside_area {
+        node_hidden(txn, recent_packets, &packet, envelope.created_at).await?;
+        update_node_visibility(
+            txn,
+            recent_packets,
+            packet.from,
+            !inside_area,
+            envelope.created_at,
+        )
+        .await?;
     }
+
+    if let Some(RuleMatch {
+        action: RuleAction::Discard,
+        ..
+    }) = rule_match
+    {
+        return Ok(());
+    }
+
+    process_mesh_packet(
+        txn,
+        recent_packets,
+        gateway_id,
+        &envelope.hash,
+        &packet,
+        envelope.created_at,
+        rule_match.is_some(),
+    )
+    .await
 }
 
-async fn handle_raw_service_envelope(
-    txn: &mut PoolConnection<DB>,
-    raw_message_hash: &[u8],
-    service_envelope: &[u8],
+/// Stores a raw service envelope, it is picked up by the import server. The topic is only known
+/// for envelopes received over MQTT.
+///
+/// When inserting in a transaction, call `notify_importer` again after committing it.
+pub async fn queue_service_envelope(
+    conn: &mut DbConnection<'_>,
+    raw_message: &[u8],
+    topic: Option<&str>,
     created_at: i64,
 ) -> anyhow::Result<()> {
-    if let Ok(message) = ServiceEnvelope::decode(service_envelope)
-        && let Some(mut packet) = message.packet {
-            if let Some(proto::meshtastic::mesh_packet::PayloadVariant::Encrypted(
-                encrypted_payload,
-            )) = packet.payload_variant.clone()
-                && let Some(decrypted_payload) = decrypt(&packet, &encrypted_payload)
-                    && let Ok(data) = proto::meshtastic::Data::decode(&*decrypted_payload) {
-                        packet.payload_variant =
-                            Some(proto::meshtastic::mesh_packet::PayloadVariant::Decoded(
-                                data,
-                            ));
-                    }
-
-            process_mesh_packet(
-                txn,
-                message.gateway_id,
-                raw_message_hash,
-                &packet,
-                created_at,
+    let raw_message_hash = blake3::hash(raw_message).as_bytes().to_vec();
+
+    on_db!(conn, |conn| {
+        sqlx::query(
+            "INSERT INTO service_envelopes (payload_data, hash, topic, created_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(raw_message)
+        .bind(&raw_message_hash)
+        .bind(topic)
+        .bind(created_at)
+        .execute(conn)
+        .await
+        .map(|_| ())
+    })?;
+
+    notify_importer();
+
+    Ok(())
+}
+
+/// Imports a node from the NodeDB of a directly connected radio. The user info of the radio is
+/// leading, its position is only used for nodes without a known position.
+pub async fn import_node_info(
+    pool: &DbPool,
+    node_info: &NodeInfo,
+    received_at: i64,
+) -> anyhow::Result<()> {
+    if node_info.num == 0 || node_info.num == 0xFFFFFFFF {
+        return Ok(());
+    }
+
+    let mut txn = pool.begin().await?;
+
+    let user_id = format!("!{:08x}", node_info.num);
+    let last_heard = none_if_default(node_info.last_heard as i64).map(|t| t * 1_000_000_000);
+
+    on_db!(txn, |txn| {
+        sqlx::query(
+            "INSERT INTO nodes (node_id, user_id, last_rx_time, last_rx_snr, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $5)
+            ON CONFLICT(node_id) DO NOTHING",
+        )
+        .bind(i64::from(node_info.num))
+        .bind(&user_id)
+        .bind(last_heard)
+        .bind(node_info.snr)
+        .bind(received_at)
+        .execute(txn)
+        .await
+        .map(|_| ())
+    })?;
+
+    if let Some(user) = &node_info.user {
+        on_db!(txn, |txn| {
+            sqlx::query(
+                "UPDATE nodes
+                SET user_id = $1, long_name = $2, short_name = $3, hw_model_id = $4, is_licensed = $5, role = $6, public_key = $7, is_unmessagable = $8, updated_at = $9
+                WHERE node_id = $10",
             )
-            .await?;
-        }
+            .bind(&user.id)
+            .bind(&user.long_name)
+            .bind(&user.short_name)
+            .bind(user.hw_model)
+            .bind(i64::from(user.is_licensed))
+            .bind(user.role)
+            .bind(&user.public_key)
+            .bind(user.is_unmessagable.map(i64::from))
+            .bind(received_at)
+            .bind(i64::from(node_info.num))
+            .execute(txn)
+            .await
+            .map(|_| ())
+        })?;
+    }
+    search::index_node(&mut txn.executor(), node_info.num).await?;
+
+    if let Some(Position {
+        latitude_i: Some(latitude_i),
+        longitude_i: Some(longitude_i),
+        altitude,
+        ..
+    }) = node_info.position
+    {
+        let latitude = latitude_i as f64 / 1e7;
+        let longitude = longitude_i as f64 / 1e7;
+
+        on_db!(txn, |txn| {
+            sqlx::query(
+                "UPDATE nodes SET latitude = $1, longitude = $2, altitude = $3, updated_at = $4
+                WHERE node_id = $5 AND latitude IS NULL",
+            )
+            .bind(latitude)
+            .bind(longitude)
+            .bind(altitude)
+            .bind(received_at)
+            .bind(i64::from(node_info.num))
+            .execute(txn)
+            .await
+            .map(|_| ())
+        })?;
+    }
+
+    txn.commit().await?;
 
     Ok(())
 }
 
-pub async fn start_server(pool: SqlitePool) -> anyhow::Result<()> {
+/// Maximum number of envelopes imported in one transaction.
+const BATCH_SIZE: i64 = 500;
+
+/// Number of recent packets kept in memory to detect duplicates.
+const DEFAULT_RECENT_PACKETS: usize = 100_000;
+
+const THROUGHPUT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+fn envelopes_queued() -> &'static Notify {
+    static QUEUED: OnceLock<Notify> = OnceLock::new();
+    QUEUED.get_or_init(Notify::new)
+}
+
+/// Wakes up the import server running in this process. Envelopes queued by other processes are
+/// picked up by polling.
+pub fn notify_importer() {
+    envelopes_queued().notify_one();
+}
+
+/// Imports the queued envelopes. Envelopes are decoded and decrypted on a pool of
+/// `import_workers` threads, a single writer stores the batches. Old data is pruned according to
+/// the `retention` table, and the database is backed up according to the `backup` table.
+pub async fn start_server(pool: DbPool) -> anyhow::Result<()> {
     info!("Starting import server");
 
-    let mut stream = IntervalStream::new(time::interval(Duration::from_secs(1)));
+    let ingest_rules = Arc::new(IngestRules::from_config()?);
+    let workers = get_config()
+        .get_int("import_workers")
+        .ok()
+        .map(|workers| workers.max(1) as usize)
+        .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1);
+    let capacity = get_config()
+        .get_int("import_recent_packets")
+        .map(|capacity| capacity.max(1) as usize)
+        .unwrap_or(DEFAULT_RECENT_PACKETS);
+
+    let recent_packets = RecentPackets::load(&pool, capacity).await?;
+    info!("Decoding envelopes with {} workers", workers);
+
+    {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            if let Err(err) = retention::start_pruning(pool).await {
+                error!("Pruning stopped: {:?}", err);
+            }
+        });
+    }
 
-    while (stream.next().await).is_some() {
-        process_service_envelopes(&pool).await?;
+    {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            if let Err(err) = backup::start_backups(pool).await {
+                error!("Backups stopped: {:?}", err);
+            }
+        });
     }
 
-    Ok(())
+    // The decoders can run a batch ahead of the writer
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::select! {
+        result = decode_envelopes(pool.clone(), ingest_rules, workers, sender) => result,
+        result = write_envelopes(pool, recent_packets, receiver) => result,
+    }
 }
 
-async fn process_service_envelopes(pool: &SqlitePool) -> Result<(), anyhow::Error> {
-    let entities = sqlx::query_as!(
-        ServiceEnvelopeSelectResult,
-        "SELECT id, hash, payload_data, created_at FROM service_envelopes WHERE processed_at IS NULL ORDER BY created_at LIMIT 100"
-    ).fetch_all(pool)
-        .await?;
+/// Reads the queued envelopes in batches and decodes them. Envelopes are read in the order they
+/// were queued, each envelope is read once.
+async fn decode_envelopes(
+    pool: DbPool,
+    ingest_rules: Arc<IngestRules>,
+    workers: usize,
+    sender: mpsc::Sender<Vec<DecodedEnvelope>>,
+) -> anyhow::Result<()> {
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut last_id = 0;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = envelopes_queued().notified() => {}
+        }
+
+        // Keep going while there is a backlog
+        loop {
+            let envelopes = on_db!(pool, |pool| {
+                sqlx::query_as::<_, ServiceEnvelopeSelectResult>(
+                    "SELECT id, hash, payload_data, created_at FROM service_envelopes WHERE processed_at IS NULL AND id > $1 ORDER BY id LIMIT $2",
+                )
+                .bind(last_id)
+                .bind(BATCH_SIZE)
+                .fetch_all(pool)
+                .await
+            })?;
+
+            let Some(last) = envelopes.last() else {
+                break;
+            };
+            last_id = last.id;
+            let is_full = envelopes.len() == BATCH_SIZE as usize;
+
+            sender
+                .send(decode_batch(&ingest_rules, workers, envelopes).await?)
+                .await?;
+
+            if !is_full {
+                break;
+            }
+        }
+    }
+}
+
+/// Splits a batch over the workers, the decoded envelopes keep their order.
+async fn decode_batch(
+    ingest_rules: &Arc<IngestRules>,
+    workers: usize,
+    envelopes: Vec<ServiceEnvelopeSelectResult>,
+) -> anyhow::Result<Vec<DecodedEnvelope>> {
+    let chunk_size = envelopes.len().div_ceil(workers).max(1);
+
+    let tasks = envelopes
+        .into_iter()
+        .chunks(chunk_size)
+        .into_iter()
+        .map(|chunk| {
+            let chunk = chunk.collect_vec();
+            let ingest_rules = ingest_rules.clone();
+
+            task::spawn_blocking(move || {
+                chunk
+                    .into_iter()
+                    .map(|envelope| decode_envelope(&ingest_rules, envelope))
+                    .collect_vec()
+            })
+        })
+        .collect_vec();
+
+    let mut decoded = Vec::new();
+    for task in tasks {
+        decoded.extend(task.await?);
+    }
+
+    Ok(decoded)
+}
 
-    for service_envelope in entities {
-        let mut txn = pool.acquire().await?;
-        sqlx::query!("BEGIN IMMEDIATE").execute(&mut *txn).await?;
-        process_service_envelope(&mut txn, service_envelope).await?;
-        sqlx::query!("COMMIT").execute(&mut *txn).await?;
+/// Stores the decoded batches, one transaction per batch.
+async fn write_envelopes(
+    pool: DbPool,
+    mut recent_packets: RecentPackets,
+    mut receiver: mpsc::Receiver<Vec<DecodedEnvelope>>,
+) -> anyhow::Result<()> {
+    let mut imported = 0;
+    let mut last_log = Instant::now();
+
+    while let Some(batch) = receiver.recv().await {
+        let count = batch.len();
+        write_batch(&pool, &mut recent_packets, batch).await?;
+
+        imported += count;
+        let elapsed = last_log.elapsed();
+        if elapsed >= THROUGHPUT_LOG_INTERVAL {
+            info!(
+                "Imported {} envelopes in the last {} seconds ({:.1}/s)",
+                imported,
+                elapsed.as_secs(),
+                imported as f64 / elapsed.as_secs_f64()
+            );
+            imported = 0;
+            last_log = Instant::now();
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a batch in one transaction.
+async fn write_batch(
+    pool: &DbPool,
+    recent_packets: &mut RecentPackets,
+    batch: Vec<DecodedEnvelope>,
+) -> anyhow::Result<()> {
+    // The transaction is rolled back when a write fails
+    let mut txn = pool.begin_write().await?;
+    for decoded_envelope in batch {
+        write_envelope(&mut txn.executor(), recent_packets, decoded_envelope).await?;
     }
+    txn.commit().await?;
+    recent_packets.commit();
 
     Ok(())
 }
 
-pub async fn process_service_envelope(
-    txn: &mut PoolConnection<DB>,
-    service_envelope: ServiceEnvelopeSelectResult,
+/// Imports all queued envelopes in one batch, without ingest rules.
+#[cfg(test)]
+pub(crate) async fn import_queued(
+    pool: &DbPool,
+    recent_packets: &mut RecentPackets,
+) -> anyhow::Result<()> {
+    let envelopes = on_db!(pool, |pool| {
+        sqlx::query_as::<_, ServiceEnvelopeSelectResult>(
+            "SELECT id, hash, payload_data, created_at FROM service_envelopes WHERE processed_at IS NULL ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+    })?;
+
+    let ingest_rules = IngestRules::default();
+    let batch = envelopes
+        .into_iter()
+        .map(|envelope| decode_envelope(&ingest_rules, envelope))
+        .collect();
+
+    write_batch(pool, recent_packets, batch).await
+}
+
+/// Writes the packet of an envelope in a savepoint, so a packet that fails halfway leaves nothing
+/// behind, in the database or in the cache. Packets that are stored but not processed any
+/// further are kept.
+async fn write_envelope(
+    txn: &mut DbConnection<'_>,
+    recent_packets: &mut RecentPackets,
+    decoded_envelope: DecodedEnvelope,
 ) -> Result<(), anyhow::Error> {
-    let result = handle_raw_service_envelope(
-        txn,
-        &service_envelope.hash,
-        &service_envelope.payload_data,
-        service_envelope.created_at,
-    )
-    .await;
-    if let Err(err) = result {
-        if let Some(MeshPacketProcessingError(_)) = err.downcast_ref() {
-            warn!("Skipping packet after processing error: {}", err);
+    let DecodedEnvelope { envelope, packet } = decoded_envelope;
+
+    // The matched rule is recorded, even if the packet could not be processed
+    let matched_rule = packet
+        .as_ref()
+        .and_then(|packet| packet.rule_match.as_ref())
+        .map(|rule_match| rule_match.rule.clone());
+
+    if let Some(packet) = packet {
+        let mut savepoint = txn.begin().await?;
+        let cache_savepoint = recent_packets.savepoint();
+
+        match write_packet(&mut savepoint.executor(), recent_packets, &envelope, packet).await {
+            Ok(()) => savepoint.commit().await?,
+            Err(err) if err.downcast_ref::<MeshPacketProcessingError>().is_some() => {
+                warn!("Skipping packet after processing error: {}", err);
+                savepoint.commit().await?;
+            }
+            Err(err) => {
+                debug!("Skipping packet: {}", err);
+                savepoint.rollback().await?;
+                recent_packets.rollback_to(cache_savepoint);
+            }
         }
-    } else {
-        result?;
     }
 
     // Get the current timestamp in nanoseconds
     let processed_at = chrono::Utc::now().timestamp_nanos_opt();
 
     // Update the processed_at field for the given service_envelope_id
-    sqlx::query!(
-        "UPDATE service_envelopes SET processed_at = ? WHERE id = ?",
-        processed_at,
-        service_envelope.id
-    )
-    .execute(&mut **txn)
-    .await?;
+    on_db!(txn, |txn| {
+        sqlx::query(
+            "UPDATE service_envelopes SET processed_at = $1, matched_rule = $2 WHERE id = $3",
+        )
+        .bind(processed_at)
+        .bind(&matched_rule)
+        .bind(envelope.id)
+        .execute(txn)
+        .await
+        .map(|_| ())
+    })?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::connect_to_test_db;
+
+    async fn search_node_ids(pool: &DbPool, input: &str) -> Vec<i64> {
+        let expression = search::expression(pool, input).unwrap();
+        search::search_nodes(pool, &expression)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn imported_node_info_is_searchable() {
+        let pool = connect_to_test_db().await;
+        let mut node_info = NodeInfo {
+            num: 0x1234abcd,
+            user: Some(User {
+                id: "!1234abcd".to_string(),
+                long_name: "Antenna Hill".to_string(),
+                short_name: "AH".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        import_node_info(&pool, &node_info, 1).await.unwrap();
+        assert_eq!(search_node_ids(&pool, "anten").await, [0x1234abcd]);
+
+        node_info.user.as_mut().unwrap().long_name = "Water Tower".to_string();
+        import_node_info(&pool, &node_info, 2).await.unwrap();
+        assert!(search_node_ids(&pool, "anten").await.is_empty());
+        assert_eq!(search_node_ids(&pool, "tower").await, [0x1234abcd]);
+    }
+}
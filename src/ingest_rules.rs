@@ -0,0 +1,359 @@
+use crate::{
+    proto::meshtastic::{mesh_packet::PayloadVariant::Decoded, MeshPacket, PortNum, Position},
+    util::config::get_config,
+};
+use anyhow::anyhow;
+use prost::Message;
+use serde::Deserialize;
+use tracing::info;
+
+/// What happens with packets that match one of the ingest rules.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// The packet is not stored at all.
+    #[default]
+    Discard,
+    /// The packet is stored, but hidden from the web interface.
+    Hide,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ChannelSelector {
+    Hash(u32),
+    Name(String),
+}
+
+impl ChannelSelector {
+    fn matches(&self, channel_name: &str, channel_hash: u32) -> bool {
+        match self {
+            ChannelSelector::Hash(hash) => *hash == channel_hash,
+            ChannelSelector::Name(name) => {
+                name == channel_name || name.parse::<u32>().is_ok_and(|hash| hash == channel_hash)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct IngestRulesConfig {
+    action: RuleAction,
+    allow_gateways: Vec<String>,
+    deny_gateways: Vec<String>,
+    allow_channels: Vec<ChannelSelector>,
+    deny_channels: Vec<ChannelSelector>,
+    /// Polygon of `[latitude, longitude]` pairs, node positions outside of it are rejected.
+    area: Vec<[f64; 2]>,
+    max_hops: Option<u32>,
+}
+
+/// The ingest rule a packet matched, stored with the service envelope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub action: RuleAction,
+}
+
+/// Rules to filter out traffic from the `ingest_rules` configuration table before it is imported.
+#[derive(Clone, Debug, Default)]
+pub struct IngestRules {
+    config: IngestRulesConfig,
+}
+
+impl IngestRules {
+    pub fn from_config() -> anyhow::Result<IngestRules> {
+        let config: IngestRulesConfig = match get_config().get("ingest_rules") {
+            Ok(config) => config,
+            Err(config::ConfigError::NotFound(_)) => IngestRulesConfig::default(),
+            Err(err) => return Err(anyhow!(err)),
+        };
+
+        let rules = IngestRules::new(config)?;
+
+        if !rules.is_empty() {
+            info!("Ingest rules enabled: {:?}", rules.config);
+        }
+
+        Ok(rules)
+    }
+
+    fn new(config: IngestRulesConfig) -> anyhow::Result<IngestRules> {
+        if !config.area.is_empty() && config.area.len() < 3 {
+            return Err(anyhow!("ingest_rules.area needs at least three points"));
+        }
+
+        let config = IngestRulesConfig {
+            allow_gateways: config
+                .allow_gateways
+                .iter()
+                .map(String::as_str)
+                .map(normalize_gateway_id)
+                .collect(),
+            deny_gateways: config
+                .deny_gateways
+                .iter()
+                .map(String::as_str)
+                .map(normalize_gateway_id)
+                .collect(),
+            ..config
+        };
+
+        Ok(IngestRules { config })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.config.allow_gateways.is_empty()
+            && self.config.deny_gateways.is_empty()
+            && self.config.allow_channels.is_empty()
+            && self.config.deny_channels.is_empty()
+            && self.config.area.is_empty()
+            && self.config.max_hops.is_none()
+    }
+
+    /// Evaluates the rules for a (decrypted) packet, returning the first rule that matched.
+    pub fn evaluate(
+        &self,
+        gateway_id: &str,
+        channel_name: &str,
+        packet: &MeshPacket,
+    ) -> Option<RuleMatch> {
+        let gateway_id = normalize_gateway_id(gateway_id);
+        let config = &self.config;
+
+        let rule =
+            if !config.allow_gateways.is_empty() && !config.allow_gateways.contains(&gateway_id) {
+                Some(format!("allow_gateways: {}", gateway_id))
+            } else if config.deny_gateways.contains(&gateway_id) {
+                Some(format!("deny_gateways: {}", gateway_id))
+            } else if !config.allow_channels.is_empty()
+                && !config
+                    .allow_channels
+                    .iter()
+                    .any(|channel| channel.matches(channel_name, packet.channel))
+            {
+                Some(format!("allow_channels: {}", channel_name))
+            } else if config
+                .deny_channels
+                .iter()
+                .any(|channel| channel.matches(channel_name, packet.channel))
+            {
+                Some(format!("deny_channels: {}", channel_name))
+            } else if let Some(max_hops) = config
+                .max_hops
+                .filter(|max_hops| num_hops(packet) > *max_hops)
+            {
+                Some(format!("max_hops: {}", max_hops))
+            } else if self.position_in_area(packet) == Some(false) {
+                Some("area".to_string())
+            } else {
+                None
+            };
+
+        rule.map(|rule| RuleMatch {
+            rule,
+            action: config.action,
+        })
+    }
+
+    /// Returns whether the position in a position packet lies within the configured area, or
+    /// `None` if the packet has no position or no area is configured.
+    pub fn position_in_area(&self, packet: &MeshPacket) -> Option<bool> {
+        if self.config.area.is_empty() {
+            return None;
+        }
+
+        let Some(Decoded(ref data)) = packet.payload_variant else {
+            return None;
+        };
+
+        if data.portnum != PortNum::PositionApp as i32 {
+            return None;
+        }
+
+        let position = Position::decode(&*data.payload).ok()?;
+        let latitude = position.latitude_i? as f64 / 1e7;
+        let longitude = position.longitude_i? as f64 / 1e7;
+
+        Some(point_in_polygon(&self.config.area, latitude, longitude))
+    }
+}
+
+fn normalize_gateway_id(gateway_id: &str) -> String {
+    let gateway_id = gateway_id.trim().to_lowercase();
+
+    if gateway_id.starts_with('!') {
+        gateway_id
+    } else {
+        format!("!{}", gateway_id)
+    }
+}
+
+fn num_hops(packet: &MeshPacket) -> u32 {
+    packet.hop_start.saturating_sub(packet.hop_limit)
+}
+
+/// Ray casting point in polygon test, good enough for the small areas a mesh covers.
+fn point_in_polygon(polygon: &[[f64; 2]], latitude: f64, longitude: f64) -> bool {
+    let mut inside = false;
+    let mut previous = polygon[polygon.len() - 1];
+
+    for &current in polygon {
+        let [lat_i, lon_i] = current;
+        let [lat_j, lon_j] = previous;
+
+        if (lat_i > latitude) != (lat_j > latitude)
+            && longitude < (lon_j - lon_i) * (latitude - lat_i) / (lat_j - lat_i) + lon_i
+        {
+            inside = !inside;
+        }
+
+        previous = current;
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::meshtastic::{mesh_packet::PayloadVariant, Data};
+
+    fn packet(channel: u32, hop_start: u32, hop_limit: u32) -> MeshPacket {
+        MeshPacket {
+            from: 0xdeadbeef,
+            channel,
+            hop_start,
+            hop_limit,
+            ..Default::default()
+        }
+    }
+
+    fn position_packet(latitude: f64, longitude: f64) -> MeshPacket {
+        let position = Position {
+            latitude_i: Some((latitude * 1e7) as i32),
+            longitude_i: Some((longitude * 1e7) as i32),
+            ..Default::default()
+        };
+
+        MeshPacket {
+            payload_variant: Some(PayloadVariant::Decoded(Data {
+                portnum: PortNum::PositionApp as i32,
+                payload: position.encode_to_vec(),
+                ..Default::default()
+            })),
+            ..packet(8, 3, 3)
+        }
+    }
+
+    fn area() -> Vec<[f64; 2]> {
+        vec![[52.0, 4.0], [52.0, 5.0], [53.0, 5.0], [53.0, 4.0]]
+    }
+
+    #[test]
+    fn no_rules_match_nothing() {
+        let rules = IngestRules::default();
+
+        assert!(rules.is_empty());
+        assert_eq!(
+            rules.evaluate("!deadbeef", "LongFast", &packet(8, 7, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn gateways_are_compared_normalized() {
+        let rules = IngestRules::new(IngestRulesConfig {
+            allow_gateways: vec!["DEADBEEF".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            rules.evaluate("!deadbeef", "LongFast", &packet(8, 3, 3)),
+            None
+        );
+        assert_eq!(
+            rules.evaluate("!cafebabe", "LongFast", &packet(8, 3, 3)),
+            Some(RuleMatch {
+                rule: "allow_gateways: !cafebabe".to_string(),
+                action: RuleAction::Discard,
+            })
+        );
+    }
+
+    #[test]
+    fn channels_match_by_name_or_hash() {
+        let rules = IngestRules::new(IngestRulesConfig {
+            action: RuleAction::Hide,
+            deny_channels: vec![
+                ChannelSelector::Name("Private".to_string()),
+                ChannelSelector::Hash(31),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            rules.evaluate("!deadbeef", "LongFast", &packet(8, 3, 3)),
+            None
+        );
+        assert_eq!(
+            rules
+                .evaluate("!deadbeef", "Private", &packet(8, 3, 3))
+                .map(|rule_match| rule_match.action),
+            Some(RuleAction::Hide)
+        );
+        assert!(rules
+            .evaluate("!deadbeef", "Other", &packet(31, 3, 3))
+            .is_some());
+    }
+
+    #[test]
+    fn packets_over_max_hops_match() {
+        let rules = IngestRules::new(IngestRulesConfig {
+            max_hops: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            rules.evaluate("!deadbeef", "LongFast", &packet(8, 7, 5)),
+            None
+        );
+        assert_eq!(
+            rules
+                .evaluate("!deadbeef", "LongFast", &packet(8, 7, 4))
+                .map(|rule_match| rule_match.rule),
+            Some("max_hops: 2".to_string())
+        );
+    }
+
+    #[test]
+    fn positions_outside_the_area_match() {
+        let rules = IngestRules::new(IngestRulesConfig {
+            area: area(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let inside = position_packet(52.5, 4.5);
+        let outside = position_packet(51.5, 4.5);
+
+        assert_eq!(rules.position_in_area(&inside), Some(true));
+        assert_eq!(rules.position_in_area(&outside), Some(false));
+        assert_eq!(rules.position_in_area(&packet(8, 3, 3)), None);
+        assert_eq!(rules.evaluate("!deadbeef", "LongFast", &inside), None);
+        assert!(rules.evaluate("!deadbeef", "LongFast", &outside).is_some());
+    }
+
+    #[test]
+    fn an_area_needs_three_points() {
+        assert!(IngestRules::new(IngestRulesConfig {
+            area: vec![[52.0, 4.0], [53.0, 5.0]],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -1,18 +1,34 @@
 #![windows_subsystem = "console"]
 
+mod archive;
+mod backup;
+mod broker;
+mod capture;
+mod downlink;
 mod dto;
+mod event_hub;
+mod forward;
 mod import;
+mod ingest_rules;
+mod merge;
 mod mqtt_processor;
 mod proto;
+mod radio;
+mod recent_packets;
+mod retention;
+mod rollups;
+mod search;
+mod simulate;
 mod template;
+mod traceroute;
 mod util;
 mod web_interface;
 
-use sqlx::SqlitePool;
+use chrono::DateTime;
 use std::{env, process::exit};
 use tokio::sync::oneshot::{self, error::RecvError, Receiver};
 use tracing::{error, info};
-use util::{config::get_config, connect_to_db, setup_tracing};
+use util::{config::get_config, connect_to_db, database_url, setup_tracing, DbPool};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -32,31 +48,204 @@ async fn main() -> anyhow::Result<()> {
     let args: &Vec<String> = &env::args().collect();
 
     let choice = args.get(1).cloned().unwrap_or("all".into());
+    let database_url = database_url(&choice)?;
+
+    // The database is replaced, so it must not be opened
+    if choice == "restore" {
+        match args.get(2) {
+            Some(path) => backup::restore(&database_url, path.as_ref()).await?,
+            None => {
+                println!("Usage: meshstellar restore <backup file>, while meshstellar is stopped")
+            }
+        }
+        return Ok(());
+    }
+
+    // The merged envelopes are matched with the packets around them when the import server
+    // starts, it must not import them while they are being merged
+    if choice == "merge" {
+        backup::stopped_database_path(&database_url).await?;
+    }
 
     let http_addr = get_config().get_string("http_addr")?;
-    let pool = connect_to_db().await?;
+    let pool = connect_to_db(&database_url).await?;
 
     match choice.as_str() {
         "all" => {
-            let mqtt_channel = start_mqtt_processor(pool.clone());
+            // The radio ingests only run when they are configured
+            let tcp_addresses = radio::tcp::configured_addresses()?;
+            let tcp_channel = optional_channel(
+                (!tcp_addresses.is_empty()).then(|| start_tcp(pool.clone(), tcp_addresses)),
+            );
+            let udp_channel = optional_channel(
+                get_config()
+                    .get_bool("udp_multicast")
+                    .unwrap_or(false)
+                    .then(|| start_udp(pool.clone())),
+            );
+            // The embedded broker replaces the connection to an external broker
+            let mqtt_channel = if broker::is_configured()? {
+                start_broker(pool.clone())
+            } else {
+                start_mqtt_processor(pool.clone())
+            };
             let import_channel = start_import(pool.clone());
-            let web_channel = start_webserver(pool, http_addr);
+            let web_channel = start_webserver(pool, database_url, http_addr);
 
             tokio::select! {
                 res = mqtt_channel => handle_nested_result(res),
+                res = tcp_channel => handle_nested_result(res),
+                res = udp_channel => handle_nested_result(res),
                 res = import_channel => handle_nested_result(res),
                 res = web_channel => handle_nested_result(res),
             }
         }
         "mqtt" => handle_result(start_mqtt_processor(pool).await?),
-        "web" => handle_result(start_webserver(pool, http_addr).await?),
+        "web" => handle_result(start_webserver(pool, database_url, http_addr).await?),
         "import" => handle_result(start_import(pool).await?),
-        _ => println!("Make a valid choice (all, mqtt, web, import)"),
+        "serial" => match args.get(2) {
+            Some(path) => handle_result(start_serial(pool, path.clone()).await?),
+            None => println!("Usage: meshstellar serial <port>, e.g. /dev/ttyUSB0 or COM3"),
+        },
+        "tcp" => {
+            let addresses = match &args[2..] {
+                [] => radio::tcp::configured_addresses()?,
+                hosts => hosts
+                    .iter()
+                    .map(|host| radio::tcp::with_default_port(host))
+                    .collect(),
+            };
+
+            if addresses.is_empty() {
+                println!("Usage: meshstellar tcp <host[:port]>..., or configure radio_tcp_hosts");
+            } else {
+                handle_result(start_tcp(pool, addresses).await?)
+            }
+        }
+        "udp" => handle_result(start_udp(pool).await?),
+        "broker" => handle_result(start_broker(pool).await?),
+        "forward" => {
+            let to =
+                option_value(args, "--to").or_else(|| get_config().get_string("forward_to").ok());
+
+            match to {
+                Some(to) => handle_result(start_forward(pool, to).await?),
+                None => println!("Usage: meshstellar forward --to <url>, or configure forward_to"),
+            }
+        }
+        "simulate" => {
+            // Demo without radios: the simulated traffic is imported and shown right away
+            let simulate_channel = start_simulate(pool.clone());
+            let import_channel = start_import(pool.clone());
+            let web_channel = start_webserver(pool, database_url, http_addr);
+
+            tokio::select! {
+                res = simulate_channel => handle_nested_result(res),
+                res = import_channel => handle_nested_result(res),
+                res = web_channel => handle_nested_result(res),
+            }
+        }
+        "export" => match positional_arg(args, &["--format", "--since"]) {
+            Some(path) => {
+                let format = match option_value(args, "--format") {
+                    Some(format) => capture::Format::parse(&format)?,
+                    None => capture::Format::from_path(path),
+                };
+                let since = match option_value(args, "--since") {
+                    Some(since) => DateTime::parse_from_rfc3339(&since)?
+                        .timestamp_nanos_opt()
+                        .unwrap_or(0),
+                    None => 0,
+                };
+
+                let exported = capture::export(&pool, path, format, since).await?;
+                info!("Exported {} envelopes to {}", exported, path);
+            }
+            None => println!(
+                "Usage: meshstellar export <file> [--format delimited|jsonl] [--since <RFC 3339>]"
+            ),
+        },
+        "replay" => match positional_arg(args, &["--format", "--speed"]) {
+            Some(path) => {
+                let format = match option_value(args, "--format") {
+                    Some(format) => capture::Format::parse(&format)?,
+                    None => capture::Format::from_path(path),
+                };
+                let speed = option_value(args, "--speed")
+                    .map(|speed| speed.parse::<f64>())
+                    .transpose()?
+                    .filter(|speed| *speed > 0.0);
+
+                let replayed = capture::replay(&pool, path, format, speed).await?;
+                info!("Replayed {} envelopes from {}", replayed, path);
+            }
+            None => println!(
+                "Usage: meshstellar replay <file> [--format delimited|jsonl] [--speed <factor>]"
+            ),
+        },
+        "vacuum" => {
+            info!("Vacuuming the database, this can take a while");
+            retention::vacuum(pool.sqlite()?).await?;
+        }
+        "backup" => match args.get(2) {
+            Some(path) => backup::backup(pool.sqlite()?, path.as_ref()).await?,
+            None => {
+                backup::backup_now(pool.sqlite()?).await?;
+            }
+        },
+        "merge" => match args.get(2) {
+            Some(path) => {
+                merge::merge(pool.sqlite()?, path.as_ref()).await?;
+            }
+            None => {
+                println!("Usage: meshstellar merge <other database>, while meshstellar is stopped")
+            }
+        },
+        "archive" => match option_value(args, "--before") {
+            Some(before) => archive::archive(pool.sqlite()?, archive::parse_month(&before)?).await?,
+            None => println!("Usage: meshstellar archive --before <YYYY-MM>"),
+        },
+        _ => println!(
+            "Make a valid choice (all, mqtt, web, import, serial, tcp, udp, broker, forward, export, replay, simulate, vacuum, backup, restore, archive, merge)"
+        ),
     }
 
     Ok(())
 }
 
+/// Returns the value following `name` on the command line, e.g. `--to <url>`.
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1).cloned())
+}
+
+/// Returns the first argument after the mode that is neither one of the `options` nor an option
+/// value, e.g. the file of `export --format jsonl <file>`.
+fn positional_arg<'a>(args: &'a [String], options: &[&str]) -> Option<&'a String> {
+    let mut rest = args.iter().skip(2);
+
+    while let Some(arg) = rest.next() {
+        if options.contains(&arg.as_str()) {
+            rest.next();
+        } else {
+            return Some(arg);
+        }
+    }
+
+    None
+}
+
+/// Waits for an optional channel, forever if there is none.
+async fn optional_channel(
+    channel: Option<Receiver<anyhow::Result<()>>>,
+) -> Result<anyhow::Result<()>, RecvError> {
+    match channel {
+        Some(channel) => channel.await,
+        None => std::future::pending().await,
+    }
+}
+
 fn handle_nested_result(res: Result<anyhow::Result<()>, RecvError>) {
     match res {
         Err(err) => {
@@ -82,17 +271,21 @@ fn handle_result(res: anyhow::Result<()>) {
     }
 }
 
-fn start_webserver(pool: SqlitePool, http_addr: String) -> Receiver<anyhow::Result<()>> {
+fn start_webserver(
+    pool: DbPool,
+    database_url: String,
+    http_addr: String,
+) -> Receiver<anyhow::Result<()>> {
     let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
     tokio::spawn(async move {
         sender
-            .send(web_interface::start_server(pool, http_addr).await)
+            .send(web_interface::start_server(pool, database_url, http_addr).await)
             .unwrap();
     });
     receiver
 }
 
-fn start_mqtt_processor(pool: SqlitePool) -> Receiver<anyhow::Result<()>> {
+fn start_mqtt_processor(pool: DbPool) -> Receiver<anyhow::Result<()>> {
     let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
     tokio::spawn(async move {
         sender
@@ -102,10 +295,95 @@ fn start_mqtt_processor(pool: SqlitePool) -> Receiver<anyhow::Result<()>> {
     receiver
 }
 
-fn start_import(pool: SqlitePool) -> Receiver<anyhow::Result<()>> {
+fn start_import(pool: DbPool) -> Receiver<anyhow::Result<()>> {
     let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
     tokio::spawn(async move {
         sender.send(import::start_server(pool).await).unwrap();
     });
     receiver
 }
+
+fn start_serial(pool: DbPool, path: String) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender
+            .send(radio::serial::start_server(pool, path).await)
+            .unwrap();
+    });
+    receiver
+}
+
+fn start_tcp(pool: DbPool, addresses: Vec<String>) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender
+            .send(radio::tcp::start_server(pool, addresses).await)
+            .unwrap();
+    });
+    receiver
+}
+
+fn start_udp(pool: DbPool) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender.send(radio::udp::start_server(pool).await).unwrap();
+    });
+    receiver
+}
+
+fn start_broker(pool: DbPool) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender.send(broker::start_server(pool).await).unwrap();
+    });
+    receiver
+}
+
+fn start_forward(pool: DbPool, to: String) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender.send(forward::start_server(pool, to).await).unwrap();
+    });
+    receiver
+}
+
+fn start_simulate(pool: DbPool) -> Receiver<anyhow::Result<()>> {
+    let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+    tokio::spawn(async move {
+        sender.send(simulate::start_server(pool).await).unwrap();
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn positional_arg_skips_options_and_their_values() {
+        let options = ["--format", "--since"];
+
+        assert_eq!(
+            positional_arg(&args(&["meshstellar", "export", "capture.bin"]), &options),
+            Some(&"capture.bin".to_string())
+        );
+        assert_eq!(
+            positional_arg(
+                &args(&["meshstellar", "export", "--format", "jsonl", "capture"]),
+                &options
+            ),
+            Some(&"capture".to_string())
+        );
+        assert_eq!(
+            positional_arg(
+                &args(&["meshstellar", "export", "--format", "jsonl"]),
+                &options
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Merges the envelopes of another meshstellar database. They are queued like received envelopes
+//! and imported the next time the import server starts, which matches them with the packets that
+//! were received around the same time, so packets both instances saw are marked as repeats.
+
+use crate::{archive, util::MIGRATOR};
+use anyhow::anyhow;
+use sqlx::{
+    sqlite::SqliteConnectOptions, ConnectOptions, Connection, SqliteConnection, SqlitePool,
+};
+use std::path::Path;
+use tracing::info;
+
+/// The columns of the envelopes of the other database that are merged.
+const ENVELOPE_COLUMNS: [&str; 4] = ["payload_data", "hash", "topic", "created_at"];
+
+/// Queues the envelopes of the database at `path` that are not in this database or its archives
+/// yet, by their hash. The other database is only read. Returns the number of queued envelopes.
+pub async fn merge(pool: &SqlitePool, path: &Path) -> anyhow::Result<u64> {
+    if !path.exists() {
+        return Err(anyhow!("{} does not exist", path.display()));
+    }
+
+    let mut other = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await?;
+    let result = check_schema(&mut other).await;
+    other.close().await?;
+    result.map_err(|err| anyhow!("Cannot merge {}: {}", path.display(), err))?;
+
+    let mut conn = pool.acquire().await?;
+    sqlx::query("ATTACH DATABASE ? AS other")
+        .bind(format!("file:{}?mode=ro", path.to_string_lossy()))
+        .execute(&mut *conn)
+        .await?;
+    let result = queue_envelopes(&mut conn).await;
+    sqlx::query("DETACH DATABASE other")
+        .execute(&mut *conn)
+        .await?;
+    let queued = result?;
+
+    info!(
+        "Queued {} envelopes from {}, they are imported when meshstellar starts",
+        queued,
+        path.display()
+    );
+
+    Ok(queued)
+}
+
+/// The other database must have been migrated by this version of meshstellar or an older one,
+/// and have the merged columns.
+async fn check_schema(other: &mut SqliteConnection) -> anyhow::Result<()> {
+    let versions: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(&mut *other)
+            .await
+            .map_err(|_| anyhow!("it is not a meshstellar database"))?;
+    if let Some(version) = versions.iter().find(|version| {
+        !MIGRATOR
+            .iter()
+            .any(|migration| migration.version == **version)
+    }) {
+        return Err(anyhow!(
+            "it has migration {}, which this version of meshstellar does not know, upgrade meshstellar first",
+            version
+        ));
+    }
+
+    let columns: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM pragma_table_info('service_envelopes')")
+            .fetch_all(&mut *other)
+            .await?;
+    if let Some(column) = ENVELOPE_COLUMNS
+        .iter()
+        .find(|column| !columns.iter().any(|name| name == *column))
+    {
+        return Err(anyhow!(
+            "its envelopes have no {} column, run a current meshstellar on a copy of it first",
+            column
+        ));
+    }
+
+    Ok(())
+}
+
+/// Envelopes are also compared with the packets, their envelopes may have been pruned already.
+/// The new envelopes are collected first, so the archives can be attached one at a time to leave
+/// out the envelopes they hold. They are queued in the order they were received.
+async fn queue_envelopes(conn: &mut SqliteConnection) -> anyhow::Result<u64> {
+    sqlx::query(
+        "CREATE TEMP TABLE merged_envelopes AS
+         SELECT id, payload_data, hash, topic, created_at FROM other.service_envelopes AS envelope
+         WHERE NOT EXISTS (SELECT 1 FROM main.service_envelopes WHERE hash = envelope.hash)
+         AND NOT EXISTS (SELECT 1 FROM main.mesh_packets WHERE hash = envelope.hash)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let result = queue_new_envelopes(conn).await;
+    sqlx::query("DROP TABLE temp.merged_envelopes")
+        .execute(&mut *conn)
+        .await?;
+
+    result
+}
+
+async fn queue_new_envelopes(conn: &mut SqliteConnection) -> anyhow::Result<u64> {
+    for (_, path) in archive::archive_files()? {
+        archive::attach(conn, &path).await?;
+        let result = sqlx::query(
+            "DELETE FROM temp.merged_envelopes AS envelope
+             WHERE EXISTS (SELECT 1 FROM archive.service_envelopes WHERE hash = envelope.hash)
+             OR EXISTS (SELECT 1 FROM archive.mesh_packets WHERE hash = envelope.hash)",
+        )
+        .execute(&mut *conn)
+        .await;
+        archive::detach(conn).await?;
+        result?;
+    }
+
+    let result = sqlx::query(
+        "INSERT INTO main.service_envelopes (payload_data, hash, topic, created_at)
+         SELECT payload_data, hash, topic, created_at FROM temp.merged_envelopes
+         ORDER BY created_at, id",
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        import::{import_queued, queue_service_envelope},
+        proto::meshtastic::{
+            mesh_packet::PayloadVariant, Data, MeshPacket, PortNum, ServiceEnvelope,
+        },
+        recent_packets::RecentPackets,
+        util::{connect_to_sqlite_test_db, Db, DbPool},
+    };
+    use prost::Message;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::fs;
+
+    fn envelope(packet_id: u32, gateway_id: &str, rx_time: u32, text: &str) -> Vec<u8> {
+        ServiceEnvelope {
+            packet: Some(MeshPacket {
+                from: 0x1234abcd,
+                to: 0xffffffff,
+                id: packet_id,
+                rx_time,
+                payload_variant: Some(PayloadVariant::Decoded(Data {
+                    portnum: PortNum::TextMessageApp as i32,
+                    payload: text.as_bytes().to_vec(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            channel_id: "LongFast".to_string(),
+            gateway_id: gateway_id.to_string(),
+        }
+        .encode_to_vec()
+    }
+
+    async fn queue(pool: &DbPool, envelope: &[u8], rx_time: u32) {
+        let mut conn = pool.acquire().await.unwrap();
+        queue_service_envelope(
+            &mut conn.executor(),
+            envelope,
+            None,
+            rx_time as i64 * 1_000_000_000,
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn repeat_links(pool: &SqlitePool) -> Vec<(String, Option<i64>, i64)> {
+        sqlx::query_as(
+            "SELECT gateway_id, duplicate_of_mesh_packet_id, id FROM mesh_packets ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn merged_repeats_link_to_the_first_packet() {
+        let time = 1_750_000_000;
+        let received = envelope(1, "!0000aaaa", time, "hello");
+        let repeat = envelope(1, "!0000bbbb", time + 5, "hello");
+        let later = envelope(2, "!0000aaaa", time + 60, "later");
+
+        let pool = connect_to_sqlite_test_db().await;
+        queue(&pool, &received, time).await;
+        queue(&pool, &later, time + 60).await;
+        import_queued(&pool, &mut RecentPackets::new(10))
+            .await
+            .unwrap();
+
+        // The other instance received the first packet through both gateways
+        let path =
+            std::env::temp_dir().join(format!("meshstellar-merge-{}.db", std::process::id()));
+        let other = SqlitePoolOptions::new()
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(&path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+        MIGRATOR.run(&other).await.unwrap();
+        let other = Db::Sqlite(other);
+        queue(&other, &received, time).await;
+        queue(&other, &repeat, time + 5).await;
+        other.sqlite().unwrap().close().await;
+
+        let queued = merge(pool.sqlite().unwrap(), &path).await;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(queued.unwrap(), 1);
+
+        // Only the latest packet is cached, the first one is looked up in the database
+        let mut recent_packets = RecentPackets::load(&pool, 1).await.unwrap();
+        import_queued(&pool, &mut recent_packets).await.unwrap();
+
+        let links = repeat_links(pool.sqlite().unwrap()).await;
+        let first = links[0].2;
+        assert_eq!(
+            links
+                .into_iter()
+                .map(|(gateway_id, repeat_of, _)| (gateway_id, repeat_of))
+                .collect::<Vec<_>>(),
+            [
+                ("!0000aaaa".to_string(), None),
+                ("!0000aaaa".to_string(), None),
+                ("!0000bbbb".to_string(), Some(first)),
+            ]
+        );
+    }
+}
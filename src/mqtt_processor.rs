@@ -1,16 +1,40 @@
 use chrono::Utc;
-use rumqttc::{ConnAck, ConnectReturnCode, Event, Incoming, QoS, SubAck};
-use sqlx::SqlitePool;
+use rumqttc::{ConnAck, ConnectReturnCode, Event, Incoming, SubAck};
 use std::time::Duration;
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
 
-use crate::{dto::ServiceEnvelopeSelectResult, util::connect_to_mqtt};
+use crate::{
+    forward::spool_envelope,
+    import::queue_service_envelope,
+    util::{
+        connect_to_mqtt, format_subscriptions, mqtt_subscriptions, mqtt_topic_exclusions, DbPool,
+    },
+};
 
-pub async fn start_server(pool: SqlitePool) -> anyhow::Result<()> {
-    let mqtt_topic = crate::util::config::get_config().get_string("mqtt_topic")?;
+/// Where the received envelopes are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// The `service_envelopes` table, processed by the import server.
+    Import,
+    /// The `forward_queue` table, uploaded to a central instance by the forwarder.
+    Forward,
+}
+
+pub async fn start_server(pool: DbPool) -> anyhow::Result<()> {
+    subscribe(pool, Destination::Import).await
+}
+
+/// Subscribes to the configured topics and stores every message in the destination.
+pub async fn subscribe(pool: DbPool, destination: Destination) -> anyhow::Result<()> {
+    let subscriptions = mqtt_subscriptions()?;
+    let exclusions = mqtt_topic_exclusions()?;
     info!("Starting MQTT processor");
 
+    if !exclusions.is_empty() {
+        info!("Excluding MQTT topics {}", exclusions.join(", "));
+    }
+
     let (mut eventloop, client) = connect_to_mqtt().await?;
 
     loop {
@@ -31,32 +55,46 @@ pub async fn start_server(pool: SqlitePool) -> anyhow::Result<()> {
                 info!("Reconnected to broker!");
 
                 if !session_present {
-                    info!("Resubscribing to topic {}", mqtt_topic);
-                    client.subscribe(&mqtt_topic, QoS::AtLeastOnce).await?;
+                    info!(
+                        "Resubscribing to topics {}",
+                        format_subscriptions(&subscriptions)
+                    );
+                    client.subscribe_many(subscriptions.clone()).await?;
                 }
             }
             Ok(Event::Incoming(Incoming::SubAck(SubAck {
                 pkid: _,
                 return_codes: _,
             }))) => {
-                info!("MQTT subscribed to {}", mqtt_topic);
+                info!(
+                    "MQTT subscribed to {}",
+                    format_subscriptions(&subscriptions)
+                );
+            }
+            Ok(Event::Incoming(Incoming::Publish(p)))
+                if exclusions
+                    .iter()
+                    .any(|exclusion| rumqttc::matches(&p.topic, exclusion)) =>
+            {
+                debug!("Skipping message on excluded topic {}", p.topic);
             }
             Ok(Event::Incoming(Incoming::Publish(p))) => {
-                let raw_message = p.payload.to_vec();
-
-                let raw_message_hash = blake3::hash(&raw_message).as_bytes().to_vec();
                 let created_at = Utc::now().timestamp_nanos_opt().unwrap();
-                let _service_envelope: ServiceEnvelopeSelectResult = sqlx::query_as!(
-                    ServiceEnvelopeSelectResult,
-                    "INSERT INTO service_envelopes (payload_data, hash, created_at)
-                 VALUES (?, ?, ?)
-                 RETURNING id, hash, payload_data, created_at",
-                    raw_message,
-                    raw_message_hash, // Convert hash to bytes if not already in this format
-                    created_at,
-                )
-                .fetch_one(&pool)
-                .await?;
+
+                match destination {
+                    Destination::Import => {
+                        queue_service_envelope(
+                            &mut pool.acquire().await?.executor(),
+                            &p.payload,
+                            Some(&p.topic),
+                            created_at,
+                        )
+                        .await?
+                    }
+                    Destination::Forward => {
+                        spool_envelope(&pool, &p.payload, &p.topic, created_at).await?
+                    }
+                }
             }
             event => {
                 debug!("MQTT event: {:?}", event);
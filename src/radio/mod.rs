@@ -0,0 +1,397 @@
+//! Ingest directly from radios, over the Meshtastic stream protocol (serial and TCP) or UDP
+//! multicast.
+
+pub mod serial;
+pub mod stream;
+pub mod tcp;
+pub mod udp;
+
+use crate::{
+    import,
+    proto::meshtastic::{
+        channel,
+        config::{self, lo_ra_config::ModemPreset},
+        from_radio, to_radio, Config, Heartbeat, MeshPacket, ServiceEnvelope, ToRadio,
+    },
+    util::{
+        capitalize,
+        crypto::{channel_hash, expand_channel_key},
+        DbPool,
+    },
+};
+use chrono::Utc;
+use itertools::Itertools;
+use prost::Message;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use stream::{read_from_radio, write_to_radio, START2};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+use tracing::{debug, info};
+
+/// The radio drops clients that stay silent for too long.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A radio with an active session, used to send packets into the mesh without a broker.
+#[derive(Clone, Debug)]
+pub struct ConnectedRadio {
+    pub node_num: u32,
+    /// Channel indexes and names of the enabled channels of the radio.
+    pub channels: Vec<(u32, String)>,
+    sender: mpsc::Sender<ToRadio>,
+}
+
+impl ConnectedRadio {
+    /// Hands a packet to the radio, which encrypts and transmits it.
+    pub async fn send(&self, packet: MeshPacket) -> anyhow::Result<()> {
+        self.sender
+            .send(ToRadio {
+                payload_variant: Some(to_radio::PayloadVariant::Packet(packet)),
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+fn connected_radios() -> &'static Mutex<HashMap<String, ConnectedRadio>> {
+    static RADIOS: OnceLock<Mutex<HashMap<String, ConnectedRadio>>> = OnceLock::new();
+    RADIOS.get_or_init(Default::default)
+}
+
+/// Returns the radio connected as `name` (the serial port or TCP address) in this process.
+pub fn connected_radio(name: &str) -> Option<ConnectedRadio> {
+    connected_radios().lock().unwrap().get(name).cloned()
+}
+
+/// Requests the configuration of the radio and queues every packet it receives for import, with
+/// the radio as gateway. The NodeDB of the radio is imported from the configuration dump.
+///
+/// Returns when the connection fails.
+pub async fn run_session<S: AsyncRead + AsyncWrite>(
+    pool: &DbPool,
+    name: &str,
+    stream: S,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    // Wake up the radio, it might be sleeping or in the middle of a frame
+    writer.write_all(&[START2; 32]).await?;
+
+    let config_id = (Utc::now().timestamp() as u32).max(1);
+    let (sender, receiver) = mpsc::channel(16);
+    sender
+        .send(ToRadio {
+            payload_variant: Some(to_radio::PayloadVariant::WantConfigId(config_id)),
+        })
+        .await?;
+
+    let result = tokio::select! {
+        result = write_messages(writer, receiver) => result,
+        result = read_messages(pool, name, BufReader::new(reader), config_id, sender) => result,
+    };
+
+    connected_radios().lock().unwrap().remove(name);
+
+    result
+}
+
+/// Writes the queued messages, and a heartbeat every now and then.
+async fn write_messages<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut receiver: mpsc::Receiver<ToRadio>,
+) -> anyhow::Result<()> {
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // The configuration request is sent first, no need for a heartbeat right away
+    heartbeat.reset();
+
+    loop {
+        let to_radio = tokio::select! {
+            Some(to_radio) = receiver.recv() => to_radio,
+            _ = heartbeat.tick() => ToRadio {
+                payload_variant: Some(to_radio::PayloadVariant::Heartbeat(Heartbeat::default())),
+            },
+        };
+
+        write_to_radio(&mut writer, &to_radio).await?;
+    }
+}
+
+async fn read_messages<R: AsyncRead + Unpin>(
+    pool: &DbPool,
+    name: &str,
+    mut reader: R,
+    config_id: u32,
+    sender: mpsc::Sender<ToRadio>,
+) -> anyhow::Result<()> {
+    let mut node_num: Option<u32> = None;
+    let mut gateway_id: Option<String> = None;
+    // Names and keys of the channels by channel index
+    let mut channel_settings: HashMap<u32, (String, Vec<u8>)> = HashMap::new();
+    let mut modem_preset = ModemPreset::LongFast;
+
+    loop {
+        let from_radio = read_from_radio(&mut reader).await?;
+        let received_at = Utc::now().timestamp_nanos_opt().unwrap();
+
+        match from_radio.payload_variant {
+            Some(from_radio::PayloadVariant::MyInfo(my_info)) => {
+                info!("Connected to radio !{:08x}", my_info.my_node_num);
+                node_num = Some(my_info.my_node_num);
+                gateway_id = Some(format!("!{:08x}", my_info.my_node_num));
+            }
+            Some(from_radio::PayloadVariant::NodeInfo(node_info)) => {
+                import::import_node_info(pool, &node_info, received_at).await?;
+            }
+            Some(from_radio::PayloadVariant::Channel(channel)) => {
+                if let (Some(settings), Ok(channel::Role::Primary | channel::Role::Secondary)) =
+                    (channel.settings, channel::Role::try_from(channel.role))
+                {
+                    channel_settings.insert(channel.index as u32, (settings.name, settings.psk));
+                }
+            }
+            Some(from_radio::PayloadVariant::Config(Config {
+                payload_variant: Some(config::PayloadVariant::Lora(lora)),
+            })) => {
+                modem_preset = ModemPreset::try_from(lora.modem_preset).unwrap_or(modem_preset);
+            }
+            Some(from_radio::PayloadVariant::ConfigCompleteId(id)) if id == config_id => {
+                info!(
+                    "Received configuration and {} channels from the radio",
+                    channel_settings.len()
+                );
+
+                if let Some(node_num) = node_num {
+                    let channels = channel_settings
+                        .iter()
+                        .map(|(index, (channel_name, _))| match channel_name.as_str() {
+                            "" => (*index, preset_channel_name(modem_preset)),
+                            _ => (*index, channel_name.clone()),
+                        })
+                        .sorted()
+                        .collect();
+
+                    connected_radios().lock().unwrap().insert(
+                        name.to_string(),
+                        ConnectedRadio {
+                            node_num,
+                            channels,
+                            sender: sender.clone(),
+                        },
+                    );
+                }
+            }
+            Some(from_radio::PayloadVariant::Packet(mut packet)) => {
+                let Some(gateway_id) = gateway_id.clone() else {
+                    debug!("Skipping packet received before the node info of the radio");
+                    continue;
+                };
+
+                // The radio only sets the receive time when it knows the time
+                if packet.rx_time == 0 {
+                    packet.rx_time = Utc::now().timestamp() as u32;
+                }
+
+                let (channel_id, key) = match channel_settings.get(&packet.channel) {
+                    Some((name, psk)) if !name.is_empty() => (name.clone(), psk.as_slice()),
+                    Some((_, psk)) => (preset_channel_name(modem_preset), psk.as_slice()),
+                    None => (preset_channel_name(modem_preset), [1u8].as_slice()),
+                };
+
+                // The radio reports the index of the channel, packets from MQTT carry the
+                // channel hash instead. An empty key means the channel is not encrypted.
+                packet.channel =
+                    channel_hash(&channel_id, &expand_channel_key(key).unwrap_or_default());
+
+                let service_envelope = ServiceEnvelope {
+                    packet: Some(packet),
+                    channel_id,
+                    gateway_id,
+                };
+
+                import::queue_service_envelope(
+                    &mut pool.acquire().await?.executor(),
+                    &service_envelope.encode_to_vec(),
+                    None,
+                    received_at,
+                )
+                .await?;
+            }
+            other => debug!("Radio message: {:?}", other),
+        }
+    }
+}
+
+/// Channels without a name are named after the modem preset, `LONG_FAST` becomes `LongFast`.
+fn preset_channel_name(modem_preset: ModemPreset) -> String {
+    modem_preset
+        .as_str_name()
+        .split('_')
+        .map(capitalize)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::meshtastic::{
+            from_radio::PayloadVariant as FromRadioVariant, mesh_packet, Channel, ChannelSettings,
+            Data, FromRadio, MyNodeInfo, NodeInfo, PortNum, User,
+        },
+        recent_packets::RecentPackets,
+        util::{connect_to_test_db, db::on_db},
+    };
+    use stream::START1;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    /// Reads the next `ToRadio` frame written by the session, skipping the wake up bytes.
+    async fn read_to_radio<R: AsyncRead + Unpin>(reader: &mut R) -> ToRadio {
+        let mut previous = 0u8;
+        loop {
+            let byte = reader.read_u8().await.unwrap();
+            if previous == START1 && byte == START2 {
+                break;
+            }
+            previous = byte;
+        }
+
+        let mut payload = vec![0u8; reader.read_u16().await.unwrap() as usize];
+        reader.read_exact(&mut payload).await.unwrap();
+        ToRadio::decode(&*payload).unwrap()
+    }
+
+    async fn write_from_radio<W: AsyncWrite + Unpin>(writer: &mut W, variant: FromRadioVariant) {
+        let payload = FromRadio {
+            payload_variant: Some(variant),
+            ..Default::default()
+        }
+        .encode_to_vec();
+
+        writer.write_all(&[START1, START2]).await.unwrap();
+        writer
+            .write_all(&(payload.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        writer.write_all(&payload).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_imports_the_node_db_and_queues_received_packets() {
+        let pool = connect_to_test_db().await;
+        let (radio, client) = duplex(4096);
+        let session = {
+            let pool = pool.clone();
+            tokio::spawn(async move { run_session(&pool, "test-radio", client).await })
+        };
+        let (mut radio_reader, mut radio_writer) = tokio::io::split(radio);
+
+        let Some(to_radio::PayloadVariant::WantConfigId(config_id)) =
+            read_to_radio(&mut radio_reader).await.payload_variant
+        else {
+            panic!("The session did not request the configuration");
+        };
+
+        // The configuration dump, followed by a packet received by the radio
+        for variant in [
+            FromRadioVariant::MyInfo(MyNodeInfo {
+                my_node_num: 0xaabbccdd,
+                ..Default::default()
+            }),
+            FromRadioVariant::NodeInfo(NodeInfo {
+                num: 0x1234abcd,
+                user: Some(User {
+                    id: "!1234abcd".to_string(),
+                    long_name: "Antenna Hill".to_string(),
+                    short_name: "AH".to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            FromRadioVariant::Channel(Channel {
+                index: 0,
+                settings: Some(ChannelSettings {
+                    psk: vec![1],
+                    ..Default::default()
+                }),
+                role: channel::Role::Primary as i32,
+            }),
+            FromRadioVariant::ConfigCompleteId(config_id),
+            FromRadioVariant::Packet(MeshPacket {
+                from: 0x1234abcd,
+                to: 0xffffffff,
+                id: 7,
+                channel: 0,
+                rx_time: 1_750_000_000,
+                payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+                    portnum: PortNum::TextMessageApp as i32,
+                    payload: b"hello".to_vec(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+        ] {
+            write_from_radio(&mut radio_writer, variant).await;
+        }
+
+        let mut queued = 0;
+        for _ in 0..100 {
+            queued = on_db!(pool, |pool| {
+                sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM service_envelopes")
+                    .fetch_one(pool)
+                    .await
+                    .unwrap()
+            });
+            if queued > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(queued, 1);
+
+        let radio = connected_radio("test-radio").unwrap();
+        assert_eq!(radio.node_num, 0xaabbccdd);
+        assert_eq!(radio.channels, [(0, "LongFast".to_string())]);
+
+        import::import_queued(&pool, &mut RecentPackets::new(10))
+            .await
+            .unwrap();
+        session.abort();
+
+        let long_name: Option<String> = on_db!(pool, |pool| {
+            sqlx::query_scalar("SELECT long_name FROM nodes WHERE node_id = $1")
+                .bind(0x1234abcd_i64)
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        });
+        assert_eq!(long_name.as_deref(), Some("Antenna Hill"));
+
+        let (gateway_id, from_id, unique_id, channel_id, payload_data): (
+            String,
+            i64,
+            i64,
+            i64,
+            Vec<u8>,
+        ) = on_db!(pool, |pool| {
+            sqlx::query_as(
+                "SELECT gateway_id, from_id, unique_id, channel_id, payload_data FROM mesh_packets",
+            )
+            .fetch_one(pool)
+            .await
+            .unwrap()
+        });
+        assert_eq!(gateway_id, "!aabbccdd");
+        assert_eq!(from_id, 0x1234abcd);
+        assert_eq!(unique_id, 7);
+        assert_eq!(
+            channel_id,
+            channel_hash("LongFast", &expand_channel_key(&[1]).unwrap()) as i64
+        );
+        assert_eq!(payload_data, b"hello");
+    }
+}
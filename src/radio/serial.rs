@@ -0,0 +1,31 @@
+use crate::util::DbPool;
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio_serial::SerialPortBuilderExt;
+use tracing::{error, info, warn};
+
+const BAUD_RATE: u32 = 115200;
+
+/// Ingests the packets received by a radio connected to a serial port, reopening the port when
+/// the connection is lost.
+pub async fn start_server(pool: DbPool, path: String) -> anyhow::Result<()> {
+    info!("Starting serial ingest from {}", path);
+
+    loop {
+        match tokio_serial::new(&path, BAUD_RATE).open_native_async() {
+            Ok(port) => {
+                info!("Opened serial port {}", path);
+
+                if let Err(err) = super::run_session(&pool, &path, port).await {
+                    error!("Serial connection to {} lost: {:?}", path, err);
+                }
+            }
+            Err(err) => error!("Could not open serial port {}: {}", path, err),
+        }
+
+        // Only retry every 5 seconds
+        sleep(Duration::from_secs(5)).await;
+
+        warn!("Reconnecting to {}", path);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::proto::meshtastic::{FromRadio, ToRadio};
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Every frame of the stream protocol starts with these two bytes, followed by the length of the
+/// protobuf as a big endian u16.
+pub const START1: u8 = 0x94;
+pub const START2: u8 = 0xc3;
+pub const MAX_PAYLOAD_SIZE: usize = 512;
+
+/// Writes a framed `ToRadio` message.
+pub async fn write_to_radio<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    to_radio: &ToRadio,
+) -> anyhow::Result<()> {
+    let payload = to_radio.encode_to_vec();
+
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&[START1, START2]);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&payload);
+
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads the next framed `FromRadio` message. Anything in between the frames, like the debug
+/// log output of the radio, is skipped.
+pub async fn read_from_radio<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<FromRadio> {
+    let mut previous = 0u8;
+
+    loop {
+        let byte = reader.read_u8().await?;
+
+        if previous != START1 || byte != START2 {
+            previous = byte;
+            continue;
+        }
+        previous = 0;
+
+        let length = reader.read_u16().await? as usize;
+        if length > MAX_PAYLOAD_SIZE {
+            debug!("Skipping frame with invalid length {}", length);
+            continue;
+        }
+
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload).await?;
+
+        match FromRadio::decode(&*payload) {
+            Ok(from_radio) => return Ok(from_radio),
+            Err(err) => debug!("Skipping invalid frame: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::meshtastic::from_radio;
+    use tokio::io::duplex;
+
+    fn frame(from_radio: &FromRadio) -> Vec<u8> {
+        let payload = from_radio.encode_to_vec();
+        let mut frame = vec![START1, START2];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn config_complete(id: u32) -> FromRadio {
+        FromRadio {
+            id,
+            payload_variant: Some(from_radio::PayloadVariant::ConfigCompleteId(id)),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads_are_joined() {
+        let bytes = [frame(&config_complete(1)), frame(&config_complete(2))].concat();
+        let (mut writer, mut reader) = duplex(64);
+
+        tokio::spawn(async move {
+            for byte in bytes {
+                writer.write_all(&[byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(1)
+        );
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn bytes_between_frames_are_skipped() {
+        let bytes = [
+            b"INFO | booting\r\n".to_vec(),
+            vec![START1, START1],
+            frame(&config_complete(1)),
+            vec![START2, START1, 0x00],
+            frame(&config_complete(2)),
+        ]
+        .concat();
+        let mut reader = bytes.as_slice();
+
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(1)
+        );
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_with_oversized_lengths_are_skipped() {
+        let bytes = [vec![START1, START2, 0xff, 0xff], frame(&config_complete(1))].concat();
+        let mut reader = bytes.as_slice();
+
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn undecodable_frames_are_skipped() {
+        let bytes = [
+            vec![START1, START2, 0x00, 0x02, 0xff, 0xff],
+            frame(&config_complete(1)),
+        ]
+        .concat();
+        let mut reader = bytes.as_slice();
+
+        assert_eq!(
+            read_from_radio(&mut reader).await.unwrap(),
+            config_complete(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_end_of_the_stream_is_an_error() {
+        let bytes = frame(&config_complete(1));
+        let mut reader = &bytes[..bytes.len() - 1];
+
+        assert!(read_from_radio(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn written_frames_start_with_the_header_and_length() {
+        let mut bytes = Vec::new();
+        write_to_radio(&mut bytes, &ToRadio::default())
+            .await
+            .unwrap();
+        assert_eq!(bytes, [START1, START2, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::util::{config::get_list, DbPool};
+use std::{net::IpAddr, time::Duration};
+use tokio::{net::TcpStream, task::JoinSet, time::sleep};
+use tracing::{error, info, warn};
+
+/// Port of the stream API of nodes with WiFi or ethernet.
+pub const DEFAULT_PORT: u16 = 4403;
+
+/// The `radio_tcp_hosts` to connect to, with the default port added when missing.
+pub fn configured_addresses() -> anyhow::Result<Vec<String>> {
+    let Ok(hosts) = get_list("radio_tcp_hosts") else {
+        return Ok(Vec::new());
+    };
+
+    hosts
+        .into_iter()
+        .map(|host| -> anyhow::Result<String> { Ok(with_default_port(&host.into_string()?)) })
+        .collect()
+}
+
+pub fn with_default_port(host: &str) -> String {
+    match host.trim().parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, DEFAULT_PORT),
+        Ok(IpAddr::V4(ip)) => format!("{}:{}", ip, DEFAULT_PORT),
+        Err(_) if host.contains(':') => host.trim().to_string(),
+        Err(_) => format!("{}:{}", host.trim(), DEFAULT_PORT),
+    }
+}
+
+/// Ingests the packets received by the nodes at `addresses`, every node has its own connection.
+pub async fn start_server(pool: DbPool, addresses: Vec<String>) -> anyhow::Result<()> {
+    info!("Starting TCP ingest from {}", addresses.join(", "));
+
+    let mut connections = JoinSet::new();
+    for address in addresses {
+        connections.spawn(connect(pool.clone(), address));
+    }
+
+    while let Some(result) = connections.join_next().await {
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Keeps a connection open to a node, reconnecting when it is lost.
+async fn connect(pool: DbPool, address: String) {
+    loop {
+        match TcpStream::connect(&address).await {
+            Ok(stream) => {
+                info!("Connected to {}", address);
+                let _ = stream.set_nodelay(true);
+
+                if let Err(err) = super::run_session(&pool, &address, stream).await {
+                    error!("Connection to {} lost: {:?}", address, err);
+                }
+            }
+            Err(err) => error!("Could not connect to {}: {}", address, err),
+        }
+
+        // Only retry every 5 seconds
+        sleep(Duration::from_secs(5)).await;
+
+        warn!("Reconnecting to {}", address);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    import,
+    proto::meshtastic::{MeshPacket, ServiceEnvelope},
+    util::{
+        config::{get_config, get_list},
+        crypto, parse_node_id, DbPool,
+    },
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use prost::Message;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+};
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+/// Multicast group and port the firmware sends the mesh packets to.
+pub const DEFAULT_MULTICAST_ADDR: &str = "224.0.0.69:4403";
+
+/// Packets from a node that has not been identified yet are kept until it is.
+const MAX_PENDING_PACKETS: usize = 100;
+
+/// The `udp_gateways` setting, a list of `address=node_id` pairs for nodes that do not identify
+/// themselves quickly enough.
+fn configured_gateways() -> anyhow::Result<HashMap<IpAddr, u32>> {
+    let Ok(gateways) = get_list("udp_gateways") else {
+        return Ok(HashMap::new());
+    };
+
+    gateways
+        .into_iter()
+        .map(|gateway| -> anyhow::Result<(IpAddr, u32)> {
+            let gateway = gateway.into_string()?;
+            let (address, node_id) = gateway
+                .split_once('=')
+                .ok_or(anyhow!("Invalid udp_gateways entry: {}", gateway))?;
+
+            Ok((
+                address.trim().parse()?,
+                parse_node_id(node_id.trim())
+                    .ok_or(anyhow!("Invalid udp_gateways node id: {}", node_id))?,
+            ))
+        })
+        .collect()
+}
+
+/// Packets sent by a node itself have not been received over the air yet.
+fn is_sent_by_gateway(packet: &MeshPacket) -> bool {
+    packet.hop_start != 0
+        && packet.hop_start == packet.hop_limit
+        && packet.rx_snr == 0.0
+        && packet.rx_rssi == 0
+}
+
+fn bind_multicast(addr: SocketAddrV4) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+    // Other applications on this host might listen for the packets as well
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, addr.port()).into())?;
+    socket.join_multicast_v4(addr.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Ingests the mesh packets nodes on the LAN send to the multicast group. The node that sent a
+/// packet is its gateway, nodes are identified by the first packet they send themselves or by the
+/// `udp_gateways` setting.
+pub async fn start_server(pool: DbPool) -> anyhow::Result<()> {
+    let addr: SocketAddrV4 = get_config()
+        .get_string("udp_multicast_addr")
+        .unwrap_or_else(|_| DEFAULT_MULTICAST_ADDR.to_string())
+        .parse()?;
+
+    let mut gateways = Gateways {
+        nodes: configured_gateways()?,
+        pending: HashMap::new(),
+    };
+
+    let socket = bind_multicast(addr)?;
+    info!("Listening for mesh packets on {}", addr);
+
+    // Mesh packets are limited to 256 bytes on air, their protobuf is a bit larger
+    let mut buf = [0u8; 1024];
+
+    loop {
+        let (length, sender) = socket.recv_from(&mut buf).await?;
+        let received_at = Utc::now().timestamp_nanos_opt().unwrap();
+
+        for (gateway, packet, received_at) in
+            gateways.receive(sender.ip(), &buf[..length], received_at)
+        {
+            queue_packet(&pool, gateway, packet, received_at).await?;
+        }
+    }
+}
+
+/// The nodes sending packets to the multicast group, by address.
+struct Gateways {
+    nodes: HashMap<IpAddr, u32>,
+    /// Packets from nodes that have not been identified yet, with their receive time.
+    pending: HashMap<IpAddr, Vec<(MeshPacket, i64)>>,
+}
+
+impl Gateways {
+    /// Decodes a datagram and returns the packets that can be queued with their gateway. Once the
+    /// sender is known, the packets kept until then come first.
+    fn receive(
+        &mut self,
+        address: IpAddr,
+        datagram: &[u8],
+        received_at: i64,
+    ) -> Vec<(u32, MeshPacket, i64)> {
+        let mut packet = match MeshPacket::decode(datagram) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Skipping invalid packet from {}: {}", address, err);
+                return Vec::new();
+            }
+        };
+
+        if !self.nodes.contains_key(&address) && is_sent_by_gateway(&packet) {
+            info!("Identified {} as !{:08x}", address, packet.from);
+            self.nodes.insert(address, packet.from);
+        }
+
+        if packet.rx_time == 0 {
+            packet.rx_time = (received_at / 1_000_000_000) as u32;
+        }
+
+        match self.nodes.get(&address) {
+            Some(&gateway) => self
+                .pending
+                .remove(&address)
+                .unwrap_or_default()
+                .into_iter()
+                .chain([(packet, received_at)])
+                .map(|(packet, received_at)| (gateway, packet, received_at))
+                .collect(),
+            None => {
+                let packets = self.pending.entry(address).or_default();
+
+                if packets.len() < MAX_PENDING_PACKETS {
+                    packets.push((packet, received_at));
+                } else {
+                    debug!("Dropping packet from unidentified node {}", address);
+                }
+
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// The packets are still encrypted, the importer decrypts them with the configured channels.
+async fn queue_packet(
+    pool: &DbPool,
+    gateway: u32,
+    packet: MeshPacket,
+    received_at: i64,
+) -> anyhow::Result<()> {
+    let channel_id = crypto::configured_channels()
+        .iter()
+        .find(|channel| channel.hash == packet.channel)
+        .map(|channel| channel.name.clone())
+        .unwrap_or_else(|| packet.channel.to_string());
+
+    let service_envelope = ServiceEnvelope {
+        packet: Some(packet),
+        channel_id,
+        gateway_id: format!("!{:08x}", gateway),
+    };
+
+    import::queue_service_envelope(
+        &mut pool.acquire().await?.executor(),
+        &service_envelope.encode_to_vec(),
+        None,
+        received_at,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::meshtastic::{mesh_packet::PayloadVariant, Data, PortNum},
+        recent_packets::RecentPackets,
+        util::{connect_to_test_db, db::on_db},
+    };
+
+    const GATEWAY: u32 = 0xaabbccdd;
+    const RECEIVED_AT: i64 = 1_750_000_000_000_000_000;
+
+    fn address() -> IpAddr {
+        "192.168.1.20".parse().unwrap()
+    }
+
+    fn gateways() -> Gateways {
+        Gateways {
+            nodes: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// A packet relayed by the gateway, received over the air.
+    fn relayed(id: u32) -> MeshPacket {
+        MeshPacket {
+            from: 0x1234abcd,
+            id,
+            rx_time: 1_750_000_000,
+            rx_snr: 6.25,
+            rx_rssi: -90,
+            hop_start: 3,
+            hop_limit: 2,
+            ..Default::default()
+        }
+    }
+
+    /// A packet sent by the gateway itself.
+    fn own(id: u32) -> MeshPacket {
+        MeshPacket {
+            from: GATEWAY,
+            id,
+            rx_time: 1_750_000_000,
+            hop_start: 3,
+            hop_limit: 3,
+            ..Default::default()
+        }
+    }
+
+    fn ids(packets: &[(u32, MeshPacket, i64)]) -> Vec<(u32, u32)> {
+        packets
+            .iter()
+            .map(|(gateway, packet, _)| (*gateway, packet.id))
+            .collect()
+    }
+
+    #[test]
+    fn invalid_datagrams_are_skipped() {
+        let mut gateways = gateways();
+
+        assert!(gateways
+            .receive(address(), &[0xff, 0xff, 0xff], RECEIVED_AT)
+            .is_empty());
+        assert!(gateways.pending.is_empty());
+    }
+
+    #[test]
+    fn packets_are_kept_until_the_sender_identifies_itself() {
+        let mut gateways = gateways();
+
+        let packets = gateways.receive(address(), &relayed(1).encode_to_vec(), RECEIVED_AT);
+        assert!(packets.is_empty());
+
+        let packets = gateways.receive(address(), &own(2).encode_to_vec(), RECEIVED_AT);
+        assert_eq!(ids(&packets), [(GATEWAY, 1), (GATEWAY, 2)]);
+
+        let packets = gateways.receive(address(), &relayed(3).encode_to_vec(), RECEIVED_AT);
+        assert_eq!(ids(&packets), [(GATEWAY, 3)]);
+    }
+
+    #[test]
+    fn configured_gateways_tag_packets_right_away() {
+        let mut gateways = gateways();
+        gateways.nodes.insert(address(), GATEWAY);
+
+        let packets = gateways.receive(address(), &relayed(1).encode_to_vec(), RECEIVED_AT);
+        assert_eq!(ids(&packets), [(GATEWAY, 1)]);
+
+        // Another node on the LAN is not tagged with the gateway
+        let other = "192.168.1.21".parse().unwrap();
+        assert!(gateways
+            .receive(other, &relayed(2).encode_to_vec(), RECEIVED_AT)
+            .is_empty());
+    }
+
+    #[test]
+    fn pending_packets_are_capped() {
+        let mut gateways = gateways();
+
+        for id in 0..MAX_PENDING_PACKETS as u32 + 5 {
+            gateways.receive(address(), &relayed(id).encode_to_vec(), RECEIVED_AT);
+        }
+
+        assert_eq!(gateways.pending[&address()].len(), MAX_PENDING_PACKETS);
+    }
+
+    #[test]
+    fn missing_receive_times_are_set() {
+        let mut gateways = gateways();
+        gateways.nodes.insert(address(), GATEWAY);
+        let packet = MeshPacket {
+            rx_time: 0,
+            ..relayed(1)
+        };
+
+        let packets = gateways.receive(address(), &packet.encode_to_vec(), RECEIVED_AT);
+        assert_eq!(packets[0].1.rx_time, 1_750_000_000);
+    }
+
+    #[tokio::test]
+    async fn encrypted_packets_are_queued_for_decryption() {
+        let pool = connect_to_test_db().await;
+        let key = crypto::channel_key(crypto::DEFAULT_KEY).unwrap();
+        let data = Data {
+            portnum: PortNum::TextMessageApp as i32,
+            payload: b"hello".to_vec(),
+            ..Default::default()
+        };
+        let packet = MeshPacket {
+            channel: crypto::channel_hash("LongFast", &key),
+            payload_variant: Some(PayloadVariant::Encrypted(
+                crypto::apply_channel_cipher(&key, 1, 0x1234abcd, &data.encode_to_vec()).unwrap(),
+            )),
+            ..relayed(1)
+        };
+
+        queue_packet(&pool, GATEWAY, packet, RECEIVED_AT)
+            .await
+            .unwrap();
+        import::import_queued(&pool, &mut RecentPackets::new(10))
+            .await
+            .unwrap();
+
+        let (gateway_id, portnum, payload_data): (String, i64, Vec<u8>) = on_db!(pool, |pool| {
+            sqlx::query_as("SELECT gateway_id, portnum, payload_data FROM mesh_packets")
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        });
+        assert_eq!(gateway_id, "!aabbccdd");
+        assert_eq!(portnum, PortNum::TextMessageApp as i64);
+        assert_eq!(payload_data, b"hello");
+    }
+}
@@ -0,0 +1,404 @@
+//! In-memory state of the importer, so most duplicates are detected without querying the
+//! database. Only the single writer of the import server uses it.
+
+use crate::{
+    proto::meshtastic::{Data, MeshPacket},
+    util::db::{on_db, DbPool},
+};
+use anyhow::anyhow;
+use lru::LruCache;
+use std::{collections::HashMap, num::NonZeroUsize};
+
+/// Repeats of a packet are only detected when they are received within an hour.
+pub(crate) const REPEAT_WINDOW_SECONDS: i64 = 3600;
+
+/// The id, unique_id, payload_data, hash, rx_time and duplicate_of_mesh_packet_id of a packet.
+type LoadedPacket = (i64, i64, Vec<u8>, Vec<u8>, i64, Option<i64>);
+
+/// A change written in the current transaction.
+enum Change {
+    Packet {
+        hash: Vec<u8>,
+        key: (u32, [u8; 32]),
+        first: Option<(i64, u32)>,
+        rx_time: u32,
+    },
+    Node {
+        node_id: u32,
+        hidden: bool,
+    },
+}
+
+/// Packets and nodes are recorded as pending, and only become part of the cache once the
+/// transaction that wrote them is committed. Lookups see the pending changes as well.
+///
+/// The cache decides for packets received well after `uncached_until`, older packets may be
+/// duplicates or repeats of packets that are only found in the database.
+pub struct RecentPackets {
+    /// Hashes of the envelopes of recently imported packets, with their receive time.
+    hashes: LruCache<Vec<u8>, u32>,
+    /// The first mesh packet and its receive time by packet id and payload hash.
+    packets: LruCache<(u32, [u8; 32]), (i64, u32)>,
+    /// The latest receive time of the imported packets that are not cached, because they were
+    /// evicted or imported before the cache was loaded.
+    uncached_until: Option<u32>,
+    /// Known nodes and whether they are hidden by the ingest rules.
+    nodes: HashMap<u32, bool>,
+    /// Changes of the transaction being written, in the order they were made.
+    pending: Vec<Change>,
+}
+
+impl RecentPackets {
+    pub fn new(capacity: usize) -> RecentPackets {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+
+        RecentPackets {
+            hashes: LruCache::new(capacity),
+            packets: LruCache::new(capacity),
+            uncached_until: None,
+            nodes: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Loads the last `capacity` packets and all nodes, so duplicates are detected right after a
+    /// restart as well. Queued envelopes received before them, e.g. from a merged database, are
+    /// matched with the packets in the database, see [`RecentPackets::may_miss`].
+    pub async fn load(pool: &DbPool, capacity: usize) -> anyhow::Result<RecentPackets> {
+        let mut recent_packets = RecentPackets::new(capacity);
+
+        let limit = recent_packets.hashes.cap().get() as i64;
+        let packets: Vec<LoadedPacket> = on_db!(pool, |pool| {
+            sqlx::query_as(
+                "SELECT id, unique_id, payload_data, hash, rx_time, duplicate_of_mesh_packet_id FROM mesh_packets WHERE unique_id != 0 ORDER BY id DESC LIMIT $1",
+            )
+            .bind(limit)
+            .fetch_all(pool)
+            .await
+        })?;
+
+        // Packets are not always imported in the order they were received, e.g. from a merged
+        // database, so the older packets can have been received later than the loaded ones
+        if let Some((oldest_id, ..)) = packets.last() {
+            let uncached_until: Option<i64> = on_db!(pool, |pool| {
+                sqlx::query_scalar(
+                    "SELECT MAX(rx_time) FROM mesh_packets WHERE unique_id != 0 AND id < $1",
+                )
+                .bind(oldest_id)
+                .fetch_one(pool)
+                .await
+            })?;
+            recent_packets.uncached_until =
+                uncached_until.map(|rx_time| (rx_time / 1_000_000_000) as u32);
+        }
+
+        for (id, unique_id, payload_data, hash, rx_time, duplicate_of_mesh_packet_id) in
+            packets.into_iter().rev()
+        {
+            recent_packets.put_loaded(
+                id,
+                unique_id,
+                payload_data,
+                hash,
+                rx_time,
+                duplicate_of_mesh_packet_id,
+            );
+        }
+
+        let nodes: Vec<(i64, i64)> = on_db!(pool, |pool| {
+            sqlx::query_as("SELECT node_id, hidden FROM nodes")
+                .fetch_all(pool)
+                .await
+        })?;
+        for (node_id, hidden) in nodes {
+            recent_packets.nodes.insert(node_id as u32, hidden != 0);
+        }
+
+        Ok(recent_packets)
+    }
+
+    fn put_loaded(
+        &mut self,
+        id: i64,
+        unique_id: i64,
+        payload_data: Vec<u8>,
+        hash: Vec<u8>,
+        rx_time: i64,
+        duplicate_of_mesh_packet_id: Option<i64>,
+    ) {
+        let rx_time = (rx_time / 1_000_000_000) as u32;
+        self.put_hash(hash, rx_time);
+
+        if duplicate_of_mesh_packet_id.is_none() {
+            let key = (unique_id as u32, *blake3::hash(&payload_data).as_bytes());
+            self.put_packet(key, (id, rx_time));
+        }
+    }
+
+    fn put_hash(&mut self, hash: Vec<u8>, rx_time: u32) {
+        if let Some((evicted, evicted_rx_time)) = self.hashes.push(hash.clone(), rx_time)
+            && evicted != hash
+        {
+            self.uncache(evicted_rx_time);
+        }
+    }
+
+    fn put_packet(&mut self, key: (u32, [u8; 32]), first: (i64, u32)) {
+        if let Some((evicted, (_, evicted_rx_time))) = self.packets.push(key, first)
+            && evicted != key
+        {
+            self.uncache(evicted_rx_time);
+        }
+    }
+
+    fn uncache(&mut self, rx_time: u32) {
+        self.uncached_until = self.uncached_until.max(Some(rx_time));
+    }
+
+    /// Whether the packets a packet could be a duplicate or a repeat of may not be cached, so
+    /// [`RecentPackets::repeat_of`] cannot rule them out and the database has to be checked.
+    pub fn may_miss(&self, packet: &MeshPacket) -> bool {
+        packet.id != 0
+            && self.uncached_until.is_some_and(|uncached_until| {
+                packet.rx_time as i64 - REPEAT_WINDOW_SECONDS <= uncached_until as i64
+            })
+    }
+
+    /// Fails for cached packets that were imported before. Returns the id of the first mesh
+    /// packet when the packet is a repeat of a cached one, received through another gateway.
+    pub fn repeat_of(
+        &mut self,
+        packet: &MeshPacket,
+        data: &Data,
+        raw_message_hash: &[u8],
+    ) -> anyhow::Result<Option<i64>> {
+        // Packets without an id cannot be told apart
+        if packet.id == 0 {
+            return Ok(None);
+        }
+
+        let is_pending = self.pending.iter().any(
+            |change| matches!(change, Change::Packet { hash, .. } if hash == raw_message_hash),
+        );
+        if is_pending || self.hashes.contains(raw_message_hash) {
+            return Err(anyhow!("Duplicate mesh packet: {}", packet.id));
+        }
+
+        let key = (packet.id, *blake3::hash(&data.payload).as_bytes());
+        let first = self
+            .pending
+            .iter()
+            .rev()
+            .find_map(|change| match change {
+                Change::Packet {
+                    key: pending_key,
+                    first: Some(first),
+                    ..
+                } if *pending_key == key => Some(*first),
+                _ => None,
+            })
+            .or_else(|| self.packets.get(&key).copied());
+
+        Ok(first.and_then(|(id, rx_time)| {
+            ((packet.rx_time as i64 - rx_time as i64).abs() <= REPEAT_WINDOW_SECONDS).then_some(id)
+        }))
+    }
+
+    /// Records an imported packet, pending until [`RecentPackets::commit`].
+    pub fn insert(
+        &mut self,
+        packet: &MeshPacket,
+        data: &Data,
+        raw_message_hash: &[u8],
+        mesh_packet_id: i64,
+        repeat_of: Option<i64>,
+    ) {
+        if packet.id == 0 {
+            return;
+        }
+
+        self.pending.push(Change::Packet {
+            hash: raw_message_hash.to_vec(),
+            key: (packet.id, *blake3::hash(&data.payload).as_bytes()),
+            first: repeat_of
+                .is_none()
+                .then_some((mesh_packet_id, packet.rx_time)),
+            rx_time: packet.rx_time,
+        });
+    }
+
+    /// Returns whether a node is hidden, or `None` for nodes that are not cached.
+    pub fn node_hidden(&self, node_id: u32) -> Option<bool> {
+        self.pending
+            .iter()
+            .rev()
+            .find_map(|change| match change {
+                Change::Node {
+                    node_id: pending_node_id,
+                    hidden,
+                } if *pending_node_id == node_id => Some(*hidden),
+                _ => None,
+            })
+            .or_else(|| self.nodes.get(&node_id).copied())
+    }
+
+    /// Records the visibility of a node, pending until [`RecentPackets::commit`].
+    pub fn insert_node(&mut self, node_id: u32, hidden: bool) {
+        self.pending.push(Change::Node { node_id, hidden });
+    }
+
+    /// Marks the changes recorded so far, to roll back the changes made after it.
+    pub fn savepoint(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the changes recorded after `savepoint`, when the savepoint was rolled back.
+    pub fn rollback_to(&mut self, savepoint: usize) {
+        self.pending.truncate(savepoint);
+    }
+
+    /// Applies the pending changes, after the transaction that wrote them was committed.
+    pub fn commit(&mut self) {
+        for change in std::mem::take(&mut self.pending) {
+            match change {
+                Change::Packet {
+                    hash,
+                    key,
+                    first,
+                    rx_time,
+                } => {
+                    self.put_hash(hash, rx_time);
+
+                    if let Some(first) = first {
+                        self.put_packet(key, first);
+                    }
+                }
+                Change::Node { node_id, hidden } => {
+                    self.nodes.insert(node_id, hidden);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id: u32, rx_time: u32, payload: &[u8]) -> (MeshPacket, Data) {
+        let packet = MeshPacket {
+            id,
+            rx_time,
+            ..Default::default()
+        };
+        let data = Data {
+            payload: payload.to_vec(),
+            ..Default::default()
+        };
+
+        (packet, data)
+    }
+
+    #[test]
+    fn exact_duplicates_are_rejected() {
+        let mut recent_packets = RecentPackets::new(10);
+        let (packet, data) = packet(1, 1000, b"hello");
+
+        assert_eq!(
+            recent_packets.repeat_of(&packet, &data, b"a").unwrap(),
+            None
+        );
+        recent_packets.insert(&packet, &data, b"a", 1, None);
+        assert!(recent_packets.repeat_of(&packet, &data, b"a").is_err());
+
+        recent_packets.commit();
+        assert!(recent_packets.repeat_of(&packet, &data, b"a").is_err());
+    }
+
+    #[test]
+    fn repeats_within_the_window_refer_to_the_first_packet() {
+        let mut recent_packets = RecentPackets::new(10);
+        let (first, data) = packet(1, 1000, b"hello");
+        recent_packets.insert(&first, &data, b"a", 7, None);
+
+        let (repeat, _) = packet(1, 1000 + REPEAT_WINDOW_SECONDS as u32, b"hello");
+        assert_eq!(
+            recent_packets.repeat_of(&repeat, &data, b"b").unwrap(),
+            Some(7)
+        );
+
+        recent_packets.commit();
+        assert_eq!(
+            recent_packets.repeat_of(&repeat, &data, b"b").unwrap(),
+            Some(7)
+        );
+
+        let (late, _) = packet(1, 1001 + REPEAT_WINDOW_SECONDS as u32, b"hello");
+        assert_eq!(recent_packets.repeat_of(&late, &data, b"c").unwrap(), None);
+
+        let (other, other_data) = packet(1, 1000, b"other");
+        assert_eq!(
+            recent_packets.repeat_of(&other, &other_data, b"d").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn packets_without_an_id_are_never_duplicates() {
+        let mut recent_packets = RecentPackets::new(10);
+        let (packet, data) = packet(0, 1000, b"hello");
+
+        recent_packets.insert(&packet, &data, b"a", 1, None);
+        recent_packets.commit();
+        assert_eq!(
+            recent_packets.repeat_of(&packet, &data, b"a").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn evicted_packets_are_looked_up() {
+        let mut recent_packets = RecentPackets::new(1);
+        let (first, data) = packet(1, 1000, b"hello");
+        let (second, _) = packet(2, 100_000, b"hello");
+        assert!(!recent_packets.may_miss(&first));
+
+        recent_packets.insert(&first, &data, b"a", 1, None);
+        recent_packets.insert(&second, &data, b"b", 2, None);
+        recent_packets.commit();
+
+        let (repeat, _) = packet(1, 1010, b"hello");
+        assert!(recent_packets.may_miss(&repeat));
+        assert_eq!(
+            recent_packets.repeat_of(&repeat, &data, b"c").unwrap(),
+            None
+        );
+
+        let (later, _) = packet(3, 1001 + REPEAT_WINDOW_SECONDS as u32, b"hello");
+        assert!(!recent_packets.may_miss(&later));
+
+        let (without_id, _) = packet(0, 1000, b"hello");
+        assert!(!recent_packets.may_miss(&without_id));
+    }
+
+    #[test]
+    fn rolled_back_changes_are_dropped() {
+        let mut recent_packets = RecentPackets::new(10);
+        let (packet, data) = packet(1, 1000, b"hello");
+        recent_packets.insert_node(1, false);
+
+        let savepoint = recent_packets.savepoint();
+        recent_packets.insert(&packet, &data, b"a", 1, None);
+        recent_packets.insert_node(1, true);
+        assert_eq!(recent_packets.node_hidden(1), Some(true));
+
+        recent_packets.rollback_to(savepoint);
+        recent_packets.commit();
+        assert_eq!(
+            recent_packets.repeat_of(&packet, &data, b"a").unwrap(),
+            None
+        );
+        assert_eq!(recent_packets.node_hidden(1), Some(false));
+        assert_eq!(recent_packets.node_hidden(2), None);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{
+    proto::meshtastic::PortNum,
+    util::{
+        config::get_config,
+        db::{on_db, Db, DbPool},
+        none_if_default,
+    },
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+const NANOS_PER_DAY: i64 = 24 * 3600 * 1_000_000_000;
+/// Writers waiting for the lock get a turn between the batches.
+pub(crate) const BATCH_PAUSE: Duration = Duration::from_millis(100);
+/// Pages freed per incremental vacuum step.
+const VACUUM_PAGES: i64 = 1000;
+
+/// Data is kept for the configured number of days, or forever for 0 days.
+#[derive(Deserialize)]
+struct RetentionConfig {
+    /// Days processed service envelopes are kept.
+    #[serde(default = "default_envelopes_days")]
+    envelopes_days: u64,
+    /// Days mesh packets are kept, except for telemetry.
+    #[serde(default = "default_packets_days")]
+    packets_days: u64,
+    /// Days telemetry packets are kept.
+    #[serde(default)]
+    telemetry_days: u64,
+    #[serde(default = "default_batch_size")]
+    batch_size: i64,
+    #[serde(default = "default_interval_minutes")]
+    interval_minutes: u64,
+}
+
+fn default_envelopes_days() -> u64 {
+    30
+}
+
+fn default_packets_days() -> u64 {
+    180
+}
+
+fn default_batch_size() -> i64 {
+    1000
+}
+
+fn default_interval_minutes() -> u64 {
+    60
+}
+
+/// Prunes the database according to the `retention` table. Returns immediately when it is not
+/// configured.
+pub async fn start_pruning(pool: DbPool) -> anyhow::Result<()> {
+    let config: RetentionConfig = match get_config().get("retention") {
+        Ok(config) => config,
+        Err(config::ConfigError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(anyhow!(err)),
+    };
+
+    // PostgreSQL reuses the space of deleted rows with its autovacuum
+    let incremental = match &pool {
+        Db::Sqlite(pool) => {
+            let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+                .fetch_one(pool)
+                .await?;
+            // 2 is incremental, the mode can only be changed by a full vacuum
+            if auto_vacuum != 2 {
+                warn!("Run `meshstellar vacuum` once to return pruned space to the file system");
+            }
+            auto_vacuum == 2
+        }
+        Db::Postgres(_) => false,
+    };
+
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.interval_minutes.max(1) * 60));
+
+    loop {
+        interval.tick().await;
+
+        let deleted = prune(&pool, &config).await?;
+        if deleted > 0 && incremental {
+            incremental_vacuum(pool.sqlite()?).await?;
+        }
+    }
+}
+
+fn cutoff(days: u64) -> i64 {
+    Utc::now().timestamp_nanos_opt().unwrap() - days as i64 * NANOS_PER_DAY
+}
+
+/// Returns the number of deleted rows.
+async fn prune(pool: &DbPool, config: &RetentionConfig) -> anyhow::Result<u64> {
+    let telemetry_app = PortNum::TelemetryApp as i64;
+    let batch_size = config.batch_size.max(1);
+    let mut deleted = 0;
+
+    if let Some(days) = none_if_default(config.envelopes_days) {
+        let before = cutoff(days);
+        let count = delete_in_batches(batch_size, || async move {
+            on_db!(pool, |pool| {
+                sqlx::query(
+                    "DELETE FROM service_envelopes WHERE id IN (SELECT id FROM service_envelopes WHERE created_at < $1 AND processed_at IS NOT NULL LIMIT $2)",
+                )
+                    .bind(before)
+                    .bind(batch_size)
+                    .execute(pool)
+                    .await
+                    .map(|result| result.rows_affected())
+            })
+        })
+        .await?;
+
+        info!(
+            "Pruned {} service envelopes older than {} days",
+            count, days
+        );
+        deleted += count;
+    }
+
+    // Positions, telemetry and the other payloads are deleted with their packets, the references
+    // of the nodes to them are cleared
+    if let Some(days) = none_if_default(config.packets_days) {
+        let before = cutoff(days);
+        let count = delete_in_batches(batch_size, || async move {
+            on_db!(pool, |pool| {
+                sqlx::query(
+                    "DELETE FROM mesh_packets WHERE id IN (SELECT id FROM mesh_packets WHERE created_at < $1 AND portnum != $2 LIMIT $3)",
+                )
+                    .bind(before)
+                    .bind(telemetry_app)
+                    .bind(batch_size)
+                    .execute(pool)
+                    .await
+                    .map(|result| result.rows_affected())
+            })
+        })
+        .await?;
+
+        let probes = delete_in_batches(batch_size, || async move {
+            on_db!(pool, |pool| {
+                sqlx::query(
+                    "DELETE FROM traceroute_probes WHERE id IN (SELECT id FROM traceroute_probes WHERE created_at < $1 LIMIT $2)",
+                )
+                    .bind(before)
+                    .bind(batch_size)
+                    .execute(pool)
+                    .await
+                    .map(|result| result.rows_affected())
+            })
+        })
+        .await?;
+
+        // The links are kept while their neighbors are reported
+        let links = delete_in_batches(batch_size, || async move {
+            on_db!(pool, |pool| {
+                sqlx::query(
+                    "DELETE FROM neighbor_links WHERE (node_id, neighbor_node_id) IN (SELECT node_id, neighbor_node_id FROM neighbor_links WHERE last_seen < $1 LIMIT $2)",
+                )
+                    .bind(before)
+                    .bind(batch_size)
+                    .execute(pool)
+                    .await
+                    .map(|result| result.rows_affected())
+            })
+        })
+        .await?;
+
+        info!("Pruned {} packets older than {} days", count, days);
+        deleted += count + probes + links;
+    }
+
+    if let Some(days) = none_if_default(config.telemetry_days) {
+        let before = cutoff(days);
+        let count = delete_in_batches(batch_size, || async move {
+            on_db!(pool, |pool| {
+                sqlx::query(
+                    "DELETE FROM mesh_packets WHERE id IN (SELECT id FROM mesh_packets WHERE created_at < $1 AND portnum = $2 LIMIT $3)",
+                )
+                    .bind(before)
+                    .bind(telemetry_app)
+                    .bind(batch_size)
+                    .execute(pool)
+                    .await
+                    .map(|result| result.rows_affected())
+            })
+        })
+        .await?;
+
+        info!(
+            "Pruned {} telemetry packets older than {} days",
+            count, days
+        );
+        deleted += count;
+    }
+
+    Ok(deleted)
+}
+
+async fn delete_in_batches<F, Fut>(batch_size: i64, mut delete: F) -> anyhow::Result<u64>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = sqlx::Result<u64>>,
+{
+    let mut deleted = 0;
+
+    loop {
+        let count = delete().await?;
+        deleted += count;
+
+        if count < batch_size as u64 {
+            return Ok(deleted);
+        }
+
+        sleep(BATCH_PAUSE).await;
+    }
+}
+
+/// Returns the free pages to the file system a few at a time, so the database is not locked for
+/// long.
+async fn incremental_vacuum(pool: &SqlitePool) -> anyhow::Result<()> {
+    loop {
+        let free_pages: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+            .fetch_one(pool)
+            .await?;
+        if free_pages == 0 {
+            return Ok(());
+        }
+
+        sqlx::query(&format!("PRAGMA incremental_vacuum({})", VACUUM_PAGES))
+            .execute(pool)
+            .await?;
+        sleep(BATCH_PAUSE).await;
+    }
+}
+
+/// Switches the database to incremental vacuum and rebuilds it. This locks the database and needs
+/// free disk space for a copy of it, so it is run on request only.
+pub async fn vacuum(pool: &SqlitePool) -> anyhow::Result<()> {
+    let mut connection = pool.acquire().await?;
+
+    sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("VACUUM").execute(&mut *connection).await?;
+
+    Ok(())
+}
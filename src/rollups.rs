@@ -0,0 +1,268 @@
+//! Hourly and daily min/avg/max of the telemetry and reception metrics, so long-term plots do not
+//! need the raw rows. The importer updates them with every packet.
+
+use crate::{
+    proto::meshtastic::{DeviceMetrics, EnvironmentMetrics, PowerMetrics},
+    util::db::{on_db, DbConnection},
+};
+
+const NANOS_PER_HOUR: i64 = 3600 * 1_000_000_000;
+const NANOS_PER_DAY: i64 = 24 * NANOS_PER_HOUR;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Resolution {
+    /// Picks the resolution giving a few hundred points at most for a window starting at
+    /// `min_time` (nanoseconds), where 0 means all data.
+    pub fn for_window(min_time: i64, now: i64) -> Resolution {
+        match now - min_time {
+            window if window <= 6 * NANOS_PER_HOUR => Resolution::Raw,
+            window if window <= 14 * NANOS_PER_DAY => Resolution::Hourly,
+            _ => Resolution::Daily,
+        }
+    }
+
+    /// The length of a bucket in nanoseconds.
+    pub fn bucket_nanos(self) -> i64 {
+        match self {
+            Resolution::Raw => 0,
+            Resolution::Hourly => NANOS_PER_HOUR,
+            Resolution::Daily => NANOS_PER_DAY,
+        }
+    }
+}
+
+/// Adds a value to the hourly and daily buckets of a metric. Reception metrics are kept per
+/// gateway, telemetry uses an empty `gateway_id`.
+pub async fn record(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    gateway_id: &str,
+    metric: &str,
+    time: i64,
+    value: f64,
+) -> anyhow::Result<()> {
+    let hour = time - time % NANOS_PER_HOUR;
+    let day = time - time % NANOS_PER_DAY;
+    for (table, bucket) in [
+        ("telemetry_rollups_hourly", hour),
+        ("telemetry_rollups_daily", day),
+    ] {
+        let query = format!(
+            "INSERT INTO {table} (node_id, gateway_id, metric, bucket, min_value, max_value, sum_value, count)
+             VALUES ($1, $2, $3, $4, $5, $5, $5, 1)
+             ON CONFLICT (node_id, metric, gateway_id, bucket) DO UPDATE SET
+                min_value = CASE WHEN excluded.min_value < {table}.min_value THEN excluded.min_value ELSE {table}.min_value END,
+                max_value = CASE WHEN excluded.max_value > {table}.max_value THEN excluded.max_value ELSE {table}.max_value END,
+                sum_value = {table}.sum_value + excluded.sum_value,
+                count = {table}.count + 1"
+        );
+        on_db!(conn, |conn| {
+            sqlx::query(&query)
+                .bind(i64::from(node_id))
+                .bind(gateway_id)
+                .bind(metric)
+                .bind(bucket)
+                .bind(value)
+                .execute(conn)
+                .await
+                .map(|_| ())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// A metric, its value and the filter deciding whether the value is recorded.
+type MetricValue<'a> = (&'a str, Option<f64>, fn(f64) -> bool);
+
+/// Records the values that pass the filter, the filters match the ones of the raw plots so unset
+/// values are skipped.
+async fn record_all(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    gateway_id: &str,
+    time: i64,
+    values: &[MetricValue<'_>],
+) -> anyhow::Result<()> {
+    for (metric, value, keep) in values {
+        if let Some(value) = value.filter(|value| keep(*value)) {
+            record(conn, node_id, gateway_id, metric, time, value).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn positive(value: f64) -> bool {
+    value > 0.0
+}
+
+fn non_zero(value: f64) -> bool {
+    value != 0.0
+}
+
+/// `time` is the receive time in nanoseconds, packets without one are skipped.
+pub async fn record_reception(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    gateway_id: &str,
+    time: i64,
+    rx_rssi: i32,
+    rx_snr: f32,
+) -> anyhow::Result<()> {
+    if time <= 0 {
+        return Ok(());
+    }
+
+    record_all(
+        conn,
+        node_id,
+        gateway_id,
+        time,
+        &[
+            ("rx_rssi", Some(rx_rssi as f64), non_zero),
+            ("rx_snr", Some(rx_snr as f64), non_zero),
+        ],
+    )
+    .await
+}
+
+pub async fn record_device_metrics(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    time: i64,
+    metrics: &DeviceMetrics,
+) -> anyhow::Result<()> {
+    record_all(
+        conn,
+        node_id,
+        "",
+        time,
+        &[
+            (
+                "battery_level",
+                metrics.battery_level.map(f64::from),
+                positive,
+            ),
+            ("voltage", metrics.voltage.map(f64::from), positive),
+            (
+                "channel_utilization",
+                metrics.channel_utilization.map(f64::from),
+                positive,
+            ),
+            ("air_util_tx", metrics.air_util_tx.map(f64::from), positive),
+        ],
+    )
+    .await
+}
+
+pub async fn record_environment_metrics(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    time: i64,
+    metrics: &EnvironmentMetrics,
+) -> anyhow::Result<()> {
+    record_all(
+        conn,
+        node_id,
+        "",
+        time,
+        &[
+            ("temperature", metrics.temperature.map(f64::from), |value| {
+                value > -100.0
+            }),
+            (
+                "relative_humidity",
+                metrics.relative_humidity.map(f64::from),
+                positive,
+            ),
+            (
+                "barometric_pressure",
+                metrics.barometric_pressure.map(f64::from),
+                positive,
+            ),
+            (
+                "gas_resistance",
+                metrics.gas_resistance.map(f64::from),
+                positive,
+            ),
+            ("iaq", metrics.iaq.map(f64::from), positive),
+        ],
+    )
+    .await
+}
+
+pub async fn record_power_metrics(
+    conn: &mut DbConnection<'_>,
+    node_id: u32,
+    time: i64,
+    metrics: &PowerMetrics,
+) -> anyhow::Result<()> {
+    record_all(
+        conn,
+        node_id,
+        "",
+        time,
+        &[
+            ("ch1_voltage", metrics.ch1_voltage.map(f64::from), non_zero),
+            ("ch1_current", metrics.ch1_current.map(f64::from), non_zero),
+            ("ch2_voltage", metrics.ch2_voltage.map(f64::from), non_zero),
+            ("ch2_current", metrics.ch2_current.map(f64::from), non_zero),
+            ("ch3_voltage", metrics.ch3_voltage.map(f64::from), non_zero),
+            ("ch3_current", metrics.ch3_current.map(f64::from), non_zero),
+        ],
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_windows_use_the_raw_values() {
+        let now = 100 * NANOS_PER_DAY;
+
+        assert_eq!(
+            Resolution::for_window(now - NANOS_PER_HOUR, now),
+            Resolution::Raw
+        );
+        assert_eq!(
+            Resolution::for_window(now - 6 * NANOS_PER_HOUR, now),
+            Resolution::Raw
+        );
+    }
+
+    #[test]
+    fn longer_windows_use_the_rollups() {
+        let now = 100 * NANOS_PER_DAY;
+
+        assert_eq!(
+            Resolution::for_window(now - 6 * NANOS_PER_HOUR - 1, now),
+            Resolution::Hourly
+        );
+        assert_eq!(
+            Resolution::for_window(now - 14 * NANOS_PER_DAY, now),
+            Resolution::Hourly
+        );
+        assert_eq!(
+            Resolution::for_window(now - 14 * NANOS_PER_DAY - 1, now),
+            Resolution::Daily
+        );
+        // All data
+        assert_eq!(Resolution::for_window(0, now), Resolution::Daily);
+    }
+
+    #[test]
+    fn buckets_match_the_resolution() {
+        assert_eq!(Resolution::Raw.bucket_nanos(), 0);
+        assert_eq!(Resolution::Hourly.bucket_nanos(), NANOS_PER_HOUR);
+        assert_eq!(Resolution::Daily.bucket_nanos(), NANOS_PER_DAY);
+    }
+}
@@ -0,0 +1,279 @@
+//! Full-text search over text messages, waypoints and node names with SQLite FTS5, or the text
+//! search of PostgreSQL. The importer adds packets and nodes to the index, triggers or foreign keys
+//! remove the rows of deleted packets and nodes.
+
+use crate::{
+    dto::{Highlight, NodeSearchResult, PacketSearchResult},
+    proto::meshtastic::PortNum,
+    util::{
+        db::{on_db, DbConnection},
+        DbPool, NODENUM_BROADCAST,
+    },
+};
+use itertools::Itertools;
+
+/// The matches are marked with control characters, so they are told apart from the text without
+/// trusting it as HTML.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// Adds the text of a text message or waypoint.
+pub async fn index_packet(
+    conn: &mut DbConnection<'_>,
+    mesh_packet_id: i64,
+    content: &str,
+) -> anyhow::Result<()> {
+    if content.trim().is_empty() {
+        return Ok(());
+    }
+
+    let query = conn.pick(
+        "INSERT INTO packet_search (rowid, content) VALUES ($1, $2)",
+        "INSERT INTO packet_search (mesh_packet_id, content) VALUES ($1, $2)",
+    );
+    on_db!(conn, |conn| {
+        sqlx::query(query)
+            .bind(mesh_packet_id)
+            .bind(content)
+            .execute(conn)
+            .await
+            .map(|_| ())
+    })?;
+
+    Ok(())
+}
+
+/// Replaces the names of a node with the ones in the nodes table.
+pub async fn index_node(conn: &mut DbConnection<'_>, node_id: u32) -> anyhow::Result<()> {
+    let key = conn.pick("rowid", "id");
+    let delete = format!(
+        "DELETE FROM node_search WHERE {} = (SELECT id FROM nodes WHERE node_id = $1)",
+        key
+    );
+    let insert = format!(
+        "INSERT INTO node_search ({}, long_name, short_name, user_id)
+         SELECT id, COALESCE(long_name, ''), COALESCE(short_name, ''), user_id FROM nodes WHERE node_id = $1",
+        key
+    );
+    for query in [delete, insert] {
+        on_db!(conn, |conn| {
+            sqlx::query(&query)
+                .bind(i64::from(node_id))
+                .execute(conn)
+                .await
+                .map(|_| ())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The search expression of the input for the backend, see `match_expression` and
+/// `tsquery_expression`.
+pub fn expression(pool: &DbPool, input: &str) -> Option<String> {
+    if pool.is_sqlite() {
+        match_expression(input)
+    } else {
+        tsquery_expression(input)
+    }
+}
+
+/// Turns the input into an FTS5 query matching all words as prefixes, so the search syntax of
+/// FTS5 cannot cause errors. Returns `None` when there is nothing to search for.
+pub fn match_expression(input: &str) -> Option<String> {
+    let terms = input
+        .split_whitespace()
+        .filter(|term| term.chars().any(char::is_alphanumeric))
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect_vec();
+
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// The same for a PostgreSQL `tsquery`: every word is quoted as a prefix and all of them have to
+/// match.
+pub fn tsquery_expression(input: &str) -> Option<String> {
+    let terms = input
+        .split_whitespace()
+        .filter(|term| term.chars().any(char::is_alphanumeric))
+        .map(|term| format!("'{}':*", term.replace('\\', "\\\\").replace('\'', "''")))
+        .collect_vec();
+
+    (!terms.is_empty()).then(|| terms.join(" & "))
+}
+
+/// Splits a marked text into the matches and the text between them.
+pub fn highlight(text: &str) -> Vec<Highlight> {
+    text.split([MATCH_START, MATCH_END])
+        .enumerate()
+        .filter(|(_, part)| !part.is_empty())
+        .map(|(index, part)| {
+            if index % 2 == 1 {
+                Highlight::Match(part.to_string())
+            } else {
+                Highlight::Text(part.to_string())
+            }
+        })
+        .collect()
+}
+
+pub async fn search_nodes(pool: &DbPool, expression: &str) -> sqlx::Result<Vec<NodeSearchResult>> {
+    let query = pool.pick(
+        r#"
+            SELECT
+                nodes.node_id,
+                highlight(node_search, 0, char(2), char(3)) AS long_name,
+                highlight(node_search, 1, char(2), char(3)) AS short_name,
+                highlight(node_search, 2, char(2), char(3)) AS user_id
+            FROM node_search
+            JOIN nodes ON nodes.id = node_search.rowid
+            WHERE node_search MATCH $1 AND nodes.hidden = 0
+            ORDER BY rank
+            LIMIT 20
+        "#,
+        r#"
+            SELECT
+                nodes.node_id,
+                ts_headline('meshstellar', node_search.long_name, query, 'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS long_name,
+                ts_headline('meshstellar', node_search.short_name, query, 'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS short_name,
+                ts_headline('meshstellar', node_search.user_id, query, 'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS user_id
+            FROM node_search
+            CROSS JOIN to_tsquery('meshstellar', $1) AS query
+            JOIN nodes ON nodes.id = node_search.id
+            WHERE node_search.document @@ query AND nodes.hidden = 0
+            ORDER BY ts_rank(node_search.document, query) DESC
+            LIMIT 20
+        "#,
+    );
+
+    on_db!(pool, |pool| {
+        sqlx::query_as::<_, NodeSearchResult>(query)
+            .bind(expression)
+            .fetch_all(pool)
+            .await
+    })
+}
+
+/// Private messages are left out when `hide_private_messages` is set, like in the message list.
+pub async fn search_packets(
+    pool: &DbPool,
+    expression: &str,
+    hide_private_messages: bool,
+) -> sqlx::Result<Vec<PacketSearchResult>> {
+    let text_message_app = PortNum::TextMessageApp as i64;
+    let broadcast = NODENUM_BROADCAST as i64;
+
+    let query = pool.pick(
+        r#"
+            SELECT
+                mesh_packets.id,
+                mesh_packets.from_id,
+                mesh_packets.portnum,
+                COALESCE(mesh_packets.received_at, mesh_packets.created_at) AS received_at,
+                snippet(packet_search, 0, char(2), char(3), '…', 24) AS snippet
+            FROM packet_search
+            JOIN mesh_packets ON mesh_packets.id = packet_search.rowid
+            WHERE packet_search MATCH $1 AND mesh_packets.hidden = 0
+            AND NOT ($2 AND mesh_packets.portnum = $3 AND mesh_packets.to_id != $4)
+            ORDER BY rank
+            LIMIT 50
+        "#,
+        r#"
+            SELECT
+                mesh_packets.id,
+                mesh_packets.from_id,
+                mesh_packets.portnum,
+                COALESCE(mesh_packets.received_at, mesh_packets.created_at) AS received_at,
+                ts_headline('meshstellar', packet_search.content, query, 'MaxWords=24, MinWords=12, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS snippet
+            FROM packet_search
+            CROSS JOIN to_tsquery('meshstellar', $1) AS query
+            JOIN mesh_packets ON mesh_packets.id = packet_search.mesh_packet_id
+            WHERE packet_search.document @@ query AND mesh_packets.hidden = 0
+            AND NOT ($2 = 1 AND mesh_packets.portnum = $3 AND mesh_packets.to_id != $4)
+            ORDER BY ts_rank(packet_search.document, query) DESC
+            LIMIT 50
+        "#,
+    );
+
+    on_db!(pool, |pool| {
+        sqlx::query_as::<_, PacketSearchResult>(query)
+            .bind(expression)
+            .bind(i64::from(hide_private_messages))
+            .bind(text_message_app)
+            .bind(broadcast)
+            .fetch_all(pool)
+            .await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_matched_as_prefixes() {
+        assert_eq!(
+            match_expression("  hello   mesh "),
+            Some("\"hello\"* \"mesh\"*".to_string())
+        );
+    }
+
+    #[test]
+    fn search_syntax_is_quoted() {
+        assert_eq!(
+            match_expression("say \"hi\" OR NEAR(a b)"),
+            Some("\"say\"* \"\"\"hi\"\"\"* \"OR\"* \"NEAR(a\"* \"b)\"*".to_string())
+        );
+    }
+
+    #[test]
+    fn inputs_without_words_match_nothing() {
+        assert_eq!(match_expression(""), None);
+        assert_eq!(match_expression(" * \" - "), None);
+    }
+
+    #[test]
+    fn words_are_matched_as_tsquery_prefixes() {
+        assert_eq!(
+            tsquery_expression("  hello   mesh "),
+            Some("'hello':* & 'mesh':*".to_string())
+        );
+        assert_eq!(
+            tsquery_expression("it's a\\b | !x"),
+            Some("'it''s':* & 'a\\\\b':* & '!x':*".to_string())
+        );
+        assert_eq!(tsquery_expression(" * ' - "), None);
+    }
+
+    #[test]
+    fn marked_matches_are_highlighted() {
+        let text = format!(
+            "say {}hello{} to the {}mesh{}",
+            MATCH_START, MATCH_END, MATCH_START, MATCH_END
+        );
+
+        assert_eq!(
+            highlight(&text),
+            vec![
+                Highlight::Text("say ".to_string()),
+                Highlight::Match("hello".to_string()),
+                Highlight::Text(" to the ".to_string()),
+                Highlight::Match("mesh".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_at_the_start_are_highlighted() {
+        let text = format!("{}<b>{}</b>", MATCH_START, MATCH_END);
+
+        assert_eq!(
+            highlight(&text),
+            vec![
+                Highlight::Match("<b>".to_string()),
+                Highlight::Text("</b>".to_string()),
+            ]
+        );
+        assert_eq!(highlight(""), vec![]);
+    }
+}
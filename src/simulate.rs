@@ -0,0 +1,717 @@
+//! A virtual mesh that writes service envelopes into the pipeline, to demo the web interface
+//! without radios and to load test the importer.
+
+use crate::{
+    import::{notify_importer, queue_service_envelope},
+    proto::meshtastic::{
+        config::device_config::Role, mesh_packet::PayloadVariant, telemetry, Data, DeviceMetrics,
+        EnvironmentMetrics, HardwareModel, MeshPacket, Neighbor, NeighborInfo, PortNum, Position,
+        RouteDiscovery, ServiceEnvelope, Telemetry, User,
+    },
+    util::{config::get_config, crypto, DbPool, NODENUM_BROADCAST},
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use prost::Message;
+use serde::Deserialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tracing::info;
+
+const HOP_LIMIT: u32 = 3;
+/// Links with a lower average SNR (in dB) do not carry packets, LongFast decodes down to about
+/// -20 dB.
+const SNR_FLOOR: f64 = -17.5;
+
+const NODE_INFO_INTERVAL: f64 = 3.0 * 3600.0;
+const NEIGHBOR_INFO_INTERVAL: f64 = 3.0 * 3600.0;
+const POSITION_INTERVAL: f64 = 15.0 * 60.0;
+const MOBILE_POSITION_INTERVAL: f64 = 60.0;
+const TELEMETRY_INTERVAL: f64 = 30.0 * 60.0;
+const TEXT_MESSAGE_INTERVAL: f64 = 5.0 * 60.0;
+const TRACEROUTE_INTERVAL: f64 = 15.0 * 60.0;
+
+const TEXT_MESSAGES: &[&str] = &[
+    "Good morning mesh!",
+    "Anyone copy?",
+    "Testing from the hilltop",
+    "Signal is great today",
+    "Heading out on the bike, will report in",
+    "73",
+    "Is the router on the tower back up?",
+    "Copy that, loud and clear",
+];
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct SimulateConfig {
+    nodes: usize,
+    /// The first nodes are routers that uplink the packets they hear.
+    gateways: usize,
+    /// Latitude and longitude the nodes are placed around.
+    center: (f64, f64),
+    radius_km: f64,
+    /// Share of the nodes that move around.
+    mobile_fraction: f64,
+    /// Simulated seconds per second, 10 generates 10 times the traffic.
+    speed: f64,
+    /// Root of the topics of the envelopes, e.g. `msh/SIM/2/e`.
+    topic: String,
+    /// Seed of the generator, a random mesh is generated when not set.
+    seed: Option<u64>,
+}
+
+impl Default for SimulateConfig {
+    fn default() -> Self {
+        SimulateConfig {
+            nodes: 50,
+            gateways: 3,
+            center: (52.37, 4.89),
+            radius_km: 10.0,
+            mobile_fraction: 0.1,
+            speed: 1.0,
+            topic: "msh/SIM/2/e".to_string(),
+            seed: None,
+        }
+    }
+}
+
+/// Xorshift, the simulation does not need a cryptographically secure generator.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, min: f64, max: f64) -> f64 {
+        min + self.next_f64() * (max - min)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    fn gaussian(&mut self, std_dev: f64) -> f64 {
+        let u1 = self.next_f64().max(f64::MIN_POSITIVE);
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos() * std_dev
+    }
+
+    fn index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+}
+
+struct Node {
+    num: u32,
+    long_name: String,
+    short_name: String,
+    hw_model: HardwareModel,
+    role: Role,
+    latitude: f64,
+    longitude: f64,
+    altitude: i32,
+    /// Heading in radians and speed in m/s of mobile nodes.
+    movement: Option<(f64, f64)>,
+    battery_level: f64,
+    has_environment_sensor: bool,
+    next_node_info: f64,
+    next_neighbor_info: f64,
+    next_position: f64,
+    next_telemetry: f64,
+}
+
+/// A packet heard by a gateway.
+struct Reception {
+    gateway: usize,
+    hops: u32,
+    snr: f32,
+    rssi: i32,
+}
+
+struct Simulation {
+    rng: Rng,
+    config: SimulateConfig,
+    channel: &'static crypto::Channel,
+    nodes: Vec<Node>,
+    /// Simulated seconds since the start.
+    time: f64,
+    next_text_message: f64,
+    next_traceroute: f64,
+}
+
+impl Simulation {
+    fn new(config: SimulateConfig, channel: &'static crypto::Channel) -> Simulation {
+        let seed = config.seed.unwrap_or_else(|| {
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u64(Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64);
+            hasher.finish()
+        });
+        let mut rng = Rng::new(seed);
+        let gateways = config.gateways.clamp(1, config.nodes.max(1));
+        let hw_models = [
+            HardwareModel::Tbeam,
+            HardwareModel::HeltecV3,
+            HardwareModel::Rak4631,
+            HardwareModel::TEcho,
+        ];
+
+        let mut node_nums = HashSet::new();
+        let nodes = (0..config.nodes.max(1))
+            .map(|index| {
+                let num = loop {
+                    let num = rng.next_u64() as u32;
+                    if num > 0x0fffffff && num != NODENUM_BROADCAST && node_nums.insert(num) {
+                        break num;
+                    }
+                };
+                let short_name = format!("{:04x}", num & 0xffff);
+
+                // Gateways are routers close to the center, like on a tower
+                let is_gateway = index < gateways;
+                let mobile = !is_gateway && rng.chance(config.mobile_fraction);
+                let distance_km = match is_gateway {
+                    true => rng.range(0.0, config.radius_km / 2.0),
+                    false => config.radius_km * rng.next_f64().sqrt(),
+                };
+                let (latitude, longitude) = offset(
+                    config.center,
+                    distance_km,
+                    rng.range(0.0, 2.0 * std::f64::consts::PI),
+                );
+
+                Node {
+                    num,
+                    long_name: match is_gateway {
+                        true => format!("Sim Router {}", short_name),
+                        false => format!("Sim Node {}", short_name),
+                    },
+                    short_name,
+                    hw_model: match is_gateway {
+                        true => HardwareModel::StationG2,
+                        false => hw_models[rng.index(hw_models.len())],
+                    },
+                    role: match (is_gateway, mobile) {
+                        (true, _) => Role::Router,
+                        (false, true) => Role::Tracker,
+                        (false, false) if rng.chance(0.2) => Role::ClientMute,
+                        _ => Role::Client,
+                    },
+                    latitude,
+                    longitude,
+                    altitude: match is_gateway {
+                        true => rng.range(40.0, 120.0) as i32,
+                        false => rng.range(0.0, 30.0) as i32,
+                    },
+                    movement: mobile.then(|| {
+                        (
+                            rng.range(0.0, 2.0 * std::f64::consts::PI),
+                            rng.range(1.0, 15.0),
+                        )
+                    }),
+                    battery_level: rng.range(20.0, 100.0),
+                    has_environment_sensor: rng.chance(0.3),
+                    // Announce the nodes quickly so the map fills up
+                    next_node_info: rng.range(0.0, 60.0),
+                    next_neighbor_info: rng.range(60.0, NEIGHBOR_INFO_INTERVAL),
+                    next_position: rng.range(0.0, 120.0),
+                    next_telemetry: rng.range(60.0, TELEMETRY_INTERVAL),
+                }
+            })
+            .collect();
+
+        Simulation {
+            rng,
+            config,
+            channel,
+            nodes,
+            time: 0.0,
+            next_text_message: TEXT_MESSAGE_INTERVAL / 2.0,
+            next_traceroute: TRACEROUTE_INTERVAL / 2.0,
+        }
+    }
+
+    fn distance_km(&self, a: usize, b: usize) -> f64 {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        let dy = (a.latitude - b.latitude) * 110.57;
+        let dx = (a.longitude - b.longitude) * 111.32 * a.latitude.to_radians().cos();
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Average SNR of the link between two nodes, routers are mounted higher up.
+    fn link_snr(&self, a: usize, b: usize) -> f64 {
+        let elevated = [a, b]
+            .iter()
+            .filter(|node| self.nodes[**node].role == Role::Router)
+            .count() as f64;
+
+        10.0 - 30.0 * (1.0 + self.distance_km(a, b)).log10() + elevated * 6.0
+    }
+
+    fn is_link(&self, a: usize, b: usize) -> bool {
+        a != b && self.link_snr(a, b) > SNR_FLOOR
+    }
+
+    /// Shortest paths from a node, client mute nodes do not rebroadcast. Returns the previous
+    /// node on the path and the number of hops for every node that can be reached.
+    fn paths_from(&self, from: usize) -> Vec<Option<(usize, u32)>> {
+        let mut paths = vec![None; self.nodes.len()];
+        paths[from] = Some((from, 0));
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(current) = queue.pop_front() {
+            let (_, hops) = paths[current].unwrap();
+            if current != from && self.nodes[current].role == Role::ClientMute {
+                continue;
+            }
+
+            for (next, path) in paths.iter_mut().enumerate() {
+                if path.is_none() && self.is_link(current, next) {
+                    *path = Some((current, hops + 1));
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        paths
+    }
+
+    /// Nodes on the path from `from` to `to`, without the end points.
+    fn route(&self, paths: &[Option<(usize, u32)>], from: usize, to: usize) -> Vec<usize> {
+        let mut route = Vec::new();
+        let mut current = to;
+
+        while let Some((previous, _)) = paths[current] {
+            if previous == from || previous == current {
+                break;
+            }
+            route.push(previous);
+            current = previous;
+        }
+
+        route.reverse();
+        route
+    }
+
+    /// The gateways hearing a packet, within the hop limit. Weak links lose packets now and then.
+    fn receptions(&mut self, from: usize) -> Vec<Reception> {
+        let paths = self.paths_from(from);
+        let gateways = self.config.gateways.clamp(1, self.nodes.len());
+        let mut receptions = Vec::new();
+
+        for (gateway, path) in paths.into_iter().enumerate().take(gateways) {
+            let Some((previous, hops)) = path else {
+                continue;
+            };
+
+            if hops == 0 {
+                // Gateways uplink their own packets without signal information
+                receptions.push(Reception {
+                    gateway,
+                    hops,
+                    snr: 0.0,
+                    rssi: 0,
+                });
+                continue;
+            }
+            if hops > HOP_LIMIT {
+                continue;
+            }
+
+            let snr = self.link_snr(previous, gateway) + self.rng.gaussian(2.0);
+            let loss = ((SNR_FLOOR + 10.0 - snr) / 20.0).clamp(0.02, 0.5);
+            if self.rng.chance(loss) {
+                continue;
+            }
+
+            receptions.push(Reception {
+                gateway,
+                hops,
+                snr: snr as f32,
+                rssi: (-95.0 + snr * 2.0 + self.rng.gaussian(3.0)).clamp(-130.0, -20.0) as i32,
+            });
+        }
+
+        receptions
+    }
+
+    fn packet_id(&mut self) -> u32 {
+        // Zero is not a valid packet id
+        (self.rng.next_u64() as u32).max(1)
+    }
+
+    /// Encrypts a packet and wraps it in an envelope for every gateway that hears it.
+    fn transmit(&mut self, from: usize, to: u32, id: u32, data: Data) -> Vec<(String, Vec<u8>)> {
+        let sender = self.nodes[from].num;
+        let Some(encrypted) =
+            crypto::apply_channel_cipher(&self.channel.key, id, sender, &data.encode_to_vec())
+        else {
+            return Vec::new();
+        };
+        let rx_time = Utc::now().timestamp() as u32;
+
+        self.receptions(from)
+            .into_iter()
+            .map(|reception| {
+                let gateway_id = format!("!{:08x}", self.nodes[reception.gateway].num);
+                let packet = MeshPacket {
+                    from: sender,
+                    to,
+                    channel: self.channel.hash,
+                    id,
+                    rx_time,
+                    rx_snr: reception.snr,
+                    rx_rssi: reception.rssi,
+                    hop_start: HOP_LIMIT,
+                    hop_limit: HOP_LIMIT - reception.hops,
+                    want_ack: to != NODENUM_BROADCAST,
+                    payload_variant: Some(PayloadVariant::Encrypted(encrypted.clone())),
+                    ..Default::default()
+                };
+
+                let topic = format!("{}/{}/{}", self.config.topic, self.channel.name, gateway_id);
+                let envelope = ServiceEnvelope {
+                    packet: Some(packet),
+                    channel_id: self.channel.name.clone(),
+                    gateway_id,
+                };
+
+                (topic, envelope.encode_to_vec())
+            })
+            .collect()
+    }
+
+    fn broadcast(
+        &mut self,
+        from: usize,
+        portnum: PortNum,
+        payload: Vec<u8>,
+    ) -> Vec<(String, Vec<u8>)> {
+        let id = self.packet_id();
+        let data = Data {
+            portnum: portnum as i32,
+            payload,
+            ..Default::default()
+        };
+
+        self.transmit(from, NODENUM_BROADCAST, id, data)
+    }
+
+    /// Moves the mobile nodes, they turn a bit now and then and stay within the radius.
+    fn move_nodes(&mut self, seconds: f64) {
+        let center = self.config.center;
+        let radius_km = self.config.radius_km;
+
+        for node in &mut self.nodes {
+            let Some((heading, speed)) = node.movement.as_mut() else {
+                continue;
+            };
+
+            *heading += self.rng.gaussian(0.1);
+            let (latitude, longitude) = offset(
+                (node.latitude, node.longitude),
+                *speed * seconds / 1000.0,
+                *heading,
+            );
+
+            let dy = (latitude - center.0) * 110.57;
+            let dx = (longitude - center.1) * 111.32 * center.0.to_radians().cos();
+            if (dx * dx + dy * dy).sqrt() > radius_km {
+                *heading += std::f64::consts::PI;
+            } else {
+                node.latitude = latitude;
+                node.longitude = longitude;
+            }
+        }
+    }
+
+    /// Advances the simulation, returns the envelopes received in the meantime.
+    fn step(&mut self, seconds: f64) -> Vec<(String, Vec<u8>)> {
+        self.time += seconds;
+        self.move_nodes(seconds);
+
+        let mut envelopes = Vec::new();
+
+        for index in 0..self.nodes.len() {
+            if self.nodes[index].next_node_info <= self.time {
+                self.nodes[index].next_node_info = self.next(NODE_INFO_INTERVAL);
+                let payload = self.user(index).encode_to_vec();
+                envelopes.extend(self.broadcast(index, PortNum::NodeinfoApp, payload));
+            }
+
+            if self.nodes[index].next_position <= self.time {
+                let interval = match self.nodes[index].movement {
+                    Some(_) => MOBILE_POSITION_INTERVAL,
+                    None => POSITION_INTERVAL,
+                };
+                self.nodes[index].next_position = self.next(interval);
+                let payload = self.position(index).encode_to_vec();
+                envelopes.extend(self.broadcast(index, PortNum::PositionApp, payload));
+            }
+
+            if self.nodes[index].next_telemetry <= self.time {
+                self.nodes[index].next_telemetry = self.next(TELEMETRY_INTERVAL);
+                let payload = self.device_metrics(index).encode_to_vec();
+                envelopes.extend(self.broadcast(index, PortNum::TelemetryApp, payload));
+
+                if self.nodes[index].has_environment_sensor {
+                    let payload = self.environment_metrics().encode_to_vec();
+                    envelopes.extend(self.broadcast(index, PortNum::TelemetryApp, payload));
+                }
+            }
+
+            if self.nodes[index].next_neighbor_info <= self.time {
+                self.nodes[index].next_neighbor_info = self.next(NEIGHBOR_INFO_INTERVAL);
+                let payload = self.neighbor_info(index).encode_to_vec();
+                envelopes.extend(self.broadcast(index, PortNum::NeighborinfoApp, payload));
+            }
+        }
+
+        if self.next_text_message <= self.time {
+            self.next_text_message = self.next(TEXT_MESSAGE_INTERVAL);
+            envelopes.extend(self.text_message());
+        }
+
+        if self.next_traceroute <= self.time {
+            self.next_traceroute = self.next(TRACEROUTE_INTERVAL);
+            envelopes.extend(self.traceroute());
+        }
+
+        envelopes
+    }
+
+    /// The time of the next packet, with some jitter so the nodes do not line up.
+    fn next(&mut self, interval: f64) -> f64 {
+        self.time + interval * self.rng.range(0.8, 1.2)
+    }
+
+    fn user(&self, index: usize) -> User {
+        let node = &self.nodes[index];
+
+        User {
+            id: format!("!{:08x}", node.num),
+            long_name: node.long_name.clone(),
+            short_name: node.short_name.clone(),
+            hw_model: node.hw_model as i32,
+            role: node.role as i32,
+            ..Default::default()
+        }
+    }
+
+    fn position(&mut self, index: usize) -> Position {
+        let node = &self.nodes[index];
+
+        Position {
+            latitude_i: Some((node.latitude * 1e7) as i32),
+            longitude_i: Some((node.longitude * 1e7) as i32),
+            altitude: Some(node.altitude),
+            time: Utc::now().timestamp() as u32,
+            ground_speed: node.movement.map(|(_, speed)| speed as u32),
+            sats_in_view: self.rng.range(4.0, 14.0) as u32,
+            ..Default::default()
+        }
+    }
+
+    fn device_metrics(&mut self, index: usize) -> Telemetry {
+        let uptime_seconds = self.time as u32;
+        let channel_utilization = self.rng.range(2.0, 25.0) as f32;
+        let air_util_tx = self.rng.range(0.1, 3.0) as f32;
+        let node = &mut self.nodes[index];
+
+        // Batteries drain slowly and get charged when empty
+        node.battery_level -= 0.5;
+        if node.battery_level < 10.0 {
+            node.battery_level = 100.0;
+        }
+
+        Telemetry {
+            time: Utc::now().timestamp() as u32,
+            variant: Some(telemetry::Variant::DeviceMetrics(DeviceMetrics {
+                battery_level: Some(node.battery_level as u32),
+                voltage: Some((3.3 + node.battery_level * 0.009) as f32),
+                channel_utilization: Some(channel_utilization),
+                air_util_tx: Some(air_util_tx),
+                uptime_seconds: Some(uptime_seconds),
+            })),
+        }
+    }
+
+    fn environment_metrics(&mut self) -> Telemetry {
+        Telemetry {
+            time: Utc::now().timestamp() as u32,
+            variant: Some(telemetry::Variant::EnvironmentMetrics(EnvironmentMetrics {
+                temperature: Some((15.0 + self.rng.gaussian(3.0)) as f32),
+                relative_humidity: Some(self.rng.range(40.0, 90.0) as f32),
+                barometric_pressure: Some((1013.0 + self.rng.gaussian(8.0)) as f32),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// The direct neighbors of a node, with the strongest first.
+    fn neighbor_info(&mut self, index: usize) -> NeighborInfo {
+        let num = self.nodes[index].num;
+        let mut neighbors = (0..self.nodes.len())
+            .filter(|other| self.is_link(index, *other))
+            .map(|other| (other, self.link_snr(index, other)))
+            .collect::<Vec<_>>();
+        neighbors.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        neighbors.truncate(10);
+
+        NeighborInfo {
+            node_id: num,
+            last_sent_by_id: num,
+            node_broadcast_interval_secs: NEIGHBOR_INFO_INTERVAL as u32,
+            neighbors: neighbors
+                .into_iter()
+                .map(|(other, snr)| Neighbor {
+                    node_id: self.nodes[other].num,
+                    snr: (snr + self.rng.gaussian(1.0)) as f32,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    /// A message to the channel, or a direct message to a node in range now and then.
+    fn text_message(&mut self) -> Vec<(String, Vec<u8>)> {
+        let from = self.rng.index(self.nodes.len());
+        let text = TEXT_MESSAGES[self.rng.index(TEXT_MESSAGES.len())];
+        let to = match self.rng.chance(0.2) {
+            true => self.nodes[self.rng.index(self.nodes.len())].num,
+            false => NODENUM_BROADCAST,
+        };
+
+        let id = self.packet_id();
+        let data = Data {
+            portnum: PortNum::TextMessageApp as i32,
+            payload: text.as_bytes().to_vec(),
+            ..Default::default()
+        };
+
+        self.transmit(from, to, id, data)
+    }
+
+    /// A traceroute between two nodes, the response follows the shortest path.
+    fn traceroute(&mut self) -> Vec<(String, Vec<u8>)> {
+        let from = self.rng.index(self.nodes.len());
+        let to = self.rng.index(self.nodes.len());
+        if from == to {
+            return Vec::new();
+        }
+
+        let request_id = self.packet_id();
+        let request = Data {
+            portnum: PortNum::TracerouteApp as i32,
+            payload: RouteDiscovery::default().encode_to_vec(),
+            want_response: true,
+            ..Default::default()
+        };
+        let mut envelopes = self.transmit(from, self.nodes[to].num, request_id, request);
+
+        let paths = self.paths_from(from);
+        if paths[to].is_none_or(|(_, hops)| hops > HOP_LIMIT) {
+            return envelopes;
+        }
+
+        let route = self.route(&paths, from, to);
+        let hops: Vec<usize> = [from]
+            .into_iter()
+            .chain(route.clone())
+            .chain([to])
+            .collect();
+
+        // SNR values in the route are multiplied by 4
+        let mut snr_towards = Vec::new();
+        let mut snr_back = Vec::new();
+        for link in hops.windows(2) {
+            let snr = self.link_snr(link[0], link[1]);
+            snr_towards.push(((snr + self.rng.gaussian(1.0)) * 4.0) as i32);
+            snr_back.insert(0, ((snr + self.rng.gaussian(1.0)) * 4.0) as i32);
+        }
+
+        let route_discovery = RouteDiscovery {
+            route: route.iter().map(|node| self.nodes[*node].num).collect(),
+            snr_towards,
+            route_back: route
+                .iter()
+                .rev()
+                .map(|node| self.nodes[*node].num)
+                .collect(),
+            snr_back,
+        };
+        let response = Data {
+            portnum: PortNum::TracerouteApp as i32,
+            payload: route_discovery.encode_to_vec(),
+            request_id,
+            ..Default::default()
+        };
+        let id = self.packet_id();
+        envelopes.extend(self.transmit(to, self.nodes[from].num, id, response));
+
+        envelopes
+    }
+}
+
+/// Moves a position `distance_km` in the direction of `bearing` (radians, clockwise from north).
+fn offset((latitude, longitude): (f64, f64), distance_km: f64, bearing: f64) -> (f64, f64) {
+    (
+        latitude + distance_km * bearing.cos() / 110.57,
+        longitude + distance_km * bearing.sin() / (111.32 * latitude.to_radians().cos()),
+    )
+}
+
+/// Generates the traffic of the mesh configured in the `simulate` table, as received by its
+/// gateways on the first configured channel.
+pub async fn start_server(pool: DbPool) -> anyhow::Result<()> {
+    let config: SimulateConfig = match get_config().get("simulate") {
+        Ok(config) => config,
+        Err(config::ConfigError::NotFound(_)) => SimulateConfig::default(),
+        Err(err) => return Err(anyhow!(err)),
+    };
+    let channel = crypto::configured_channels()
+        .first()
+        .ok_or(anyhow!("No channel configured to simulate"))?;
+
+    info!(
+        "Simulating {} nodes and {} gateways on {} at {}x speed",
+        config.nodes, config.gateways, channel.name, config.speed
+    );
+
+    let speed = config.speed.max(0.0);
+    let mut simulation = Simulation::new(config, channel);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let envelopes = simulation.step(speed);
+        if envelopes.is_empty() {
+            continue;
+        }
+
+        let created_at = Utc::now().timestamp_nanos_opt().unwrap();
+        let mut txn = pool.begin().await?;
+        for (topic, envelope) in &envelopes {
+            queue_service_envelope(&mut txn.executor(), envelope, Some(topic), created_at).await?;
+        }
+        txn.commit().await?;
+        notify_importer();
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
     dto::{
         mesh_packet::{MeshPacket as MeshPacketDto, Payload},
-        GatewayPacketInfo, NodeSelectResult, PlotData, StatsSelectResult,
+        GatewayPacketInfo, Highlight, NeighborLinkSelectResult, NodeSearchResult,
+        NodeSelectResult, PacketSearchResult, PlotData, StatsSelectResult,
+        TracerouteProbeSelectResult,
     },
     proto::meshtastic::config::device_config::Role,
     util::capitalize,
@@ -15,7 +17,9 @@ const GIT_VERSION: &str = env!("VERGEN_GIT_DESCRIBE");
 
 #[derive(Template)]
 #[template(path = "index.html")]
-pub(crate) struct IndexTemplate {}
+pub(crate) struct IndexTemplate {
+    pub channels: Vec<String>,
+}
 
 #[derive(Template)]
 #[template(path = "_stats.html")]
@@ -36,6 +40,20 @@ pub(crate) struct PacketTemplate {
     pub packet: MeshPacketDto,
 }
 
+#[derive(Template)]
+#[template(path = "_packet_page.html")]
+pub(crate) struct PacketDetailsTemplate {
+    pub packet: MeshPacketDto,
+}
+
+#[derive(Template)]
+#[template(path = "_search_results.html")]
+pub(crate) struct SearchResultsTemplate {
+    pub searched: bool,
+    pub nodes: Vec<NodeSearchResult>,
+    pub packets: Vec<PacketSearchResult>,
+}
+
 #[derive(Template)]
 #[template(path = "_position_details.html")]
 pub(crate) struct PositionDetailsTemplate {
@@ -49,6 +67,9 @@ pub(crate) struct NodeDetailsTemplate {
     pub plots: Vec<PlotData>,
     pub gateway_packet_info: Vec<GatewayPacketInfo>,
     pub selected_node: Option<String>,
+    pub traceroute_probes: Vec<TracerouteProbeSelectResult>,
+    pub traceroute_enabled: bool,
+    pub neighbor_links: Vec<NeighborLinkSelectResult>,
 }
 
 fn logo() -> String {
@@ -74,6 +95,10 @@ fn format_mesh_gps(latitude: &f64, longitude: &f64) -> String {
     format!("{:.7}, {:.7}", latitude, longitude)
 }
 
+fn highlight(text: &str) -> Vec<Highlight> {
+    crate::search::highlight(text)
+}
+
 fn format_timestamp(nanos: &i64) -> String {
     Utc.timestamp_nanos(*nanos).to_rfc3339()
 }
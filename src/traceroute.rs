@@ -0,0 +1,117 @@
+use crate::{
+    downlink::Downlink,
+    util::{
+        config::get_config,
+        db::{on_db, DbPool},
+        parse_node_id,
+    },
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Deserialize)]
+struct TracerouteConfig {
+    /// Channel the scheduled traceroutes are sent on, defaults to the first configured channel.
+    channel: Option<String>,
+    #[serde(default = "default_interval_hours")]
+    interval_hours: u64,
+    /// Critical nodes that are traced every `interval_hours`.
+    #[serde(default)]
+    targets: Vec<String>,
+}
+
+fn default_interval_hours() -> u64 {
+    6
+}
+
+/// Sends a traceroute request through the downlink and records it, so the response can be
+/// linked to it when it is imported.
+pub async fn send_probe(
+    pool: &DbPool,
+    downlink: &Downlink,
+    channel: &str,
+    to: u32,
+    scheduled: bool,
+) -> anyhow::Result<u32> {
+    let sent_packet = downlink.send_traceroute(channel, to).await?;
+
+    let packet_id = sent_packet.id as i64;
+    let from_id = sent_packet.from as i64;
+    let to_id = to as i64;
+    let created_at = Utc::now().timestamp_nanos_opt().unwrap();
+
+    on_db!(pool, |pool| {
+        sqlx::query(
+            r#"
+            INSERT INTO traceroute_probes (packet_id, from_id, to_id, channel, scheduled, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(packet_id)
+        .bind(from_id)
+        .bind(to_id)
+        .bind(channel)
+        .bind(i64::from(scheduled))
+        .bind(created_at)
+        .execute(pool)
+        .await
+        .map(|_| ())
+    })?;
+
+    Ok(sent_packet.id)
+}
+
+/// Periodically traces the nodes configured in the `traceroute` table. Returns immediately when
+/// no targets are configured.
+pub async fn start_scheduler(pool: DbPool, downlink: Downlink) -> anyhow::Result<()> {
+    let config: TracerouteConfig = match get_config().get("traceroute") {
+        Ok(config) => config,
+        Err(config::ConfigError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(anyhow!(err)),
+    };
+
+    let targets = config
+        .targets
+        .iter()
+        .map(|target| parse_node_id(target).ok_or(anyhow!("Invalid traceroute target: {}", target)))
+        .collect::<anyhow::Result<Vec<u32>>>()?;
+
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Tracing {} nodes every {} hours",
+        targets.len(),
+        config.interval_hours
+    );
+
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.interval_hours.max(1) * 3600));
+
+    loop {
+        interval.tick().await;
+
+        // Radios connected over serial or TCP only know their channels once connected
+        let Some(channel) = config
+            .channel
+            .clone()
+            .or_else(|| downlink.channel_names().into_iter().next())
+        else {
+            warn!("No channel available for scheduled traceroutes");
+            continue;
+        };
+
+        for to in &targets {
+            if let Err(err) = send_probe(&pool, &downlink, &channel, *to, true).await {
+                error!("Could not send traceroute to !{:08x}: {}", to, err);
+            }
+
+            // Give the mesh some air between the requests
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        }
+    }
+}
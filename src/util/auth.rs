@@ -0,0 +1,149 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use url::Url;
+
+use super::config::get_config;
+
+/// Extractor for routes that change something, like sending messages. Requires HTTP basic
+/// authentication with the configured `web_username` and `web_password`.
+///
+/// Routes using this extractor are disabled when no credentials are configured. Browsers send the
+/// credentials along with requests from any site, so requests from other sites are rejected.
+pub struct Authenticated;
+
+impl<S> FromRequestParts<S> for Authenticated
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if is_cross_origin(&parts.headers) {
+            return Err((StatusCode::FORBIDDEN, "Cross-origin request rejected").into_response());
+        }
+
+        let (Ok(username), Ok(password)) = (
+            get_config().get_string("web_username"),
+            get_config().get_string("web_password"),
+        ) else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Configure web_username and web_password to enable this feature",
+            )
+                .into_response());
+        };
+
+        let credentials = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| base64::prelude::BASE64_STANDARD.decode(value).ok())
+            .and_then(|value| String::from_utf8(value).ok());
+
+        // Comparing the hashes keeps the comparison constant time
+        let expected = blake3::hash(format!("{}:{}", username, password).as_bytes());
+
+        match credentials {
+            Some(credentials) if blake3::hash(credentials.as_bytes()) == expected => {
+                Ok(Authenticated)
+            }
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"meshstellar\"")],
+                "Authentication required",
+            )
+                .into_response()),
+        }
+    }
+}
+
+/// Browsers tell where a request comes from with `Sec-Fetch-Site`, older ones only send `Origin`.
+/// Other clients, like the forwarder, send neither.
+fn is_cross_origin(headers: &HeaderMap) -> bool {
+    let value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(site) = value(header::HeaderName::from_static("sec-fetch-site")) {
+        return !matches!(site, "same-origin" | "none");
+    }
+
+    let Some(origin) = value(header::ORIGIN) else {
+        return false;
+    };
+
+    let origin_host = Url::parse(origin).ok().and_then(|origin| {
+        let host = origin.host_str()?;
+        Some(match origin.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        })
+    });
+
+    !matches!(
+        (origin_host, value(header::HOST)),
+        (Some(origin_host), Some(host)) if origin_host.eq_ignore_ascii_case(host)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    header::HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn requests_of_other_sites_are_cross_origin() {
+        assert!(is_cross_origin(&headers(&[(
+            "sec-fetch-site",
+            "cross-site"
+        )])));
+        assert!(is_cross_origin(&headers(&[(
+            "sec-fetch-site",
+            "same-site"
+        )])));
+        assert!(!is_cross_origin(&headers(&[(
+            "sec-fetch-site",
+            "same-origin"
+        )])));
+        assert!(!is_cross_origin(&headers(&[("sec-fetch-site", "none")])));
+    }
+
+    #[test]
+    fn the_origin_is_compared_with_the_host_without_sec_fetch_site() {
+        assert!(!is_cross_origin(&headers(&[
+            ("origin", "http://127.0.0.1:3000"),
+            ("host", "127.0.0.1:3000"),
+        ])));
+        assert!(!is_cross_origin(&headers(&[
+            ("origin", "https://mesh.example.com"),
+            ("host", "mesh.example.com"),
+        ])));
+        assert!(is_cross_origin(&headers(&[
+            ("origin", "https://evil.example.com"),
+            ("host", "mesh.example.com"),
+        ])));
+        assert!(is_cross_origin(&headers(&[
+            ("origin", "null"),
+            ("host", "mesh.example.com"),
+        ])));
+    }
+
+    #[test]
+    fn clients_without_browser_headers_are_allowed() {
+        assert!(!is_cross_origin(&headers(&[("host", "mesh.example.com")])));
+        assert!(!is_cross_origin(&HeaderMap::new()));
+    }
+}
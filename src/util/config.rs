@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use std::sync::OnceLock;
 use tracing::{info, warn};
 
-use config::{Config, FileFormat};
+use config::{Config, FileFormat, Value, ValueKind};
 
 pub fn get_config() -> &'static Config {
     static CONFIG: OnceLock<Config> = OnceLock::new();
@@ -11,6 +11,24 @@ pub fn get_config() -> &'static Config {
     CONFIG.get_or_init(|| build_config().unwrap())
 }
 
+/// Reads a list setting. Lists can be configured as an array in the configuration file, or as a
+/// comma separated string (which is the only option when using environment variables).
+pub fn get_list(key: &str) -> Result<Vec<Value>> {
+    let value = get_config().get::<Value>(key)?;
+    let origin = value.origin().map(|origin| origin.to_string());
+
+    Ok(match value.kind {
+        ValueKind::Array(values) => values,
+        ValueKind::String(string) => string
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Value::new(origin.as_ref(), item))
+            .collect(),
+        kind => vec![Value::new(origin.as_ref(), kind)],
+    })
+}
+
 /// Get the location of the first found config file paths according to the following order:
 ///
 /// 1. meshstellar.toml
@@ -83,7 +101,9 @@ fn build_config() -> Result<Config> {
         info!("Loading configuration from {}", config_file);
         builder.add_source(config::File::new(&config_file, FileFormat::Toml).required(false))
     } else {
-        if std::env::var("MESHSTELLAR_MQTT_HOST").is_err() {
+        if std::env::var("MESHSTELLAR_MQTT_URL").is_err()
+            && std::env::var("MESHSTELLAR_MQTT_HOST").is_err()
+        {
             warn!("No configuration found, falling back to defaults. Create a configuration file or set the appropriate environment variables.");
         }
 
@@ -0,0 +1,113 @@
+use aes::{Aes128, Aes256};
+use base64::Engine;
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher},
+    Ctr128BE,
+};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use tracing::warn;
+
+use super::config::get_config;
+
+/// The default channel key, base64 encoded.
+pub const DEFAULT_KEY: &str = "1PG7OiApB1nwvP+rz05pAQ==";
+
+/// Decodes a base64 encoded channel key.
+///
+/// Meshtastic allows a single byte key, `AQ==` is the default key and `Ag==` - `CA==` are the
+/// default key with the last byte incremented.
+pub fn channel_key(key: &str) -> Option<Vec<u8>> {
+    expand_channel_key(&base64::prelude::BASE64_STANDARD.decode(key).ok()?)
+}
+
+/// Expands the single byte keys to the default key, other valid keys are returned as they are.
+pub fn expand_channel_key(key_bytes: &[u8]) -> Option<Vec<u8>> {
+    match key_bytes {
+        [index @ 1..=10] => {
+            let mut default_key = base64::prelude::BASE64_STANDARD.decode(DEFAULT_KEY).ok()?;
+            default_key[15] = default_key[15].wrapping_add(index - 1);
+            Some(default_key)
+        }
+        bytes if bytes.len() == 16 || bytes.len() == 32 => Some(key_bytes.to_vec()),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct ChannelConfig {
+    name: String,
+    key: String,
+}
+
+/// A channel from the `channels` configuration, used to decrypt and encrypt packets.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub key: Vec<u8>,
+    pub hash: u32,
+}
+
+/// Returns the configured channels, defaulting to the LongFast channel with the default key.
+pub fn configured_channels() -> &'static Vec<Channel> {
+    static CHANNELS: OnceLock<Vec<Channel>> = OnceLock::new();
+
+    CHANNELS.get_or_init(|| {
+        let channels: Vec<ChannelConfig> = get_config().get("channels").unwrap_or_else(|_| {
+            vec![ChannelConfig {
+                name: "LongFast".to_string(),
+                key: "AQ==".to_string(),
+            }]
+        });
+
+        channels
+            .into_iter()
+            .filter_map(|channel| match channel_key(&channel.key) {
+                Some(key) => Some(Channel {
+                    hash: channel_hash(&channel.name, &key),
+                    name: channel.name,
+                    key,
+                }),
+                None => {
+                    warn!("Ignoring channel {} with an invalid key", channel.name);
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+/// The channel hash used in the `channel` field of encrypted packets.
+pub fn channel_hash(channel_name: &str, key: &[u8]) -> u32 {
+    let xor = |bytes: &[u8]| bytes.iter().fold(0u8, |hash, byte| hash ^ byte);
+
+    (xor(channel_name.as_bytes()) ^ xor(key)) as u32
+}
+
+fn create_nonce(packet_id: u32, from_node: u32) -> [u8; 16] {
+    let mut nonce = [0u8; 16];
+    nonce[0..8].copy_from_slice(&(packet_id as u64).to_le_bytes());
+    nonce[8..12].copy_from_slice(&from_node.to_le_bytes());
+    nonce[12..16].copy_from_slice(&0u32.to_le_bytes());
+    nonce
+}
+
+/// Encrypts or decrypts a packet payload. AES-CTR is symmetric, so the same operation is used
+/// for both directions.
+pub fn apply_channel_cipher(
+    key: &[u8],
+    packet_id: u32,
+    from_node: u32,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let nonce = create_nonce(packet_id, from_node);
+    let mut buf = payload.to_vec();
+
+    match key.len() {
+        16 => Ctr128BE::<Aes128>::new(key.into(), &nonce.into()).apply_keystream(&mut buf),
+        32 => Ctr128BE::<Aes256>::new(key.into(), &nonce.into()).apply_keystream(&mut buf),
+        _ => return None,
+    }
+
+    Some(buf)
+}
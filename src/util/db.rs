@@ -0,0 +1,143 @@
+//! The database backends, chosen by the scheme of the `database_url`. SQLite keeps everything in
+//! one file, PostgreSQL lets the mqtt, import and web processes run on separate hosts.
+//!
+//! Queries are written once, with `$1` style placeholders both backends understand, and run on
+//! either backend with [`on_db!`]. The few queries that differ are picked with [`Db::pick`].
+
+use anyhow::anyhow;
+use sqlx::{
+    pool::PoolConnection, Connection, PgConnection, PgPool, Postgres, Sqlite, SqliteConnection,
+    SqlitePool, Transaction,
+};
+
+/// A value of either backend.
+#[derive(Clone, Debug)]
+pub enum Db<S, P> {
+    Sqlite(S),
+    Postgres(P),
+}
+
+pub type DbPool = Db<SqlitePool, PgPool>;
+pub type DbPoolConnection = Db<PoolConnection<Sqlite>, PoolConnection<Postgres>>;
+pub type DbConnection<'c> = Db<&'c mut SqliteConnection, &'c mut PgConnection>;
+pub type DbTransaction<'c> = Db<Transaction<'c, Sqlite>, Transaction<'c, Postgres>>;
+
+/// Runs the same code with the executor of either backend, e.g.
+/// `on_db!(pool, |pool| sqlx::query("...").execute(pool).await)`. The code is compiled once per
+/// backend, so the query and its results have the types of the backend.
+macro_rules! on_db {
+    ($db:expr, |$executor:ident| $body:expr) => {
+        match $db.executor() {
+            $crate::util::db::Db::Sqlite($executor) => $body,
+            $crate::util::db::Db::Postgres($executor) => $body,
+        }
+    };
+}
+
+pub(crate) use on_db;
+
+impl<S, P> Db<S, P> {
+    pub fn is_sqlite(&self) -> bool {
+        matches!(self, Db::Sqlite(_))
+    }
+
+    /// Picks the value for the backend, e.g. the SQL of a query that differs.
+    pub fn pick<T>(&self, sqlite: T, postgres: T) -> T {
+        match self {
+            Db::Sqlite(_) => sqlite,
+            Db::Postgres(_) => postgres,
+        }
+    }
+}
+
+impl DbPool {
+    pub fn executor(&self) -> Db<&SqlitePool, &PgPool> {
+        match self {
+            Db::Sqlite(pool) => Db::Sqlite(pool),
+            Db::Postgres(pool) => Db::Postgres(pool),
+        }
+    }
+
+    /// The SQLite pool, for the features working on the database file: backups, archives and
+    /// merges. PostgreSQL has its own tools for them.
+    pub fn sqlite(&self) -> anyhow::Result<&SqlitePool> {
+        match self {
+            Db::Sqlite(pool) => Ok(pool),
+            Db::Postgres(_) => Err(anyhow!(
+                "Only supported with a sqlite:// database_url, use the PostgreSQL tools instead"
+            )),
+        }
+    }
+
+    pub async fn acquire(&self) -> sqlx::Result<DbPoolConnection> {
+        Ok(match self {
+            Db::Sqlite(pool) => Db::Sqlite(pool.acquire().await?),
+            Db::Postgres(pool) => Db::Postgres(pool.acquire().await?),
+        })
+    }
+
+    pub async fn begin(&self) -> sqlx::Result<DbTransaction<'static>> {
+        Ok(match self {
+            Db::Sqlite(pool) => Db::Sqlite(pool.begin().await?),
+            Db::Postgres(pool) => Db::Postgres(pool.begin().await?),
+        })
+    }
+
+    /// Begins a transaction that writes. SQLite takes the write lock right away, instead of
+    /// failing when a reading transaction has to wait for another writer.
+    pub async fn begin_write(&self) -> sqlx::Result<DbTransaction<'static>> {
+        Ok(match self {
+            Db::Sqlite(pool) => Db::Sqlite(pool.begin_with("BEGIN IMMEDIATE").await?),
+            Db::Postgres(pool) => Db::Postgres(pool.begin().await?),
+        })
+    }
+}
+
+impl DbPoolConnection {
+    pub fn executor(&mut self) -> DbConnection<'_> {
+        match self {
+            Db::Sqlite(conn) => Db::Sqlite(&mut **conn),
+            Db::Postgres(conn) => Db::Postgres(&mut **conn),
+        }
+    }
+}
+
+impl DbConnection<'_> {
+    pub fn executor(&mut self) -> DbConnection<'_> {
+        match self {
+            Db::Sqlite(conn) => Db::Sqlite(&mut **conn),
+            Db::Postgres(conn) => Db::Postgres(&mut **conn),
+        }
+    }
+
+    /// Begins a savepoint in the transaction of the connection.
+    pub async fn begin(&mut self) -> sqlx::Result<DbTransaction<'_>> {
+        Ok(match self {
+            Db::Sqlite(conn) => Db::Sqlite(conn.begin().await?),
+            Db::Postgres(conn) => Db::Postgres(conn.begin().await?),
+        })
+    }
+}
+
+impl DbTransaction<'_> {
+    pub fn executor(&mut self) -> DbConnection<'_> {
+        match self {
+            Db::Sqlite(txn) => Db::Sqlite(&mut **txn),
+            Db::Postgres(txn) => Db::Postgres(&mut **txn),
+        }
+    }
+
+    pub async fn commit(self) -> sqlx::Result<()> {
+        match self {
+            Db::Sqlite(txn) => txn.commit().await,
+            Db::Postgres(txn) => txn.commit().await,
+        }
+    }
+
+    pub async fn rollback(self) -> sqlx::Result<()> {
+        match self {
+            Db::Sqlite(txn) => txn.rollback().await,
+            Db::Postgres(txn) => txn.rollback().await,
+        }
+    }
+}
@@ -3,11 +3,23 @@ use chrono::{DateTime, Duration, FixedOffset, Timelike, Utc};
 use itertools::{Itertools, MinMaxResult};
 use plotters::prelude::*;
 
+/// Plots the entries as a line, with the min and max of the `bounds` as a band around it when
+/// they are not empty.
 pub fn plot_timeseries_svg(
     label: &str,
     entries: Vec<(DateTime<FixedOffset>, f64)>,
+    bounds: Vec<(DateTime<FixedOffset>, f64, f64)>,
 ) -> anyhow::Result<String> {
-    if let MinMaxResult::MinMax(min_y, max_y) = entries.iter().map(|r| r.1).minmax() {
+    // A single bucket has a range of values, but not of time
+    if entries.len() < 2 {
+        return Err(anyhow!("Cannot create plot"));
+    }
+
+    let values = entries
+        .iter()
+        .map(|r| r.1)
+        .chain(bounds.iter().flat_map(|r| [r.1, r.2]));
+    if let MinMaxResult::MinMax(min_y, max_y) = values.minmax() {
         let mut buf = String::new();
 
         let y_diff = max_y - min_y;
@@ -39,6 +51,15 @@ pub fn plot_timeseries_svg(
             .disable_mesh()
             .draw()?;
 
+            if !bounds.is_empty() {
+                let band = bounds
+                    .iter()
+                    .map(|(time, _, max)| (*time, *max))
+                    .chain(bounds.iter().rev().map(|(time, min, _)| (*time, *min)))
+                    .collect::<Vec<_>>();
+                chart.draw_series(std::iter::once(Polygon::new(band, RED.mix(0.2).filled())))?;
+            }
+
             chart
                 .draw_series(LineSeries::new(entries, &RED))?
                 .label(label);
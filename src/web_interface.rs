@@ -1,48 +1,57 @@
 use crate::{
+    archive,
+    downlink::Downlink,
     dto::{
         mesh_packet::Payload, DeviceMetricsSelectResult, EnvironmentMetricsSelectResult,
-        GatewayPacketInfo, MeshPacket as MeshPacketDto, NeighborSelectResult, NodeSelectResult,
-        PlotData, PositionSelectResult, PowerMetricsSelectResult, RoutingDto, StatsSelectResult,
-        TracerouteDto, WaypointSelectResult,
+        GatewayPacketInfo, MeshPacket as MeshPacketDto, NeighborLinkSelectResult,
+        NeighborSelectResult, NodeSelectResult, PlotData, PositionSelectResult,
+        PowerMetricsSelectResult, RoutingDto, StatsSelectResult, TracerouteDto,
+        TracerouteProbeSelectResult, WaypointSelectResult,
     },
-    proto::meshtastic::{routing, PortNum, RouteDiscovery, Routing},
+    event_hub::EventHub,
+    import::{notify_importer, queue_service_envelope},
+    proto::meshtastic::{routing, PortNum, RouteDiscovery, Routing, ServiceEnvelope},
+    rollups::Resolution,
+    search,
     template::*,
+    traceroute,
     util::{
-        self, capitalize,
+        self,
+        auth::Authenticated,
+        capitalize,
         config::get_config,
-        demoji,
+        db::{on_db, Db, DbConnection, DbPool},
+        demoji, parse_node_id,
         static_file::{Asset, StaticFile},
         stringify,
         template::into_response,
-        DatabaseError,
+        DatabaseError, NODENUM_BROADCAST,
     },
 };
 use askama::Template;
-use async_stream::stream;
 use axum::http::HeaderMap;
 use axum::{
+    body::Bytes,
     extract::{FromRef, Path, State},
-    http::{header, Uri},
+    http::{header, StatusCode, Uri},
     response::{
         sse::{Event, Sse},
         Html, IntoResponse,
     },
-    routing::get,
-    Router,
+    routing::{get, post},
+    Form, Router,
 };
 use chrono::{DateTime, FixedOffset, TimeZone, Utc};
-use futures::{
-    stream::{select_all, Stream},
-    FutureExt,
-};
+use futures::{stream::Stream, FutureExt};
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry, JsonObject, JsonValue};
 use itertools::Itertools;
+use percent_encoding::percent_decode_str;
 use prost::Message;
 use serde::Deserialize;
 use serde_json::{json, Map};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{FromRow, SqliteConnection};
 use std::{collections::HashMap, sync::OnceLock};
-use std::{convert::Infallible, pin::Pin, time::Duration};
+use std::{convert::Infallible, time::Duration};
 use tokio::net::TcpListener;
 use tokio::sync::OnceCell;
 use tower_http::{compression::CompressionLayer, trace::TraceLayer};
@@ -55,182 +64,429 @@ struct WebConfig {
     hide_private_messages: bool,
 }
 
+/// The pool of the importer, for the few handlers that write. The other handlers read through the
+/// read-only pool in the state.
+#[derive(Clone)]
+struct WritePool(DbPool);
+
 // Define your application shared state
 #[derive(Clone, FromRef)]
 struct AppState {
-    pool: SqlitePool,
+    pool: DbPool,
+    write_pool: WritePool,
     web_config: WebConfig,
+    downlink: Option<Downlink>,
+    event_hub: EventHub,
 }
 
-async fn index() -> impl IntoResponse {
-    into_response(&IndexTemplate {})
+async fn index(State(downlink): State<Option<Downlink>>) -> impl IntoResponse {
+    into_response(&IndexTemplate {
+        channels: downlink
+            .as_ref()
+            .map(|downlink| downlink.channel_names())
+            .unwrap_or_default(),
+    })
 }
 
-const NODENUM_BROADCAST: u32 = 0xffffffff;
+#[derive(Deserialize)]
+struct SendMessageForm {
+    channel: String,
+    to: Option<String>,
+    text: String,
+}
 
-fn stats_update_stream(
-    pool: State<SqlitePool>,
-) -> Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>> {
-    Box::pin(stream! {
-        loop {
-            let result = sqlx::query_as!(
-                StatsSelectResult,
-                r#"SELECT
-                    COALESCE((SELECT COUNT(id) FROM mesh_packets), 0) AS "num_packets!",
-                    COALESCE((SELECT COUNT(id) FROM nodes), 0) AS "num_nodes!"
-                "#
-            )
-            .fetch_one(&*pool)
-            .await;
+async fn send_message(
+    _authenticated: Authenticated,
+    State(downlink): State<Option<Downlink>>,
+    Form(form): Form<SendMessageForm>,
+) -> axum::response::Result<impl IntoResponse> {
+    let downlink = downlink
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "Downlink is not configured"))?;
+
+    let to = match form.to.as_deref().map(str::trim) {
+        None | Some("") => NODENUM_BROADCAST,
+        Some(to) => parse_node_id(to).ok_or((StatusCode::BAD_REQUEST, "Invalid node id"))?,
+    };
+
+    downlink
+        .send_text_message(&form.channel, to, &form.text)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    Ok(Html(if to == NODENUM_BROADCAST {
+        format!("Message sent to {}", form.channel)
+    } else {
+        format!("Message sent to !{:08x}", to)
+    }))
+}
 
-            if let Err(err) = result {
-                error!("Error occurred while fetching stats: {}", err);
-                break;
+#[derive(Deserialize)]
+struct TracerouteForm {
+    channel: Option<String>,
+}
+
+async fn send_traceroute(
+    _authenticated: Authenticated,
+    State(WritePool(pool)): State<WritePool>,
+    State(downlink): State<Option<Downlink>>,
+    Path((node_id,)): Path<(String,)>,
+    Form(form): Form<TracerouteForm>,
+) -> axum::response::Result<impl IntoResponse> {
+    let downlink = downlink
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "Downlink is not configured"))?;
+
+    let to = parse_node_id(&node_id).ok_or((StatusCode::BAD_REQUEST, "Invalid node id"))?;
+
+    let channel = match form.channel {
+        Some(channel) => channel,
+        None => downlink
+            .channel_names()
+            .into_iter()
+            .next()
+            .ok_or((StatusCode::BAD_REQUEST, "No channel configured"))?,
+    };
+
+    traceroute::send_probe(&pool, downlink, &channel, to, false)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    Ok(Html(format!("Traceroute sent to !{:08x}", to)))
+}
+
+#[derive(Deserialize)]
+struct IngestQueryParams {
+    format: Option<String>,
+}
+
+/// Accepts raw service envelopes from remote collectors. The body is a single envelope, or a
+/// batch of length-delimited envelopes with `?format=delimited`. The `x-meshstellar-topic` header
+/// holds the topic of all envelopes, `x-meshstellar-topics` a comma separated list with a percent
+/// encoded topic per envelope. The `x-meshstellar-received-at` (RFC 3339) header holds either one
+/// value for all envelopes or a comma separated list with a value per envelope.
+async fn ingest(
+    _authenticated: Authenticated,
+    State(WritePool(pool)): State<WritePool>,
+    query: axum::extract::Query<IngestQueryParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Result<impl IntoResponse> {
+    if body.is_empty() {
+        Err((StatusCode::BAD_REQUEST, "Empty body".to_string()))?;
+    }
+
+    let envelopes = match query.format.as_deref() {
+        None | Some("single") => vec![&body[..]],
+        Some("delimited") => split_length_delimited(&body)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
+        Some(format) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown format {}", format),
+        ))?,
+    };
+
+    if let Some(index) = envelopes
+        .iter()
+        .position(|envelope| envelope.is_empty() || ServiceEnvelope::decode(*envelope).is_err())
+    {
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("Envelope {} is not a valid ServiceEnvelope", index),
+        ))?;
+    }
+
+    let topics = topics(&headers, envelopes.len())?;
+    let received_at = header_values(&headers, "x-meshstellar-received-at", envelopes.len())?
+        .into_iter()
+        .map(|value| match value {
+            Some(value) => DateTime::parse_from_rfc3339(&value)
+                .ok()
+                .and_then(|time| time.timestamp_nanos_opt())
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid x-meshstellar-received-at: {}", value),
+                )),
+            None => Ok(Utc::now().timestamp_nanos_opt().unwrap()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut txn = pool.begin().await.map_err(DatabaseError)?;
+
+    for ((envelope, topic), received_at) in envelopes.iter().zip(topics).zip(received_at) {
+        queue_service_envelope(&mut txn.executor(), envelope, topic.as_deref(), received_at)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    }
+
+    txn.commit().await.map_err(DatabaseError)?;
+    notify_importer();
+
+    Ok(format!("Stored {} envelopes", envelopes.len()))
+}
+
+fn split_length_delimited(mut body: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let mut messages = Vec::new();
+
+    while !body.is_empty() {
+        let length = prost::decode_length_delimiter(&mut body)?;
+
+        if length > body.len() {
+            return Err(anyhow::anyhow!("Truncated envelope"));
+        }
+
+        let (message, rest) = body.split_at(length);
+        messages.push(message);
+        body = rest;
+    }
+
+    Ok(messages)
+}
+
+/// Reads the topic of all envelopes, or the percent encoded topic per envelope. Topics can
+/// contain commas, so they cannot be split like the other headers. Empty topics are left out.
+fn topics(headers: &HeaderMap, count: usize) -> Result<Vec<Option<String>>, (StatusCode, String)> {
+    let invalid = |name: &str| (StatusCode::BAD_REQUEST, format!("Invalid {}", name));
+
+    match (
+        headers.get("x-meshstellar-topic"),
+        headers.get("x-meshstellar-topics"),
+    ) {
+        (None, None) => Ok(vec![None; count]),
+        (Some(topic), None) => {
+            let topic = topic
+                .to_str()
+                .map_err(|_| invalid("x-meshstellar-topic"))?
+                .trim();
+
+            Ok(vec![
+                Some(topic.to_string())
+                    .filter(|topic| !topic.is_empty());
+                count
+            ])
+        }
+        (None, Some(topics)) => {
+            let topics = topics
+                .to_str()
+                .map_err(|_| invalid("x-meshstellar-topics"))?
+                .split(',')
+                .map(|topic| {
+                    percent_decode_str(topic.trim())
+                        .decode_utf8()
+                        .map(|topic| Some(topic.into_owned()).filter(|topic| !topic.is_empty()))
+                        .map_err(|_| invalid("x-meshstellar-topics"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            match topics.len() {
+                len if len == count => Ok(topics),
+                len => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Expected {} values for x-meshstellar-topics, got {}",
+                        count, len
+                    ),
+                )),
             }
-            let template = StatsTemplate {
-                stats: result.unwrap_or_default(),
-            };
+        }
+        (Some(_), Some(_)) => Err((
+            StatusCode::BAD_REQUEST,
+            "Send either x-meshstellar-topic or x-meshstellar-topics".to_string(),
+        )),
+    }
+}
 
-                if let Ok(data) = template.render() {
-                    yield Ok(Event::default().event("statistics").data(data))
-                }
+/// Reads a header with either one value, or a comma separated value per envelope. Empty values
+/// are left out.
+fn header_values(
+    headers: &HeaderMap,
+    name: &str,
+    count: usize,
+) -> Result<Vec<Option<String>>, (StatusCode, String)> {
+    let Some(value) = headers.get(name) else {
+        return Ok(vec![None; count]);
+    };
 
-            tokio::time::sleep(Duration::from_secs(15)).await;
+    let values = value
+        .to_str()
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid {}", name)))?
+        .split(',')
+        .map(|value| Some(value.trim().to_string()).filter(|value| !value.is_empty()))
+        .collect_vec();
+
+    match values.len() {
+        1 => Ok(vec![values[0].clone(); count]),
+        len if len == count => Ok(values),
+        len => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Expected 1 or {} values for {}, got {}", count, name, len),
+        )),
+    }
+}
+
+/// Publishes the statistics every 15 seconds.
+async fn publish_stats(pool: DbPool, event_hub: EventHub) {
+    loop {
+        let result = on_db!(pool, |pool| {
+            sqlx::query_as::<_, StatsSelectResult>(
+                r#"SELECT
+                    COALESCE((SELECT COUNT(id) FROM mesh_packets), 0) AS num_packets,
+                    COALESCE((SELECT COUNT(id) FROM nodes), 0) AS num_nodes
+                "#,
+            )
+            .fetch_one(pool)
+            .await
+        });
+
+        match result {
+            Ok(stats) => {
+                if let Ok(data) = (StatsTemplate { stats }).render() {
+                    event_hub.publish_stats(data);
+                }
+            }
+            Err(err) => error!("Error occurred while fetching stats: {}", err),
         }
-    })
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+    }
 }
 
-fn node_update_stream(
-    pool: State<SqlitePool>,
-) -> Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>> {
+/// Publishes the nodes updated since the last poll, every second.
+async fn publish_node_updates(pool: DbPool, event_hub: EventHub) {
     let mut last_updated_at: i64 = 0;
 
-    Box::pin(stream! {
-        loop {
-            let nodes = sqlx::query_as!(
-                NodeSelectResult,
+    loop {
+        let nodes = on_db!(pool, |pool| {
+            sqlx::query_as::<_, NodeSelectResult>(
                 r#"
-                WITH neighbor_data AS (
-                    SELECT node_id, json_object('neighbor', printf('%x',neighbor_node_id), 'snr', snr, 'timestamp', timestamp/1000000000) AS neighbor
-                    FROM
-                        (
-                            SELECT
-                                node_id,
-                                neighbor_node_id,
-                                snr,
-                                timestamp,
-                                row_number() over (partition by node_id, neighbor_node_id
-                            ORDER BY
-                                timestamp DESC) AS row_number
-                            FROM
-                                neighbors
-                            WHERE
-                                timestamp > (strftime('%s', 'now') * 1000000000) - (8 * 60 * 60 * 1000000000)
-                        )
-                        a
-                    WHERE
-                        row_number = 1 ORDER BY timestamp DESC
-                ),
-                neighbors_per_node AS (
-                    SELECT node_id, json_group_array(json(neighbor)) AS neighbor_json FROM neighbor_data GROUP BY 1
-                )
-                SELECT
-                    nodes.node_id,
-                    user_id,
-                    last_rx_time,
-                    last_rx_snr,
-                    last_rx_rssi,
-                    last_hop_start,
-                    last_hop_limit,
-                    long_name,
-                    short_name,
-                    hw_model_id,
-                    is_licensed,
-                    role,
-                    battery_level,
-                    voltage,
-                    channel_utilization,
-                    air_util_tx,
-                    uptime_seconds,
-                    temperature,
-                    relative_humidity,
-                    barometric_pressure,
-                    gas_resistance,
-                    iaq,
-                    latitude,
-                    longitude,
-                    altitude,
-                    COALESCE(neighbor_json, '[]') AS neighbor_json,
-                    updated_at,
-                    public_key,
-                    is_unmessagable
-                FROM nodes
-                LEFT JOIN neighbors_per_node ON neighbors_per_node.node_id = nodes.node_id
-                WHERE updated_at > ?
-                ORDER BY updated_at ASC
-                "#,
-                last_updated_at
+            SELECT
+                nodes.node_id,
+                user_id,
+                last_rx_time,
+                last_rx_snr,
+                last_rx_rssi,
+                last_hop_start,
+                last_hop_limit,
+                long_name,
+                short_name,
+                hw_model_id,
+                is_licensed,
+                role,
+                battery_level,
+                voltage,
+                channel_utilization,
+                air_util_tx,
+                uptime_seconds,
+                temperature,
+                relative_humidity,
+                barometric_pressure,
+                gas_resistance,
+                iaq,
+                latitude,
+                longitude,
+                altitude,
+                updated_at,
+                public_key,
+                is_unmessagable
+            FROM nodes
+            WHERE updated_at > $1 AND nodes.hidden = 0
+            ORDER BY updated_at ASC
+            "#,
             )
-            .fetch_all(&*pool)
-            .await;
+            .bind(last_updated_at)
+            .fetch_all(pool)
+            .await
+        });
 
-            if let Err(err) = nodes {
+        let nodes = match nodes {
+            Ok(nodes) => nodes,
+            Err(err) => {
                 error!("Error occurred while fetching nodes: {}", err);
-                break;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
             }
+        };
 
-            let nodes = nodes.unwrap_or_default();
+        // Nodes hidden by the ingest rules are not shown to new clients
+        let hidden_node_ids: Vec<i64> = on_db!(pool, |pool| {
+            sqlx::query_scalar("SELECT node_id FROM nodes WHERE updated_at > $1 AND hidden = 1")
+                .bind(last_updated_at)
+                .fetch_all(pool)
+                .await
+                .unwrap_or_default()
+        });
+        for node_id in hidden_node_ids {
+            event_hub.remove_node(node_id);
+        }
 
-            last_updated_at = nodes.last().map(|n| n.updated_at).unwrap_or(last_updated_at);
+        last_updated_at = nodes
+            .last()
+            .map(|n| n.updated_at)
+            .unwrap_or(last_updated_at);
 
-            for node in nodes.into_iter() {
-                let geom = if let (Some(longitude), Some(latitude), altitude) = (node.longitude, node.latitude, node.altitude) {
-                    let geometry: Geometry = geojson::Value::Point(vec![longitude, latitude, altitude.unwrap_or_default() as f64]).into();
-                    let mut properties = JsonObject::new();
-                    let short_name = demoji(node.short_name.clone().unwrap_or_default().as_str());
-                    let display_name = match short_name.trim() {
-                        "" => node.user_id.clone(),
-                        _ => short_name
-                    };
+        for node in nodes.into_iter() {
+            let geom = if let (Some(longitude), Some(latitude), altitude) =
+                (node.longitude, node.latitude, node.altitude)
+            {
+                let geometry: Geometry = geojson::Value::Point(vec![
+                    longitude,
+                    latitude,
+                    altitude.unwrap_or_default() as f64,
+                ])
+                .into();
+                let mut properties = JsonObject::new();
+                let short_name = demoji(node.short_name.clone().unwrap_or_default().as_str());
+                let display_name = match short_name.trim() {
+                    "" => node.user_id.clone(),
+                    _ => short_name,
+                };
 
-                    properties.insert("id".to_string(), JsonValue::from(format!("{:x}", node.node_id)));
-                    properties.insert("display_name".to_string(), JsonValue::from(display_name));
-                    properties.insert("long_name".to_string(), JsonValue::from(node.long_name.clone()));
-                    properties.insert("updated_at".to_string(), JsonValue::from(node.updated_at as f64 / 1_000_000_000.0));
+                properties.insert(
+                    "id".to_string(),
+                    JsonValue::from(format!("{:x}", node.node_id)),
+                );
+                properties.insert("display_name".to_string(), JsonValue::from(display_name));
+                properties.insert(
+                    "long_name".to_string(),
+                    JsonValue::from(node.long_name.clone()),
+                );
+                properties.insert(
+                    "updated_at".to_string(),
+                    JsonValue::from(node.updated_at as f64 / 1_000_000_000.0),
+                );
 
-                    Some(GeoJson::Feature(Feature {
+                Some(
+                    GeoJson::Feature(Feature {
                         geometry: Some(geometry),
                         properties: Some(properties),
                         ..Default::default()
-                    }).to_string())
-                } else {
-                    None
-                };
+                    })
+                    .to_string(),
+                )
+            } else {
+                None
+            };
 
-                let template = NodeTemplate { node, geom };
+            let (node_id, updated_at) = (node.node_id, node.updated_at);
+            let template = NodeTemplate { node, geom };
 
-                if let Ok(data) = template.render() {
-                    yield Ok(Event::default().event("update-node").data(data))
-                }
+            if let Ok(data) = template.render() {
+                event_hub.publish_node(node_id, updated_at, data);
             }
-
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
-    })
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
 }
 
-fn mesh_packet_stream(
-    pool: State<SqlitePool>,
-    hide_private_messages: bool,
-) -> Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>> {
+/// Publishes the packets received since the last poll, every second. The lists start with the
+/// latest packets in the database, archived months are only read on request.
+async fn publish_mesh_packets(pool: DbPool, event_hub: EventHub, hide_private_messages: bool) {
     let mut last_id: i64 = 0;
 
-    Box::pin(stream! {
-        // Delay by half a second to allow nodes to load first
-        tokio::time::sleep(Duration::from_millis(500)).await;
-
-        loop {
-            let packets : Result<Vec<MeshPacketDto>, sqlx::Error> = sqlx::query_as(
+    loop {
+        let packets: Result<Vec<MeshPacketDto>, sqlx::Error> = on_db!(pool, |pool| {
+            sqlx::query_as(
                 r#"
                 SELECT
                     id,
@@ -252,14 +508,14 @@ fn mesh_packet_stream(
                 FROM mesh_packets
                 WHERE id IN (
                     SELECT id FROM mesh_packets
-                    WHERE id > ?1
-                    AND duplicate_of_mesh_packet_id IS NULL
+                    WHERE id > $1
+                    AND duplicate_of_mesh_packet_id IS NULL AND hidden = 0
                     ORDER BY id DESC
                     LIMIT 100
                 ) OR id IN (
                     SELECT id FROM mesh_packets
-                    WHERE id > ?1 AND portnum = ?2
-                    AND duplicate_of_mesh_packet_id IS NULL
+                    WHERE id > $1 AND portnum = $2
+                    AND duplicate_of_mesh_packet_id IS NULL AND hidden = 0
                     ORDER BY id DESC
                     LIMIT 100
                 )
@@ -268,235 +524,336 @@ fn mesh_packet_stream(
             )
             .bind(last_id)
             .bind(Into::<i32>::into(PortNum::TextMessageApp))
-            .fetch_all(&*pool)
-                .await;
+            .fetch_all(pool)
+            .await
+        });
 
-            if let Err(err) = packets {
+        let packets = match packets {
+            Ok(packets) => packets,
+            Err(err) => {
                 error!("Error occurred while fetching packets: {}", err);
-                break;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
             }
-
-            let packets = packets.unwrap_or_default();
-
-            let packet_ids = packets.iter().map(|p| p.id).collect_vec();
-            let packet_ids_string = packet_ids.iter().join(",");
-
-            let compute_waypoints = || {
-                async {
-                let query = format!(
-                    r#"SELECT mesh_packet_id, latitude, longitude, expire, locked_to, name, description, icon FROM waypoints WHERE mesh_packet_id IN ({})"#,
-                    packet_ids_string
-                );
-                sqlx::query_as::<_, WaypointSelectResult>(&query)
-                .fetch_all(&*pool)
-                .map(|waypoints|
-                    if let Ok(waypoints) = waypoints {
-                        waypoints.into_iter().map(|wp| (wp.mesh_packet_id, wp)).collect()
-                    } else {
-                        Default::default()
-                    }
-                ).await
+        };
+
+        let packet_ids = packets.iter().map(|p| p.id).collect_vec();
+        let packet_ids_string = packet_ids.iter().join(",");
+
+        let compute_waypoints = || async {
+            let query = format!(
+                r#"SELECT mesh_packet_id, latitude, longitude, expire, locked_to, name, description, icon FROM waypoints WHERE mesh_packet_id IN ({})"#,
+                packet_ids_string
+            );
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<_, WaypointSelectResult>(
+                    &query
+                )
+                .fetch_all(pool)
+                .await)
             }
-            };
-            let compute_positions = || {
-                async {
-                let query = format!(
-                    r#"SELECT mesh_packet_id, latitude, longitude, altitude, sats_in_view, precision_bits, ground_speed, ground_track, seq_number FROM positions WHERE mesh_packet_id IN ({})"#,
-                     packet_ids_string
-                );
-                sqlx::query_as::<_, PositionSelectResult>(&query)
-                .fetch_all(&*pool)
-                 .map(|positions|
-                    if let Ok(positions) = positions {
-                        positions.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
-                    } else {
-                        Default::default()
-                    }
-                 ).await
+            .map(|waypoints| {
+                if let Ok(waypoints) = waypoints {
+                    waypoints
+                        .into_iter()
+                        .map(|wp| (wp.mesh_packet_id, wp))
+                        .collect()
+                } else {
+                    Default::default()
                 }
-            };
-            let compute_device_metrics = || {
-                async {
-                let query = format!(
-                    r#"SELECT mesh_packet_id, time, battery_level, voltage, channel_utilization, air_util_tx, uptime_seconds FROM device_metrics WHERE mesh_packet_id IN ({})"#,
-                    packet_ids_string
-                );
-                sqlx::query_as::<_, DeviceMetricsSelectResult>(&query)
-                .fetch_all(&*pool)
-                 .map(|metrics|
-                    if let Ok(metrics) = metrics {
-                        metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
-                    } else {
-                        Default::default()
-                    }
-                 ).await
+            })
+            .await
+        };
+        let compute_positions = || async {
+            let query = format!(
+                r#"SELECT mesh_packet_id, latitude, longitude, altitude, sats_in_view, precision_bits, ground_speed, ground_track, seq_number FROM positions WHERE mesh_packet_id IN ({})"#,
+                packet_ids_string
+            );
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<_, PositionSelectResult>(
+                    &query
+                )
+                .fetch_all(pool)
+                .await)
+            }
+            .map(|positions| {
+                if let Ok(positions) = positions {
+                    positions
+                        .into_iter()
+                        .map(|p| (p.mesh_packet_id, p))
+                        .collect()
+                } else {
+                    Default::default()
                 }
-            };
-            let compute_environment_metrics = || {
-                async {
-                let query = format!(
-                    r#"SELECT mesh_packet_id, time, temperature, relative_humidity, barometric_pressure, gas_resistance, iaq FROM environment_metrics WHERE mesh_packet_id IN ({})"#,
-                     packet_ids_string
-                );
-                sqlx::query_as::<_, EnvironmentMetricsSelectResult>(&query)
-                .fetch_all(&*pool)
-                 .map(|metrics|
-                    if let Ok(metrics) = metrics {
-                        metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
-                    } else {
-                        Default::default()
-                    }
-                 ).await
+            })
+            .await
+        };
+        let compute_device_metrics = || async {
+            let query = format!(
+                r#"SELECT mesh_packet_id, time, battery_level, voltage, channel_utilization, air_util_tx, uptime_seconds FROM device_metrics WHERE mesh_packet_id IN ({})"#,
+                packet_ids_string
+            );
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<_, DeviceMetricsSelectResult>(
+                    &query
+                )
+                .fetch_all(pool)
+                .await)
+            }
+            .map(|metrics| {
+                if let Ok(metrics) = metrics {
+                    metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
+                } else {
+                    Default::default()
                 }
-            };
-            let compute_power_metrics = || {
-                async {
-                let query = format!(
-                    r#"SELECT mesh_packet_id, time, ch1_voltage, ch1_current, ch2_voltage, ch2_current, ch3_voltage, ch3_current FROM power_metrics WHERE mesh_packet_id IN ({})"#,
-                     packet_ids_string
-                );
-                sqlx::query_as::<_, PowerMetricsSelectResult>(&query)
-                .fetch_all(&*pool)
-                 .map(|metrics|
-                    if let Ok(metrics) = metrics {
-                        metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
-                    } else {
-                        Default::default()
-                    }
-                 ).await
+            })
+            .await
+        };
+        let compute_environment_metrics = || async {
+            let query = format!(
+                r#"SELECT mesh_packet_id, time, temperature, relative_humidity, barometric_pressure, gas_resistance, iaq FROM environment_metrics WHERE mesh_packet_id IN ({})"#,
+                packet_ids_string
+            );
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<
+                    _,
+                    EnvironmentMetricsSelectResult,
+                >(&query)
+                .fetch_all(pool)
+                .await)
+            }
+            .map(|metrics| {
+                if let Ok(metrics) = metrics {
+                    metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
+                } else {
+                    Default::default()
                 }
-            };
-            let compute_neighbors = || {
-                async {
-                    let query = format!(
+            })
+            .await
+        };
+        let compute_power_metrics = || async {
+            let query = format!(
+                r#"SELECT mesh_packet_id, time, ch1_voltage, ch1_current, ch2_voltage, ch2_current, ch3_voltage, ch3_current FROM power_metrics WHERE mesh_packet_id IN ({})"#,
+                packet_ids_string
+            );
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<_, PowerMetricsSelectResult>(
+                    &query
+                )
+                .fetch_all(pool)
+                .await)
+            }
+            .map(|metrics| {
+                if let Ok(metrics) = metrics {
+                    metrics.into_iter().map(|p| (p.mesh_packet_id, p)).collect()
+                } else {
+                    Default::default()
+                }
+            })
+            .await
+        };
+        let compute_neighbors = || async {
+            let query = format!(
                         "SELECT mesh_packet_id, neighbor_node_id, snr FROM neighbors WHERE mesh_packet_id IN ({}) ORDER BY mesh_packet_id, id",
                         packet_ids_string
                     );
-                    sqlx::query_as::<_, NeighborSelectResult>(&query)
-                    .fetch_all(&*pool)
-                    .map(|neighbors|
-                        if let Ok(neighbors) = neighbors {
-                            neighbors.into_iter().into_group_map_by(|p| p.mesh_packet_id)
-                        } else {
-                            Default::default()
-                        }
-                    ).await
+            async {
+                on_db!(pool, |pool| sqlx::query_as::<_, NeighborSelectResult>(
+                    &query
+                )
+                .fetch_all(pool)
+                .await)
+            }
+            .map(|neighbors| {
+                if let Ok(neighbors) = neighbors {
+                    neighbors
+                        .into_iter()
+                        .into_group_map_by(|p| p.mesh_packet_id)
+                } else {
+                    Default::default()
                 }
-            };
+            })
+            .await
+        };
 
-            // This is a hack, let's improve..
-            let waypoints : OnceCell<HashMap<i64, WaypointSelectResult>> = OnceCell::new();
-            let positions : OnceCell<HashMap<i64, PositionSelectResult>> = OnceCell::new();
-            let device_metrics : OnceCell<HashMap<i64, DeviceMetricsSelectResult>> = OnceCell::new();
-            let environment_metrics: OnceCell<HashMap<i64, EnvironmentMetricsSelectResult>> = OnceCell::new();
-            let power_metrics: OnceCell<HashMap<i64, PowerMetricsSelectResult>> = OnceCell::new();
-            let neighbors: OnceCell<HashMap<i64, Vec<NeighborSelectResult>>> = OnceCell::new();
-
-            last_id = packets.first().map(|p| p.id).unwrap_or(last_id);
-
-            for mut packet in packets.into_iter().rev() {
-                let mut event_type = "mesh-packet";
-                let mut hide_packet = false;
-
-                match PortNum::try_from(packet.portnum) {
-                    Ok(PortNum::TextMessageApp) => {
-                        hide_packet = hide_private_messages && packet.to_id != NODENUM_BROADCAST;
-                        packet.payload = Payload::TextMessage(String::from_utf8(packet.payload_data.clone()).unwrap_or_default());
-                        event_type = "text-message";
-                    }
-                    Ok(PortNum::WaypointApp) => {
-                        if let Some(waypoint) = waypoints.get_or_init(compute_waypoints).await.get(&packet.id) {
-                            packet.payload = Payload::Waypoint(waypoint.clone())
-                        }
+        let compute_traceroute_probes = || async {
+            let query = format!(
+                        "SELECT scheduled, created_at, response_mesh_packet_id, responded_at FROM traceroute_probes WHERE response_mesh_packet_id IN ({})",
+                        packet_ids_string
+                    );
+            async {
+                on_db!(pool, |pool| {
+                    sqlx::query_as::<_, TracerouteProbeSelectResult>(&query)
+                        .fetch_all(pool)
+                        .await
+                })
+            }
+            .map(|probes| {
+                if let Ok(probes) = probes {
+                    probes
+                        .into_iter()
+                        .filter_map(|p| Some((p.response_mesh_packet_id?, p)))
+                        .collect()
+                } else {
+                    Default::default()
+                }
+            })
+            .await
+        };
+
+        // This is a hack, let's improve..
+        let waypoints: OnceCell<HashMap<i64, WaypointSelectResult>> = OnceCell::new();
+        let positions: OnceCell<HashMap<i64, PositionSelectResult>> = OnceCell::new();
+        let device_metrics: OnceCell<HashMap<i64, DeviceMetricsSelectResult>> = OnceCell::new();
+        let environment_metrics: OnceCell<HashMap<i64, EnvironmentMetricsSelectResult>> =
+            OnceCell::new();
+        let power_metrics: OnceCell<HashMap<i64, PowerMetricsSelectResult>> = OnceCell::new();
+        let neighbors: OnceCell<HashMap<i64, Vec<NeighborSelectResult>>> = OnceCell::new();
+        let traceroute_probes: OnceCell<HashMap<i64, TracerouteProbeSelectResult>> =
+            OnceCell::new();
+
+        last_id = packets.first().map(|p| p.id).unwrap_or(last_id);
+
+        for mut packet in packets.into_iter().rev() {
+            let mut is_text_message = false;
+            let mut hide_packet = false;
+
+            match PortNum::try_from(packet.portnum) {
+                Ok(PortNum::TextMessageApp) => {
+                    hide_packet = hide_private_messages && packet.to_id != NODENUM_BROADCAST;
+                    packet.payload = Payload::TextMessage(
+                        String::from_utf8(packet.payload_data.clone()).unwrap_or_default(),
+                    );
+                    is_text_message = true;
+                }
+                Ok(PortNum::WaypointApp) => {
+                    if let Some(waypoint) = waypoints
+                        .get_or_init(compute_waypoints)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::Waypoint(waypoint.clone())
                     }
-                    Ok(PortNum::PositionApp) => {
-                        if let Some(position) = positions.get_or_init(compute_positions).await.get(&packet.id) {
-                            packet.payload = Payload::Position(*position)
-                        }
+                }
+                Ok(PortNum::PositionApp) => {
+                    if let Some(position) = positions
+                        .get_or_init(compute_positions)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::Position(*position)
                     }
-                    Ok(PortNum::TelemetryApp) => {
-                        if let Some(device_metrics) = device_metrics.get_or_init(compute_device_metrics).await.get(&packet.id) {
-                            packet.payload = Payload::DeviceMetrics(device_metrics.clone())
-                        } else if let Some(environment_metrics) = environment_metrics.get_or_init(compute_environment_metrics).await.get(&packet.id) {
-                            packet.payload = Payload::EnvironmentMetrics(environment_metrics.clone())
-                        } else if let Some(power_metrics) = power_metrics.get_or_init(compute_power_metrics).await.get(&packet.id) {
-                            packet.payload = Payload::PowerMetrics(power_metrics.clone())
-                        }
+                }
+                Ok(PortNum::TelemetryApp) => {
+                    if let Some(device_metrics) = device_metrics
+                        .get_or_init(compute_device_metrics)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::DeviceMetrics(device_metrics.clone())
+                    } else if let Some(environment_metrics) = environment_metrics
+                        .get_or_init(compute_environment_metrics)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::EnvironmentMetrics(environment_metrics.clone())
+                    } else if let Some(power_metrics) = power_metrics
+                        .get_or_init(compute_power_metrics)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::PowerMetrics(power_metrics.clone())
                     }
-                    Ok(PortNum::NeighborinfoApp) => {
-                        if let Some(neighbors) = neighbors.get_or_init(compute_neighbors).await.get(&packet.id) {
-                            packet.payload = Payload::Neighbors(neighbors.clone());
-                        }
+                }
+                Ok(PortNum::NeighborinfoApp) => {
+                    if let Some(neighbors) = neighbors
+                        .get_or_init(compute_neighbors)
+                        .await
+                        .get(&packet.id)
+                    {
+                        packet.payload = Payload::Neighbors(neighbors.clone());
                     }
-                    Ok(PortNum::TracerouteApp) => {
-                        if let Ok(route_discovery) = RouteDiscovery::decode(&*packet.payload_data) {
-                            let is_response = !packet.want_response;
-
-                            let (from_id, to_id) = if is_response {
-                                (packet.to_id, packet.from_id)
-                            } else {
-                                (packet.from_id, packet.to_id)
-                            };
-
-                            packet.payload = Payload::Traceroute(TracerouteDto {
-                                from_id,
-                                to_id,
-                                is_response,
-                                route: route_discovery.route,
-                                route_back: route_discovery.route_back,
-                                snr_towards: route_discovery.snr_towards,
-                                snr_back: route_discovery.snr_back,
-                            });
-                        }
+                }
+                Ok(PortNum::TracerouteApp) => {
+                    if let Ok(route_discovery) = RouteDiscovery::decode(&*packet.payload_data) {
+                        let is_response = !packet.want_response;
+                        let round_trip_seconds = if is_response {
+                            traceroute_probes
+                                .get_or_init(compute_traceroute_probes)
+                                .await
+                                .get(&packet.id)
+                                .and_then(|p| p.round_trip_seconds())
+                        } else {
+                            None
+                        };
+
+                        let (from_id, to_id) = if is_response {
+                            (packet.to_id, packet.from_id)
+                        } else {
+                            (packet.from_id, packet.to_id)
+                        };
+
+                        packet.payload = Payload::Traceroute(TracerouteDto {
+                            from_id,
+                            to_id,
+                            is_response,
+                            route: route_discovery.route,
+                            route_back: route_discovery.route_back,
+                            snr_towards: route_discovery.snr_towards,
+                            snr_back: route_discovery.snr_back,
+                            round_trip_seconds,
+                        });
                     }
-                    Ok(PortNum::RoutingApp) => {
-                        if let Ok(Some(routing_variant)) = Routing::decode(&*packet.payload_data).map(|r| r.variant) {
-                            let (variant_name, error_reason) = match routing_variant {
-                                routing::Variant::RouteRequest(_) => ("Route request", None),
-                                routing::Variant::RouteReply(_) => ("Route reply", None),
-                                routing::Variant::ErrorReason(error) => {
-                                    let error = routing::Error::try_from(error).map(|error| capitalize(error.as_str_name().replace('_'," ").as_str()))
-                                   .unwrap_or_else(|_| "Unknown".to_string());
-                                    ("Error reason", Some(error))
-                                },
-                            };
-
-                            packet.payload = Payload::Routing(RoutingDto {
-                                variant_name: variant_name.to_string(),
-                                error_reason
-                            })
-                        }
+                }
+                Ok(PortNum::RoutingApp) => {
+                    if let Ok(Some(routing_variant)) =
+                        Routing::decode(&*packet.payload_data).map(|r| r.variant)
+                    {
+                        let (variant_name, error_reason) = match routing_variant {
+                            routing::Variant::RouteRequest(_) => ("Route request", None),
+                            routing::Variant::RouteReply(_) => ("Route reply", None),
+                            routing::Variant::ErrorReason(error) => {
+                                let error = routing::Error::try_from(error)
+                                    .map(|error| {
+                                        capitalize(error.as_str_name().replace('_', " ").as_str())
+                                    })
+                                    .unwrap_or_else(|_| "Unknown".to_string());
+                                ("Error reason", Some(error))
+                            }
+                        };
+
+                        packet.payload = Payload::Routing(RoutingDto {
+                            variant_name: variant_name.to_string(),
+                            error_reason,
+                        })
                     }
-                    _ => {}
                 }
-                if !hide_packet {
-                    let template = PacketTemplate { packet };
+                _ => {}
+            }
+            if !hide_packet {
+                let id = packet.id;
+                let template = PacketTemplate { packet };
 
-                    if let Ok(data) = template.render() {
-                        yield Ok(Event::default().event(event_type).data(data))
+                if let Ok(data) = template.render() {
+                    if is_text_message {
+                        event_hub.publish_text_message(id, data);
+                    } else {
+                        event_hub.publish_packet(id, data);
                     }
                 }
             }
-
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
-    })
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
 }
 
+/// New clients get the current nodes, the latest packets and the statistics, followed by the
+/// updates.
 async fn sse_handler(
-    pool: State<SqlitePool>,
-    web_config: State<WebConfig>,
+    State(event_hub): State<EventHub>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let update_stream = select_all(
-        vec![
-            node_update_stream(pool.clone()),
-            mesh_packet_stream(pool.clone(), web_config.hide_private_messages),
-            stats_update_stream(pool),
-        ]
-        .into_iter(),
-    );
-
-    Sse::new(update_stream).keep_alive(
+    Sse::new(event_hub.subscribe()).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(15))
             .text("alive"),
@@ -549,7 +906,7 @@ struct MaxAge {
 }
 
 async fn node_positions_geojson(
-    pool: State<SqlitePool>,
+    pool: State<DbPool>,
     Path((node_id,)): Path<(String,)>,
     max_age: axum::extract::Query<MaxAge>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -562,7 +919,117 @@ async fn node_positions_geojson(
         0
     };
 
-    let query_res: Vec<MeshPacketDto> = sqlx::query(
+    let mut conn = pool.acquire().await.map_err(DatabaseError)?;
+    let mut query_res =
+        select_positions(&mut conn.executor(), "", node_id, max_age_time_nanos, 250)
+            .await
+            .map_err(DatabaseError)?;
+    // The track continues in the archives when the database holds fewer positions
+    if let Db::Sqlite(conn) = &mut conn {
+        select_archived_positions(conn, node_id, max_age_time_nanos, &mut query_res)
+            .await
+            .map_err(archive_error)?;
+    }
+
+    let res = GeoJson::FeatureCollection(query_res.into_iter().collect()).to_string();
+
+    Ok(([(header::CONTENT_TYPE, "application/geo+json")], res))
+}
+
+/// The map shows the neighbor links of the last 8 hours by default, and of at most the last week.
+const DEFAULT_NEIGHBOR_LINKS_MAX_AGE_MINUTES: u32 = 8 * 60;
+const NEIGHBOR_LINKS_MAX_AGE_MINUTES: u32 = 7 * 24 * 60;
+
+/// The neighbor links of the visible nodes seen in the last `max_age` minutes, by node id.
+async fn neighbor_links_json(
+    pool: State<DbPool>,
+    max_age: axum::extract::Query<MaxAge>,
+) -> Result<impl IntoResponse, DatabaseError> {
+    let max_age = max_age
+        .max_age
+        .unwrap_or(DEFAULT_NEIGHBOR_LINKS_MAX_AGE_MINUTES)
+        .clamp(1, NEIGHBOR_LINKS_MAX_AGE_MINUTES);
+    let since = Utc::now().timestamp_nanos_opt().unwrap() - max_age as i64 * 60_000_000_000;
+
+    let query = pool.pick(
+        r#"
+            SELECT printf('%x', node_id), printf('%x', neighbor_node_id), snr, last_seen / 1000000000
+            FROM neighbor_links
+            WHERE last_seen > $1 AND node_id IN (SELECT node_id FROM nodes WHERE hidden = 0)
+            ORDER BY last_seen DESC
+        "#,
+        r#"
+            SELECT to_hex(node_id), to_hex(neighbor_node_id), snr, last_seen / 1000000000
+            FROM neighbor_links
+            WHERE last_seen > $1 AND node_id IN (SELECT node_id FROM nodes WHERE hidden = 0)
+            ORDER BY last_seen DESC
+        "#,
+    );
+    let links: Vec<(String, String, Option<f64>, i64)> = on_db!(pool, |pool| {
+        sqlx::query_as(query).bind(since).fetch_all(pool).await
+    })
+    .map_err(DatabaseError)?;
+
+    let mut links_by_node = Map::new();
+    for (node_id, neighbor, snr, timestamp) in links {
+        if let JsonValue::Array(node_links) = links_by_node
+            .entry(node_id)
+            .or_insert_with(|| JsonValue::Array(Vec::new()))
+        {
+            node_links.push(json!({
+                "neighbor": neighbor,
+                "snr": snr,
+                "timestamp": timestamp,
+            }));
+        }
+    }
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/json")],
+        JsonValue::Object(links_by_node).to_string(),
+    ))
+}
+
+/// Adds the positions of a node from the archives, newest first, up to 250 positions.
+async fn select_archived_positions(
+    conn: &mut SqliteConnection,
+    node_id: u32,
+    since: i64,
+    positions: &mut Vec<MeshPacketDto>,
+) -> anyhow::Result<()> {
+    for path in archive::archive_files_after(since)? {
+        let limit = 250 - positions.len() as i64;
+        if limit <= 0 {
+            break;
+        }
+
+        archive::attach(conn, &path).await?;
+        let archived = select_positions(
+            &mut Db::Sqlite(&mut *conn),
+            "archive.",
+            node_id,
+            since,
+            limit,
+        )
+        .await;
+        archive::detach(conn).await?;
+
+        positions.extend(archived?);
+    }
+
+    Ok(())
+}
+
+/// Selects the latest positions of a node from the database, or an attached archive with the
+/// `archive.` prefix.
+async fn select_positions(
+    conn: &mut DbConnection<'_>,
+    prefix: &str,
+    node_id: u32,
+    since: i64,
+    limit: i64,
+) -> sqlx::Result<Vec<MeshPacketDto>> {
+    let query = format!(
         r#"
             SELECT
                 mesh_packets.id,
@@ -589,34 +1056,35 @@ async fn node_positions_geojson(
                 positions.ground_speed,
                 positions.seq_number,
                 positions.ground_track
-            FROM positions
-            JOIN mesh_packets ON positions.mesh_packet_id = mesh_packets.id
-            WHERE node_id = ? AND created_at > ?
+            FROM {prefix}positions
+            JOIN {prefix}mesh_packets ON positions.mesh_packet_id = mesh_packets.id
+            WHERE node_id = $1 AND created_at > $2
             ORDER BY created_at DESC
-            LIMIT 250
-            "#,
-    )
-    .bind(node_id)
-    .bind(max_age_time_nanos)
-    .fetch_all(&*pool)
-    .await
-    .map_err(DatabaseError)?
-    .into_iter()
-    .filter_map(|row| {
-        MeshPacketDto::from_row(&row)
-            .and_then(|mut packet| {
-                PositionSelectResult::from_row(&row).map(|position| {
-                    packet.payload = Payload::Position(position);
-                    packet
-                })
+            LIMIT $3
+        "#
+    );
+    let positions = on_db!(conn, |conn| {
+        sqlx::query(&query)
+            .bind(i64::from(node_id))
+            .bind(since)
+            .bind(limit)
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                MeshPacketDto::from_row(&row)
+                    .and_then(|mut packet| {
+                        PositionSelectResult::from_row(&row).map(|position| {
+                            packet.payload = Payload::Position(position);
+                            packet
+                        })
+                    })
+                    .ok()
             })
-            .ok()
-    })
-    .collect_vec();
-
-    let res = GeoJson::FeatureCollection(query_res.into_iter().collect()).to_string();
+            .collect_vec()
+    });
 
-    Ok(([(header::CONTENT_TYPE, "application/geo+json")], res))
+    Ok(positions)
 }
 
 #[derive(Deserialize)]
@@ -625,16 +1093,17 @@ struct NodeDetailsQueryParams {
 }
 
 async fn node_details(
-    pool: State<SqlitePool>,
+    pool: State<DbPool>,
+    State(downlink): State<Option<Downlink>>,
     Path((node_id,)): Path<(String,)>,
     query: axum::extract::Query<NodeDetailsQueryParams>,
     headers: HeaderMap,
 ) -> axum::response::Result<impl IntoResponse> {
     let node_id = i64::from_str_radix(&node_id, 16).map_err(stringify)? as i64;
     // TODO: improve query to match neighbor node updates or setup new struct.
-    let node = sqlx::query_as!(
-        NodeSelectResult,
-        r#"
+    let node = on_db!(pool, |pool| {
+        sqlx::query_as::<_, NodeSelectResult>(
+            r#"
         SELECT
             node_id,
             user_id,
@@ -661,17 +1130,17 @@ async fn node_details(
             latitude,
             longitude,
             altitude,
-            '[]' AS "neighbor_json: String",
             updated_at,
             public_key,
             is_unmessagable
         FROM nodes
-        WHERE node_id = ?
+        WHERE node_id = $1
         "#,
-        node_id
-    )
-    .fetch_one(&*pool)
-    .await
+        )
+        .bind(node_id)
+        .fetch_one(pool)
+        .await
+    })
     .map_err(DatabaseError)?;
 
     let max_age_minutes: Option<i64> = headers
@@ -698,19 +1167,64 @@ async fn node_details(
         FixedOffset::west_opt(0).unwrap()
     };
 
-    let gateway_packet_info: Vec<GatewayPacketInfo> = sqlx::query_as!(
-        GatewayPacketInfo,
-        r#"
-            SELECT
-                gateway_id as "gateway_id!",
-                COUNT(*) as "num_packets!: i64"
-            FROM mesh_packets
-            WHERE from_id = ?1 AND created_at > ?2 AND gateway_id IS NOT NULL GROUP BY 1 ORDER BY 2 DESC LIMIT 50
-        "#,
-        node_id, min_time_nanos
-    )
-    .fetch_all(&*pool)
-    .await
+    let mut conn = pool.acquire().await.map_err(DatabaseError)?;
+    let mut num_packets: HashMap<String, i64> =
+        select_gateway_packets(&mut conn.executor(), "", node_id, min_time_nanos)
+            .await
+            .map_err(DatabaseError)?;
+    // Older packets are counted in the archives
+    if let Db::Sqlite(conn) = &mut conn {
+        count_archived_gateway_packets(conn, node_id, min_time_nanos, &mut num_packets)
+            .await
+            .map_err(archive_error)?;
+    }
+    drop(conn);
+    let gateway_packet_info = num_packets
+        .into_iter()
+        .map(|(gateway_id, num_packets)| GatewayPacketInfo {
+            gateway_id,
+            num_packets,
+        })
+        .sorted_by(|a, b| {
+            b.num_packets
+                .cmp(&a.num_packets)
+                .then_with(|| a.gateway_id.cmp(&b.gateway_id))
+        })
+        .take(50)
+        .collect_vec();
+
+    let traceroute_probes = on_db!(pool, |pool| {
+        sqlx::query_as::<_, TracerouteProbeSelectResult>(
+            r#"
+                SELECT scheduled, created_at, response_mesh_packet_id, responded_at
+                FROM traceroute_probes
+                WHERE to_id = $1
+                ORDER BY created_at DESC
+                LIMIT 10
+            "#,
+        )
+        .bind(node_id)
+        .fetch_all(pool)
+        .await
+    })
+    .map_err(DatabaseError)?;
+    let traceroute_enabled = downlink.is_some();
+
+    let neighbor_links = on_db!(pool, |pool| {
+        sqlx::query_as::<_, NeighborLinkSelectResult>(
+            r#"
+                SELECT neighbor_node_id, snr, first_seen, last_seen, observations
+                FROM neighbor_links
+                WHERE node_id = $1 AND last_seen > $2
+                ORDER BY last_seen DESC
+                LIMIT 50
+            "#,
+        )
+        .bind(node_id)
+        .bind(min_time_nanos)
+        .fetch_all(pool)
+        .await
+    })
     .map_err(DatabaseError)?;
 
     let max_gateway = gateway_packet_info.first();
@@ -735,6 +1249,9 @@ async fn node_details(
                 .unwrap_or_default(),
             gateway_packet_info,
             selected_node: Some(selected_node),
+            traceroute_probes,
+            traceroute_enabled,
+            neighbor_links,
         }))
     } else {
         Ok(into_response(&NodeDetailsTemplate {
@@ -742,61 +1259,321 @@ async fn node_details(
             plots: Vec::new(),
             gateway_packet_info,
             selected_node: None,
+            traceroute_probes,
+            traceroute_enabled,
+            neighbor_links,
         }))
     }
 }
 
+async fn packet_details(
+    pool: State<DbPool>,
+    web_config: State<WebConfig>,
+    Path((packet_id,)): Path<(i64,)>,
+) -> axum::response::Result<impl IntoResponse> {
+    let mut conn = pool.acquire().await.map_err(DatabaseError)?;
+    let packet = match select_packet(&mut conn.executor(), "", packet_id)
+        .await
+        .map_err(DatabaseError)?
+    {
+        Some(packet) => Some(packet),
+        None => match &mut conn {
+            Db::Sqlite(conn) => select_archived_packet(conn, packet_id)
+                .await
+                .map_err(archive_error)?,
+            Db::Postgres(_) => None,
+        },
+    };
+    let mut packet = packet.ok_or((StatusCode::NOT_FOUND, "Packet not found"))?;
+
+    if packet.portnum == PortNum::TextMessageApp as i32 {
+        if web_config.hide_private_messages && packet.to_id != NODENUM_BROADCAST {
+            return Err((StatusCode::NOT_FOUND, "Packet not found").into());
+        }
+        packet.payload = Payload::TextMessage(
+            String::from_utf8(packet.payload_data.clone()).unwrap_or_default(),
+        );
+    }
+
+    Ok(into_response(&PacketDetailsTemplate { packet }))
+}
+
+/// Adds the packets of a node per gateway in the archives to the counts.
+async fn count_archived_gateway_packets(
+    conn: &mut SqliteConnection,
+    node_id: i64,
+    since: i64,
+    num_packets: &mut HashMap<String, i64>,
+) -> anyhow::Result<()> {
+    for path in archive::archive_files_after(since)? {
+        archive::attach(conn, &path).await?;
+        let archived =
+            select_gateway_packets(&mut Db::Sqlite(&mut *conn), "archive.", node_id, since).await;
+        archive::detach(conn).await?;
+
+        for (gateway_id, count) in archived? {
+            *num_packets.entry(gateway_id).or_default() += count;
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the packets of a node per gateway in the database, or an attached archive with the
+/// `archive.` prefix.
+async fn select_gateway_packets(
+    conn: &mut DbConnection<'_>,
+    prefix: &str,
+    node_id: i64,
+    since: i64,
+) -> sqlx::Result<HashMap<String, i64>> {
+    let query = format!(
+        r#"
+            SELECT gateway_id, COUNT(*) AS num_packets
+            FROM {prefix}mesh_packets
+            WHERE from_id = $1 AND created_at > $2 AND gateway_id IS NOT NULL GROUP BY 1
+        "#
+    );
+    let counts: Vec<GatewayPacketInfo> = on_db!(conn, |conn| {
+        sqlx::query_as(&query)
+            .bind(node_id)
+            .bind(since)
+            .fetch_all(conn)
+            .await
+    })?;
+
+    Ok(counts
+        .into_iter()
+        .map(|info| (info.gateway_id, info.num_packets))
+        .collect())
+}
+
+fn archive_error(err: anyhow::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Archive Error: {:?}", err),
+    )
+}
+
+/// Looks for a packet in the archives, newest first.
+async fn select_archived_packet(
+    conn: &mut SqliteConnection,
+    packet_id: i64,
+) -> anyhow::Result<Option<MeshPacketDto>> {
+    for (_, path) in archive::archive_files()?.into_iter().rev() {
+        archive::attach(conn, &path).await?;
+        let packet = select_packet(&mut Db::Sqlite(&mut *conn), "archive.", packet_id).await;
+        archive::detach(conn).await?;
+
+        if let Some(packet) = packet? {
+            return Ok(Some(packet));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Selects a packet with its waypoint from the database, or an attached archive with the
+/// `archive.` prefix.
+async fn select_packet(
+    conn: &mut DbConnection<'_>,
+    prefix: &str,
+    packet_id: i64,
+) -> sqlx::Result<Option<MeshPacketDto>> {
+    let query = format!(
+        r#"
+            SELECT
+                id,
+                from_id,
+                to_id,
+                gateway_id,
+                portnum,
+                rx_time,
+                hop_start,
+                hop_limit,
+                rx_snr,
+                rx_rssi,
+                priority,
+                want_ack,
+                want_response,
+                payload_data,
+                created_at,
+                received_at
+            FROM {prefix}mesh_packets
+            WHERE id = $1 AND hidden = 0
+        "#
+    );
+    let packet: Option<MeshPacketDto> = on_db!(conn, |conn| {
+        sqlx::query_as(&query)
+            .bind(packet_id)
+            .fetch_optional(conn)
+            .await
+    })?;
+
+    let Some(mut packet) = packet else {
+        return Ok(None);
+    };
+
+    if packet.portnum == PortNum::WaypointApp as i32 {
+        let query = format!(
+            "SELECT mesh_packet_id, latitude, longitude, expire, locked_to, name, description, icon FROM {prefix}waypoints WHERE mesh_packet_id = $1"
+        );
+        let waypoint: Option<WaypointSelectResult> = on_db!(conn, |conn| {
+            sqlx::query_as(&query)
+                .bind(packet_id)
+                .fetch_optional(conn)
+                .await
+        })?;
+
+        if let Some(waypoint) = waypoint {
+            packet.payload = Payload::Waypoint(waypoint);
+        }
+    }
+
+    Ok(Some(packet))
+}
+
+#[derive(Deserialize)]
+struct SearchQueryParams {
+    q: Option<String>,
+}
+
+async fn search_results(
+    pool: State<DbPool>,
+    web_config: State<WebConfig>,
+    query: axum::extract::Query<SearchQueryParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    let Some(expression) = query
+        .q
+        .as_deref()
+        .and_then(|input| search::expression(&pool, input))
+    else {
+        return Ok(into_response(&SearchResultsTemplate {
+            searched: false,
+            nodes: Vec::new(),
+            packets: Vec::new(),
+        }));
+    };
+
+    let nodes = search::search_nodes(&pool, &expression)
+        .await
+        .map_err(DatabaseError)?;
+    let packets = search::search_packets(&pool, &expression, web_config.hide_private_messages)
+        .await
+        .map_err(DatabaseError)?;
+
+    Ok(into_response(&SearchResultsTemplate {
+        searched: true,
+        nodes,
+        packets,
+    }))
+}
+
 fn plot_labels() -> &'static Vec<(&'static str, &'static str)> {
     static PLOT_LABELS: OnceLock<Vec<(&'static str, &'static str)>> = OnceLock::new();
     PLOT_LABELS.get_or_init(|| {
         Vec::from([
-            ("R", "RX RSSI"),
-            ("S", "RX SNR"),
-            ("C", "Channel utilization"),
-            ("A", "Air util tx"),
-            ("V", "Voltage"),
-            ("T", "Temperature"),
-            ("H", "Relative humidity"),
-            ("B", "Barometric pressure"),
+            ("rx_rssi", "RX RSSI"),
+            ("rx_snr", "RX SNR"),
+            ("channel_utilization", "Channel utilization"),
+            ("air_util_tx", "Air util tx"),
+            ("voltage", "Voltage"),
+            ("temperature", "Temperature"),
+            ("relative_humidity", "Relative humidity"),
+            ("barometric_pressure", "Barometric pressure"),
         ])
     })
 }
 
+/// A value with the min and max of its bucket, for the rolled up resolutions.
+type PlotEntry = (DateTime<FixedOffset>, f64, Option<(f64, f64)>);
+
 async fn create_plots(
-    pool: State<SqlitePool>,
+    pool: State<DbPool>,
     node_id: i64,
     min_time: i64,
     time_zone_offset: FixedOffset,
     gateway_id: &String,
 ) -> axum::response::Result<Vec<PlotData>> {
-    let mut entries_map : HashMap<String, Vec<(DateTime<FixedOffset>, f64)>>= sqlx::query!(
-        r#"
-            SELECT * FROM (SELECT 'R' as "plot_type!: String", rx_time as "time!", CAST(rx_rssi AS REAL) AS "value!: f64" FROM mesh_packets WHERE from_id = ?1 AND rx_time IS NOT NULL AND rx_time > ?2 AND rx_rssi != 0 AND gateway_id = ?3 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'S' as "plot_type!: String", rx_time as "time!", rx_snr AS "value!: f64" FROM mesh_packets WHERE from_id = ?1 AND rx_time IS NOT NULL AND rx_time > ?2 AND rx_snr != 0 AND gateway_id = ?3 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'C' as "plot_type!: String", time as "time!", channel_utilization AS "value!: f64" FROM device_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND channel_utilization > 0 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'A' as "plot_type!: String", time as "time!", air_util_tx AS "value!: f64" FROM device_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND air_util_tx > 0 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'V' as "plot_type!: String", time as "time!", voltage AS "value!: f64" FROM device_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND voltage > 0 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'T' as "plot_type!: String", time as "time!", temperature AS "value!: f64" FROM environment_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND temperature > -100 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'H' as "plot_type!: String", time as "time!", relative_humidity AS "value!: f64" FROM environment_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND relative_humidity > 0 ORDER BY "time!" DESC LIMIT 100)
-            UNION ALL SELECT * FROM (SELECT 'B' as "plot_type!: String", time as "time!", barometric_pressure AS "value!: f64" FROM environment_metrics WHERE node_id = ?1 AND time IS NOT NULL AND time > ?2 AND barometric_pressure > 0 ORDER BY "time!" DESC LIMIT 100)
-            ORDER BY 1, 2 DESC;
-        "#,
-        node_id, min_time, gateway_id
-    )
-    .fetch_all(&*pool)
-    .await
-    .map_err(DatabaseError)?
-    .into_iter()
-    .rev()
-    .map(|record| (record.plot_type, (Utc.timestamp_nanos(record.time).with_timezone(&time_zone_offset), record.value)))
-    .into_grouping_map()
-    .collect();
+    let to_time = |time: i64| Utc.timestamp_nanos(time).with_timezone(&time_zone_offset);
+    let resolution = Resolution::for_window(min_time, Utc::now().timestamp_nanos_opt().unwrap());
+
+    let mut entries_map: HashMap<String, Vec<PlotEntry>> = if resolution == Resolution::Raw {
+        let records: Vec<(String, i64, f64)> = on_db!(pool, |pool| {
+            sqlx::query_as(
+            r#"
+                SELECT * FROM (SELECT 'rx_rssi' AS metric, rx_time AS time, CAST(rx_rssi AS DOUBLE PRECISION) AS value FROM mesh_packets WHERE from_id = $1 AND rx_time IS NOT NULL AND rx_time > $2 AND rx_rssi != 0 AND gateway_id = $3 AND hidden = 0 ORDER BY time DESC LIMIT 500) AS metric_1
+                UNION ALL SELECT * FROM (SELECT 'rx_snr' AS metric, rx_time AS time, rx_snr AS value FROM mesh_packets WHERE from_id = $1 AND rx_time IS NOT NULL AND rx_time > $2 AND rx_snr != 0 AND gateway_id = $3 AND hidden = 0 ORDER BY time DESC LIMIT 500) AS metric_2
+                UNION ALL SELECT * FROM (SELECT 'channel_utilization' AS metric, time AS time, channel_utilization AS value FROM device_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND channel_utilization > 0 ORDER BY time DESC LIMIT 500) AS metric_3
+                UNION ALL SELECT * FROM (SELECT 'air_util_tx' AS metric, time AS time, air_util_tx AS value FROM device_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND air_util_tx > 0 ORDER BY time DESC LIMIT 500) AS metric_4
+                UNION ALL SELECT * FROM (SELECT 'voltage' AS metric, time AS time, voltage AS value FROM device_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND voltage > 0 ORDER BY time DESC LIMIT 500) AS metric_5
+                UNION ALL SELECT * FROM (SELECT 'temperature' AS metric, time AS time, temperature AS value FROM environment_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND temperature > -100 ORDER BY time DESC LIMIT 500) AS metric_6
+                UNION ALL SELECT * FROM (SELECT 'relative_humidity' AS metric, time AS time, relative_humidity AS value FROM environment_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND relative_humidity > 0 ORDER BY time DESC LIMIT 500) AS metric_7
+                UNION ALL SELECT * FROM (SELECT 'barometric_pressure' AS metric, time AS time, barometric_pressure AS value FROM environment_metrics WHERE node_id = $1 AND time IS NOT NULL AND time > $2 AND EXISTS (SELECT 1 FROM mesh_packets WHERE id = mesh_packet_id AND hidden = 0) AND barometric_pressure > 0 ORDER BY time DESC LIMIT 500) AS metric_8
+                ORDER BY 1, 2 DESC
+            "#,
+            )
+            .bind(node_id)
+            .bind(min_time)
+            .bind(gateway_id)
+            .fetch_all(pool)
+            .await
+        })
+        .map_err(DatabaseError)?;
+
+        records
+            .into_iter()
+            .rev()
+            .map(|(metric, time, value)| (metric, (to_time(time), value, None)))
+            .into_grouping_map()
+            .collect()
+    } else {
+        // Buckets starting before the window are included, they overlap it
+        let bucket_nanos = resolution.bucket_nanos();
+        let min_bucket = min_time - bucket_nanos;
+
+        let records: Vec<(String, i64, f64, f64, f64)> = on_db!(pool, |pool| {
+            sqlx::query_as(
+            r#"
+                SELECT metric, bucket, sum_value / count, min_value, max_value FROM telemetry_rollups_hourly
+                WHERE $4 = 3600000000000 AND node_id = $1 AND bucket > $2 AND gateway_id IN ('', $3)
+                UNION ALL SELECT metric, bucket, sum_value / count, min_value, max_value FROM telemetry_rollups_daily
+                WHERE $4 = 86400000000000 AND node_id = $1 AND bucket > $2 AND gateway_id IN ('', $3)
+                ORDER BY 1, 2
+            "#,
+            )
+            .bind(node_id)
+            .bind(min_bucket)
+            .bind(gateway_id)
+            .bind(bucket_nanos)
+            .fetch_all(pool)
+            .await
+        })
+        .map_err(DatabaseError)?;
+
+        records
+            .into_iter()
+            .map(|(metric, time, value, min, max)| {
+                (metric, (to_time(time), value, Some((min, max))))
+            })
+            .into_grouping_map()
+            .collect()
+    };
 
     Ok(plot_labels()
         .iter()
-        .filter_map(|(plot_type, label)| {
-            entries_map.remove(*plot_type).and_then(|entries| {
-                util::plot::plot_timeseries_svg(label, entries)
+        .filter_map(|(metric, label)| {
+            entries_map.remove(*metric).and_then(|entries| {
+                let bounds = entries
+                    .iter()
+                    .filter_map(|(time, _, bounds)| bounds.map(|(min, max)| (*time, min, max)))
+                    .collect_vec();
+                let entries = entries
+                    .into_iter()
+                    .map(|(time, value, _)| (time, value))
+                    .collect_vec();
+
+                util::plot::plot_timeseries_svg(label, entries, bounds)
                     .ok()
                     .map(|svg| PlotData {
                         label: label.to_string(),
@@ -994,7 +1771,11 @@ async fn style_json(web_config: State<WebConfig>) -> impl IntoResponse {
     )
 }
 
-pub async fn start_server(pool: SqlitePool, http_addr: String) -> anyhow::Result<()> {
+pub async fn start_server(
+    pool: DbPool,
+    database_url: String,
+    http_addr: String,
+) -> anyhow::Result<()> {
     let web_config = WebConfig {
         pmtiles_url: get_config().get_string("pmtiles_url").ok(),
         map_glyphs_url: get_config()
@@ -1006,6 +1787,28 @@ pub async fn start_server(pool: SqlitePool, http_addr: String) -> anyhow::Result
     };
     info!("Starting web server @ {}", http_addr);
 
+    let read_pool = util::connect_to_db_read_only(&database_url).await?;
+    let downlink = Downlink::from_config()?;
+
+    // The updates are queried and rendered once for all clients
+    let event_hub = EventHub::default();
+    tokio::spawn(publish_node_updates(read_pool.clone(), event_hub.clone()));
+    tokio::spawn(publish_mesh_packets(
+        read_pool.clone(),
+        event_hub.clone(),
+        web_config.hide_private_messages,
+    ));
+    tokio::spawn(publish_stats(read_pool.clone(), event_hub.clone()));
+
+    if let Some(downlink) = downlink.clone() {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            if let Err(err) = traceroute::start_scheduler(pool, downlink).await {
+                error!("Scheduled traceroutes stopped: {:?}", err);
+            }
+        });
+    }
+
     // build our application with a single route
     let app = Router::new()
         .route("/", get(index))
@@ -1014,14 +1817,26 @@ pub async fn start_server(pool: SqlitePool, http_addr: String) -> anyhow::Result
             "/node/{node_id}/positions.geojson",
             get(node_positions_geojson),
         )
+        .route("/neighbor_links.json", get(neighbor_links_json))
         .route("/node/{node_id}/details.html", get(node_details))
+        .route("/node/{node_id}/traceroute", post(send_traceroute))
+        .route("/packet/{packet_id}/details.html", get(packet_details))
+        .route("/search", get(search_results))
+        .route("/messages", post(send_message))
+        .route("/api/ingest", post(ingest))
         .route("/map/style.json", get(style_json))
         .route("/static/{*file}", get(static_handler))
         .fallback_service(get(not_found))
         .layer(CompressionLayer::new())
         .layer(TraceLayer::new_for_http())
         // Create the application state
-        .with_state(AppState { pool, web_config });
+        .with_state(AppState {
+            pool: read_pool,
+            write_pool: WritePool(pool),
+            web_config,
+            downlink,
+            event_hub,
+        });
 
     let listener = TcpListener::bind(&http_addr).await?;
     info!("Listening on {}", &http_addr);
@@ -1055,3 +1870,87 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
 async fn not_found() -> Html<&'static str> {
     Html("<h1>404</h1><p>Not Found</p>")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        values
+            .iter()
+            .map(|(name, value)| (*name, HeaderValue::from_static(value)))
+            .fold(HeaderMap::new(), |mut headers, (name, value)| {
+                headers.insert(name, value);
+                headers
+            })
+    }
+
+    #[test]
+    fn one_topic_applies_to_all_envelopes() {
+        let headers = headers(&[("x-meshstellar-topic", "msh/EU_868/2/e/a,b/!deadbeef")]);
+
+        assert_eq!(
+            topics(&headers, 2).unwrap(),
+            vec![Some("msh/EU_868/2/e/a,b/!deadbeef".to_string()); 2]
+        );
+        assert_eq!(topics(&HeaderMap::new(), 2).unwrap(), vec![None, None]);
+    }
+
+    #[test]
+    fn topics_per_envelope_are_percent_decoded() {
+        let headers = headers(&[(
+            "x-meshstellar-topics",
+            "msh/e/a%2Cb/!deadbeef,,msh/e/%C3%A9",
+        )]);
+
+        assert_eq!(
+            topics(&headers, 3).unwrap(),
+            vec![
+                Some("msh/e/a,b/!deadbeef".to_string()),
+                None,
+                Some("msh/e/é".to_string())
+            ]
+        );
+        assert!(topics(&headers, 2).is_err());
+    }
+
+    #[test]
+    fn both_topic_headers_are_rejected() {
+        let headers = headers(&[
+            ("x-meshstellar-topic", "msh/e"),
+            ("x-meshstellar-topics", "msh/e"),
+        ]);
+
+        assert!(topics(&headers, 1).is_err());
+    }
+
+    #[test]
+    fn received_at_has_one_value_or_one_per_envelope() {
+        let single = headers(&[("x-meshstellar-received-at", "2025-10-18T12:00:00Z")]);
+        assert_eq!(
+            header_values(&single, "x-meshstellar-received-at", 2).unwrap(),
+            vec![Some("2025-10-18T12:00:00Z".to_string()); 2]
+        );
+
+        let list = headers(&[("x-meshstellar-received-at", "a, b,c")]);
+        assert!(header_values(&list, "x-meshstellar-received-at", 2).is_err());
+        assert_eq!(
+            header_values(&list, "x-meshstellar-received-at", 3).unwrap(),
+            vec![
+                Some("a".to_string()),
+                Some("b".to_string()),
+                Some("c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn length_delimited_bodies_are_split() {
+        assert_eq!(
+            split_length_delimited(&[2, 1, 2, 1, 3]).unwrap(),
+            vec![&[1u8, 2][..], &[3][..]]
+        );
+        assert!(split_length_delimited(&[3, 1, 2]).is_err());
+    }
+}
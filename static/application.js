@@ -135,8 +135,8 @@ function loadingFinished() {
     }
 }
 
-function getMaxAgeInMinutes() {
-    const rawValue = localStorage.getItem('maxAge');
+function getStoredMinutes(key, defaultMinutes) {
+    const rawValue = localStorage.getItem(key);
 
     if (rawValue == 'all') {
         return null;
@@ -147,13 +147,40 @@ function getMaxAgeInMinutes() {
     if (isFinite(parsedValue)) {
         return parsedValue;
     } else {
-        localStorage.removeItem('maxAge');
+        localStorage.removeItem(key);
 
-        // Default to 60 minutes
-        return 60;
+        return defaultMinutes;
     }
 }
 
+function getMaxAgeInMinutes() {
+    // Default to 60 minutes
+    return getStoredMinutes('maxAge', 60);
+}
+
+function getNeighborMaxAgeInMinutes() {
+    // Neighbor info is sent every few hours, default to 8 hours. The longest period to choose
+    // is 7 days, a stored choice of all links gets the default as well.
+    if (localStorage.getItem('neighborMaxAge') == 'all') {
+        localStorage.removeItem('neighborMaxAge');
+    }
+
+    return getStoredMinutes('neighborMaxAge', 480);
+}
+
+// The neighbor links of the chosen period by node id
+let neighborLinks = {};
+
+function fetchNeighborLinks() {
+    fetch('/neighbor_links.json?max_age=' + getNeighborMaxAgeInMinutes())
+        .then((response) => response.json())
+        .then((links) => {
+            neighborLinks = links;
+            updateNodeGeoJSON();
+        })
+        .catch((err) => console.error('Failed to fetch the neighbor links', err));
+}
+
 function _updateNodeGeoJSON() {
     _refreshOnlineState();
 
@@ -164,14 +191,12 @@ function _updateNodeGeoJSON() {
 
     const nodeData = nodes
         .map((node) => {
-            let neighbors = htmx.find(node, '[data-neighbor-json]')?.dataset?.neighborJson;
-            if (neighbors) neighbors = JSON.parse(neighbors);
             let geojson = htmx.find(node, '[data-geojson]')?.dataset?.geojson;
             if (geojson) geojson = JSON.parse(geojson);
 
             return {
                 id: node.dataset.nodeId,
-                neighbors: neighbors,
+                neighbors: neighborLinks[node.dataset.nodeId],
                 geojson: geojson,
                 selected: node.classList.contains('selected'),
                 isOld: node.classList.contains('is-old')
@@ -184,21 +209,25 @@ function _updateNodeGeoJSON() {
         nodeFeaturesById[node.properties.id] = node;
     });
 
+    const neighborMaxAgeInMinutes = getNeighborMaxAgeInMinutes();
+    const curDate = Date.now() / 1000;
     const neighbors = nodeData
         .filter((node) => node.neighbors && node.neighbors.length > 0 && (selectedIndex == -1 || node.selected))
         .flatMap((node) => {
-            return node.neighbors.map((neighbor) => {
-                const nodes = [node.id, neighbor.neighbor].sort();
-                const key = nodes[0] + "|" + nodes[1];
-
-                return {
-                    key: key,
-                    source: node.id,
-                    target: neighbor.neighbor,
-                    snr: neighbor.snr,
-                    timestamp: neighbor.timestamp
-                };
-            });
+            return node.neighbors
+                .filter((neighbor) => curDate - neighbor.timestamp <= neighborMaxAgeInMinutes * 60)
+                .map((neighbor) => {
+                    const nodes = [node.id, neighbor.neighbor].sort();
+                    const key = nodes[0] + "|" + nodes[1];
+
+                    return {
+                        key: key,
+                        source: node.id,
+                        target: neighbor.neighbor,
+                        snr: neighbor.snr,
+                        timestamp: neighbor.timestamp
+                    };
+                });
         })
         .filter((neighbor) => nodeFeaturesById[neighbor.source] && nodeFeaturesById[neighbor.target])
         .sort((neighborA, neighborB) => neighborB.timestamp - neighborA.timestamp);
@@ -320,6 +349,7 @@ const refreshOnlineState = debounce(_refreshOnlineState, 250);
 
 function refreshMap() {
     updateNodeGeoJSON();
+    fetchNeighborLinks();
 }
 
 function handleSseMessage(event) {
@@ -567,15 +597,26 @@ function updateFilter(event) {
 
         _updateNodeGeoJSON();
     }
+
+    if (event.detail && event.detail.neighborMaxAge) {
+        localStorage.setItem("neighborMaxAge", event.detail.neighborMaxAge);
+
+        fetchNeighborLinks();
+    }
 }
 
 function initFilter() {
-    const datalist = document.getElementById('max-age-marks');
-    const input = document.getElementById('max-age');
+    initRange('max-age', getMaxAgeInMinutes());
+    initRange('neighbor-max-age', getNeighborMaxAgeInMinutes());
+    fetchNeighborLinks();
+}
+
+function initRange(id, minutesValue) {
+    const datalist = document.getElementById(id + '-marks');
+    const input = document.getElementById(id);
 
-    const maxAgeInMinutesValue = getMaxAgeInMinutes();
-    const maxAgeInMinutes = maxAgeInMinutesValue == null ? 'all' : maxAgeInMinutesValue.toFixed(0);
-    const element = htmx.find(datalist, "[data-minutes='" + maxAgeInMinutes + "']");
+    const minutes = minutesValue == null ? 'all' : minutesValue.toFixed(0);
+    const element = htmx.find(datalist, "[data-minutes='" + minutes + "']");
 
     input.value = element.value;
 